@@ -1,13 +1,20 @@
 use fs::File;
-use gameboy::{Button, ButtonState, InitializationOptions, InputEvent, System};
+use gameboy::compatibility_palette::PaletteShortcut;
+use gameboy::printer::Printer;
+use gameboy::serial::SerialDevice;
+use gameboy::sound::AudioChannel;
+use gameboy::tcp_link::TcpLink;
+use gameboy::wav::AudioRecorder;
+use gameboy::{Button, ButtonState, InitializationOptions, InputEvent, System, SystemEvent};
 use sdl2::audio::{AudioQueue, AudioSpecDesired, AudioStatus};
 use std::env;
 use std::fs;
+use std::io;
 use std::io::prelude::*;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 use sdl2::event::Event;
-use sdl2::keyboard::Keycode;
+use sdl2::keyboard::{Keycode, Mod};
 use sdl2::pixels::PixelFormatEnum;
 
 const FREQUENCY: u32 = 48000;
@@ -52,10 +59,67 @@ fn main() {
         game_rom: &game_rom,
         external_ram: external_ram.as_deref(),
         boot_rom: boot_rom.as_deref(),
+        cartridge: None,
+        cgb_compatibility: args.iter().any(|arg| arg == "--cgb-compatibility"),
+        super_game_boy: args.iter().any(|arg| arg == "--sgb"),
         debug_mode: false,
         sound_frequency: FREQUENCY,
     };
-    let mut system = System::new(options);
+    let mut system = match System::new(options) {
+        Ok(system) => system,
+        Err(x) => panic!("Could not load {}: {}", game_file_path.display(), x),
+    };
+
+    // Another instance can be linked with --link-host <port> on one side and
+    // --link-connect <address> on the other, or a printer plugged in with
+    // --printer <directory>
+    let link_port_device: Option<io::Result<Box<dyn SerialDevice>>> =
+        if let Some(port) = option_value(&args, "--link-host") {
+            let port = port
+                .parse()
+                .unwrap_or_else(|_| panic!("Invalid link port {}", port));
+            println!("Waiting for a link cable connection on port {}", port);
+            Some(TcpLink::host(port).map(|link| Box::new(link) as Box<dyn SerialDevice>))
+        } else if let Some(address) = option_value(&args, "--link-connect") {
+            Some(TcpLink::connect(address).map(|link| Box::new(link) as Box<dyn SerialDevice>))
+        } else {
+            option_value(&args, "--printer").map(|directory| {
+                Printer::new(directory).map(|printer| Box::new(printer) as Box<dyn SerialDevice>)
+            })
+        };
+    match link_port_device {
+        Some(Ok(device)) => {
+            system.connect_serial_device(device);
+        }
+        Some(Err(x)) => panic!("Could not connect to the link port: {}", x),
+        None => {}
+    }
+
+    // --record-audio <file> records the sound from the start, R starts and
+    // stops recordings next to the ROM while playing. --record-stems saves
+    // every sound channel to its own file as well.
+    let record_stems = args.iter().any(|arg| arg == "--record-stems");
+    let mut recorder = option_value(&args, "--record-audio")
+        .map(|path| start_recording(&mut system, Path::new(path), record_stems));
+
+    // --headless <frames> runs the game for a number of frames as fast as
+    // possible without a window or sound device
+    if let Some(frames) = option_value(&args, "--headless") {
+        let frames = frames
+            .parse()
+            .unwrap_or_else(|_| panic!("Invalid frame count {}", frames));
+        run_headless(&mut system, frames, &mut recorder);
+        if let Some(recording) = recorder.take() {
+            stop_recording(&mut system, recording);
+        }
+        save_external_ram(&system, &game_file_path);
+        return;
+    }
+
+    let window_title = match system.cartridge_info() {
+        Some(info) if !info.title.is_empty() => format!("Gameboy Emulator - {}", info.title),
+        _ => String::from("Gameboy Emulator"),
+    };
 
     let sdl_context = sdl2::init().unwrap(); //.ok_or("Could not create SDL Context.");
     let video_subsystem = sdl_context.video().unwrap();
@@ -67,12 +131,18 @@ fn main() {
         samples: None,
     };
 
-    let mut audio_framebuffer: Vec<u8> = Vec::with_capacity(2 * FREQUENCY as usize);
+    // Cartridge rumble is forwarded to the first connected game controller
+    let game_controller_subsystem = sdl_context.game_controller().unwrap();
+    let mut game_controller = (0..game_controller_subsystem.num_joysticks().unwrap_or(0))
+        .find(|&id| game_controller_subsystem.is_game_controller(id))
+        .and_then(|id| game_controller_subsystem.open(id).ok());
 
-    let queue: AudioQueue<u8> = audio_subsystem.open_queue(None, &audio_spec).unwrap();
+    let mut audio_framebuffer: Vec<i16> = Vec::with_capacity(2 * FREQUENCY as usize);
+
+    let queue: AudioQueue<i16> = audio_subsystem.open_queue(None, &audio_spec).unwrap();
 
     let window = video_subsystem
-        .window("Gameboy Emulator", 800, 600)
+        .window(&window_title, 800, 600)
         .position_centered()
         .resizable()
         .build()
@@ -83,8 +153,8 @@ fn main() {
     let mut texture = texture_creator
         .create_texture_streaming(
             PixelFormatEnum::ARGB8888,
-            System::screen_width(),
-            System::screen_height(),
+            system.framebuffer_width(),
+            system.framebuffer_height(),
         )
         .unwrap();
 
@@ -108,7 +178,9 @@ fn main() {
                         });
                     }
                 }
-                Event::KeyUp { keycode, .. } => {
+                Event::KeyUp {
+                    keycode, keymod, ..
+                } => {
                     if keycode == Some(Keycode::Space) {
                         paused = !paused;
                         if paused {
@@ -118,6 +190,31 @@ fn main() {
                         }
                     }
 
+                    if let Some(shortcut) = keycode_to_palette_shortcut(keycode) {
+                        system.set_compatibility_palette(&shortcut.palette());
+                    }
+
+                    if keycode == Some(Keycode::R) {
+                        match recorder.take() {
+                            Some(recording) => stop_recording(&mut system, recording),
+                            None => {
+                                let path = next_recording_path(&game_file_path);
+                                recorder = Some(start_recording(&mut system, &path, record_stems));
+                            }
+                        }
+                    }
+
+                    // 1-4 mute a sound channel, holding shift solos it
+                    if let Some(channel) = keycode_to_audio_channel(keycode) {
+                        if keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD) {
+                            let soloed = !system.is_channel_soloed(channel);
+                            system.set_channel_soloed(channel, soloed);
+                        } else {
+                            let muted = !system.is_channel_muted(channel);
+                            system.set_channel_muted(channel, muted);
+                        }
+                    }
+
                     if let Some(button) = keycode_to_button(keycode) {
                         events.push(InputEvent {
                             button,
@@ -133,6 +230,7 @@ fn main() {
             texture
                 .with_lock(None, |buffer: &mut [u8], _: usize| {
                     system.run_single_frame(&events, buffer, &mut audio_framebuffer);
+                    record_audio(&mut system, &mut recorder, &audio_framebuffer);
 
                     queue.queue_audio(&audio_framebuffer).unwrap();
                     if !first_frame_from_pause && queue.status() != AudioStatus::Playing {
@@ -148,6 +246,25 @@ fn main() {
             canvas.copy(&texture, None, None).unwrap();
             canvas.present();
 
+            for event in system.take_events() {
+                match event {
+                    SystemEvent::CpuLocked { address, opcode } => eprintln!(
+                        "The game locked up executing illegal opcode {:02X} at {:04X}",
+                        opcode, address
+                    ),
+                }
+            }
+
+            if let Some(game_controller) = game_controller.as_mut() {
+                let strength = (system.rumble_strength() * u16::MAX as f32) as u16;
+                // Run the motor for slightly longer than a frame so it does not
+                // stutter between updates
+                let duration = 2 * FRAME_TIME.as_millis() as u32;
+                game_controller
+                    .set_rumble(strength, strength, duration)
+                    .unwrap_or(());
+            }
+
             let now = std::time::Instant::now();
             if now < next_frame_target {
                 std::thread::sleep(next_frame_target - now);
@@ -163,9 +280,77 @@ fn main() {
             break;
         }
     }
+    if let Some(recording) = recorder.take() {
+        stop_recording(&mut system, recording);
+    }
     save_external_ram(&system, &game_file_path);
 }
 
+fn run_headless(system: &mut System, frames: u32, recorder: &mut Option<AudioRecorder>) {
+    let mut framebuffer =
+        vec![0; (4 * system.framebuffer_width() * system.framebuffer_height()) as usize];
+    let mut audio_framebuffer: Vec<i16> = Vec::with_capacity(2 * FREQUENCY as usize);
+    for _ in 0..frames {
+        system.run_single_frame(&[], &mut framebuffer, &mut audio_framebuffer);
+        record_audio(system, recorder, &audio_framebuffer);
+        audio_framebuffer.clear();
+
+        for event in system.take_events() {
+            match event {
+                SystemEvent::CpuLocked { address, opcode } => eprintln!(
+                    "The game locked up executing illegal opcode {:02X} at {:04X}",
+                    opcode, address
+                ),
+            }
+        }
+        if system.exit_requested() {
+            break;
+        }
+    }
+}
+
+fn start_recording(system: &mut System, path: &Path, stems: bool) -> AudioRecorder {
+    match AudioRecorder::start(system, path, stems) {
+        Ok(recorder) => {
+            println!("Recording audio to {}", path.display());
+            recorder
+        }
+        Err(x) => panic!("Could not record audio to {}: {}", path.display(), x),
+    }
+}
+
+fn stop_recording(system: &mut System, recording: AudioRecorder) {
+    match recording.stop(system) {
+        Ok(()) => println!("Stopped recording audio"),
+        Err(x) => eprintln!("Could not finish the audio recording: {}", x),
+    }
+}
+
+// Stops the recording if the file can't be written anymore, the game keeps
+// running
+fn record_audio(system: &mut System, recorder: &mut Option<AudioRecorder>, samples: &[i16]) {
+    if let Some(recording) = recorder.as_mut() {
+        if let Err(x) = recording.record(system, samples) {
+            eprintln!("Could not record audio: {}", x);
+            if let Some(recording) = recorder.take() {
+                stop_recording(system, recording);
+            }
+        }
+    }
+}
+
+// The first unused name such as `tetris_0001.wav` next to the ROM
+fn next_recording_path(game_file_path: &Path) -> PathBuf {
+    let stem = game_file_path
+        .file_stem()
+        .unwrap_or_default()
+        .to_string_lossy();
+    (1..)
+        .map(|index| game_file_path.with_file_name(format!("{}_{:04}.wav", stem, index)))
+        .find(|path| !path.exists())
+        .unwrap()
+}
+
 fn save_external_ram(system: &System, game_file_path: &Path) {
     if let Some(external_ram) = system.copy_external_ram_banks() {
         let game_save_path = game_file_path.with_extension("gbsave");
@@ -181,6 +366,14 @@ fn save_external_ram(system: &System, game_file_path: &Path) {
     }
 }
 
+// The value following an option such as `--link-host 5000`
+fn option_value<'a>(args: &'a [String], name: &str) -> Option<&'a str> {
+    args.iter()
+        .position(|arg| arg == name)
+        .and_then(|index| args.get(index + 1))
+        .map(|value| value.as_str())
+}
+
 fn keycode_to_button(key: Option<Keycode>) -> Option<Button> {
     let keycode = match key {
         Some(keycode) => keycode,
@@ -199,3 +392,33 @@ fn keycode_to_button(key: Option<Keycode>) -> Option<Button> {
         _ => None,
     }
 }
+
+fn keycode_to_audio_channel(key: Option<Keycode>) -> Option<AudioChannel> {
+    match key? {
+        Keycode::Num1 => Some(AudioChannel::One),
+        Keycode::Num2 => Some(AudioChannel::Two),
+        Keycode::Num3 => Some(AudioChannel::Three),
+        Keycode::Num4 => Some(AudioChannel::Four),
+        _ => None,
+    }
+}
+
+// F1-F12 pick the CGB palettes for games made for the original Game Boy
+fn keycode_to_palette_shortcut(key: Option<Keycode>) -> Option<PaletteShortcut> {
+    let index = match key? {
+        Keycode::F1 => 0,
+        Keycode::F2 => 1,
+        Keycode::F3 => 2,
+        Keycode::F4 => 3,
+        Keycode::F5 => 4,
+        Keycode::F6 => 5,
+        Keycode::F7 => 6,
+        Keycode::F8 => 7,
+        Keycode::F9 => 8,
+        Keycode::F10 => 9,
+        Keycode::F11 => 10,
+        Keycode::F12 => 11,
+        _ => return None,
+    };
+    Some(PaletteShortcut::ALL[index])
+}
@@ -3,6 +3,14 @@
 #include <stdint.h>
 #include <stdlib.h>
 
+#define CLOCK_RATE 4194304
+
+#define HEADER_END 336
+
+#define NINTENDO_LOGO_START 260
+
+#define MAX_PLAYERS 4
+
 #define VERTICAL_RES 144
 
 #define HORIZONTAL_RES 160
@@ -10,3 +18,21 @@
 #define PIXELS_PER_BYTE 4
 
 #define BUFFER_SIZE (((uintptr_t)VERTICAL_RES * (uintptr_t)HORIZONTAL_RES) / (uintptr_t)PIXELS_PER_BYTE)
+
+/**
+ * Incremented whenever the layout of a serialized component changes so that
+ * states written by older builds are rejected instead of misread.
+ */
+#define STATE_VERSION 19
+
+#define SCREEN_WIDTH 256
+
+#define SCREEN_HEIGHT 224
+
+#define SOUND_REGISTERS_START 65296
+
+#define WAVE_RAM_START 65328
+
+#define WAVE_RAM_END 65344
+
+#define SOUND_POWER 128
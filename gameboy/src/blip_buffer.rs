@@ -0,0 +1,190 @@
+use crate::save_state::{SaveState, SaveStateError, StateReader, StateWriter};
+
+// The rate the APU runs at, every level change happens on one of its cycles
+pub const CLOCK_RATE: u64 = 4_194_304;
+
+// Each step is spread over this many output samples
+const KERNEL_TAPS: usize = 16;
+// Steps are placed with 1/32 of a sample precision
+const KERNEL_PHASES: usize = 32;
+// The sound controller advances at most this many cycles between reads
+const MAX_UNREAD_CYCLES: u64 = u8::MAX as u64;
+// Fraction of the output Nyquist frequency that is kept
+const CUTOFF: f64 = 0.9;
+
+// The DMG capacitor loses this much of its charge every APU cycle
+const CAPACITOR_CHARGE_FACTOR: f64 = 0.999_958;
+
+/// Turns a signal that changes level on APU cycles into samples at the output
+/// rate. Every change is added as a band-limited step, so square waves keep
+/// sharp edges without aliasing at high pitches.
+pub struct BlipBuffer {
+    sample_rate: u64,
+    kernel: Vec<[f32; KERNEL_TAPS]>,
+    // APU cycles since the start
+    clock: u64,
+    samples_read: u64,
+    level: f32,
+    // Changes of the output waiting to be summed up, sample n is kept at
+    // n % deltas.len()
+    deltas: Vec<f32>,
+    integrator: f32,
+    high_pass: HighPass,
+}
+
+impl BlipBuffer {
+    pub fn new(sample_rate: u32) -> BlipBuffer {
+        let sample_rate = sample_rate.max(1) as u64;
+        BlipBuffer {
+            sample_rate,
+            kernel: create_kernel(),
+            clock: 0,
+            samples_read: 0,
+            level: 0.0,
+            deltas: vec![0.0; buffer_size(sample_rate)],
+            integrator: 0.0,
+            high_pass: HighPass::new(sample_rate),
+        }
+    }
+
+    /// Sets the level of the signal at the current cycle
+    pub fn set_level(&mut self, level: f32) {
+        let delta = level - self.level;
+        if delta == 0.0 {
+            return;
+        }
+        self.level = level;
+
+        // Where the step falls between two output samples
+        let position = self.clock * self.sample_rate;
+        let sample = position / CLOCK_RATE;
+        let phase = ((position % CLOCK_RATE) * KERNEL_PHASES as u64 / CLOCK_RATE) as usize;
+
+        // Samples have to be read before they are a whole kernel behind
+        let size = self.deltas.len();
+        debug_assert!(sample - self.samples_read + KERNEL_TAPS as u64 <= size as u64);
+        for (tap, weight) in self.kernel[phase].iter().enumerate() {
+            self.deltas[(sample as usize + tap) % size] += delta * weight;
+        }
+    }
+
+    /// Moves the signal forward by a number of APU cycles
+    pub fn advance(&mut self, cycles: u32) {
+        self.clock += cycles as u64;
+    }
+
+    /// The number of samples that no change can affect anymore
+    pub fn samples_available(&self) -> usize {
+        (self.clock * self.sample_rate / CLOCK_RATE - self.samples_read) as usize
+    }
+
+    /// Returns the next finished sample with the DC offset filtered out, or
+    /// `None` if it still depends on the signal that is to come
+    pub fn read_sample(&mut self) -> Option<f32> {
+        if self.samples_available() == 0 {
+            return None;
+        }
+
+        let index = self.samples_read as usize % self.deltas.len();
+        self.integrator += self.deltas[index];
+        self.deltas[index] = 0.0;
+        self.samples_read += 1;
+        Some(self.high_pass.filter(self.integrator))
+    }
+}
+
+impl SaveState for BlipBuffer {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u64(self.clock);
+        writer.write_u64(self.samples_read);
+        writer.write_u32(self.level.to_bits());
+        writer.write_u32(self.deltas.len() as u32);
+        for delta in &self.deltas {
+            writer.write_u32(delta.to_bits());
+        }
+        writer.write_u32(self.integrator.to_bits());
+        writer.write_u32(self.high_pass.capacitor.to_bits());
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.clock = reader.read_u64()?;
+        self.samples_read = reader.read_u64()?;
+        if self.samples_read > self.clock * self.sample_rate / CLOCK_RATE {
+            return Err(SaveStateError::InvalidData(
+                "sound samples read ahead of the sound clock",
+            ));
+        }
+        self.level = f32::from_bits(reader.read_u32()?);
+        if reader.read_u32()? != self.deltas.len() as u32 {
+            return Err(SaveStateError::InvalidData(
+                "sound saved at another sample rate",
+            ));
+        }
+        for delta in self.deltas.iter_mut() {
+            *delta = f32::from_bits(reader.read_u32()?);
+        }
+        self.integrator = f32::from_bits(reader.read_u32()?);
+        self.high_pass.capacitor = f32::from_bits(reader.read_u32()?);
+        Ok(())
+    }
+}
+
+// Changes are summed up in a ring of samples that covers a step starting one
+// kernel past the last sample the unread cycles produce
+fn buffer_size(sample_rate: u64) -> usize {
+    (sample_rate * MAX_UNREAD_CYCLES / CLOCK_RATE) as usize + 1 + KERNEL_TAPS
+}
+
+// The capacitor on the sound output of the Game Boy removes the DC offset
+// that the DACs add
+struct HighPass {
+    charge_factor: f32,
+    capacitor: f32,
+}
+
+impl HighPass {
+    fn new(sample_rate: u64) -> HighPass {
+        let cycles_per_sample = CLOCK_RATE as f64 / sample_rate as f64;
+        HighPass {
+            charge_factor: CAPACITOR_CHARGE_FACTOR.powf(cycles_per_sample) as f32,
+            capacitor: 0.0,
+        }
+    }
+
+    fn filter(&mut self, input: f32) -> f32 {
+        let output = input - self.capacitor;
+        self.capacitor = input - output * self.charge_factor;
+        output
+    }
+}
+
+// A windowed sinc for every phase, normalized so each step adds up to exactly
+// its size once all its samples are summed
+fn create_kernel() -> Vec<[f32; KERNEL_TAPS]> {
+    (0..KERNEL_PHASES)
+        .map(|phase| {
+            let offset = phase as f64 / KERNEL_PHASES as f64;
+            let mut taps = [0.0; KERNEL_TAPS];
+            for (tap, weight) in taps.iter_mut().enumerate() {
+                let x = tap as f64 - offset - (KERNEL_TAPS / 2) as f64;
+                let sinc = if x == 0.0 {
+                    1.0
+                } else {
+                    let angle = std::f64::consts::PI * CUTOFF * x;
+                    angle.sin() / angle
+                };
+                // Blackman window over the width of the kernel
+                let window_position = (x + (KERNEL_TAPS / 2 + 1) as f64) / (KERNEL_TAPS + 1) as f64;
+                let angle = 2.0 * std::f64::consts::PI * window_position;
+                let window = 0.42 - 0.5 * angle.cos() + 0.08 * (2.0 * angle).cos();
+                *weight = (sinc * window) as f32;
+            }
+
+            let sum: f32 = taps.iter().sum();
+            for weight in taps.iter_mut() {
+                *weight /= sum;
+            }
+            taps
+        })
+        .collect()
+}
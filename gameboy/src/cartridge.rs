@@ -0,0 +1,225 @@
+use std::fmt;
+
+use crate::cartridge_info::{parse_header, CartridgeInfo, Mapper, HEADER_END};
+use crate::mbc1::MemoryBankController1;
+use crate::mbc2::MemoryBankController2;
+use crate::mbc3::MemoryBankController3;
+use crate::mbc5::MemoryBankController5;
+use crate::save_state::{SaveState, SaveStateError, StateReader, StateWriter};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum LoadError {
+    /// The ROM image is shorter than its header says it should be
+    TruncatedRom { expected: usize, actual: usize },
+    /// The cartridge type byte at 0x147 names hardware that is not emulated
+    UnsupportedMapper(u8),
+    /// The ROM size code at 0x148 is unknown or too large for the mapper
+    InvalidRomSize(u8),
+    /// The RAM size code at 0x149 is unknown or too large for the mapper
+    InvalidRamSize(u8),
+    /// The save data does not fit the external RAM of the cartridge
+    SaveSizeMismatch { expected: usize, actual: usize },
+    /// Boot ROMs are mapped over 0x0000-0x00FF and can't be any larger
+    InvalidBootRomSize(usize),
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoadError::TruncatedRom { expected, actual } => write!(
+                f,
+                "ROM is truncated: expected {} bytes, found {}",
+                expected, actual
+            ),
+            LoadError::UnsupportedMapper(code) => {
+                write!(f, "cartridge type {:02X} is not supported", code)
+            }
+            LoadError::InvalidRomSize(code) => write!(f, "invalid ROM size code {:02X}", code),
+            LoadError::InvalidRamSize(code) => write!(f, "invalid RAM size code {:02X}", code),
+            LoadError::SaveSizeMismatch { expected, actual } => write!(
+                f,
+                "save data is {} bytes but the cartridge expects {}",
+                actual, expected
+            ),
+            LoadError::InvalidBootRomSize(size) => {
+                write!(f, "boot ROM is {} bytes, at most 256 are supported", size)
+            }
+        }
+    }
+}
+
+impl std::error::Error for LoadError {}
+
+/// The hardware on a game cartridge. `Memory` forwards every access to
+/// 0x0000-0x7FFF and 0xA000-0xBFFF to the loaded cartridge, so new memory
+/// bank controllers only need to implement this trait. Custom implementations
+/// can be supplied through `InitializationOptions::cartridge`.
+pub trait Cartridge: SaveState {
+    /// Reads from the ROM area at 0x0000-0x7FFF
+    fn read_rom(&self, address: u16) -> u8;
+
+    /// Handles writes to 0x0000-0x7FFF, which set the controller registers
+    fn write_register(&mut self, address: u16, value: u8);
+
+    /// Reads from the external RAM area at 0xA000-0xBFFF
+    fn read_ram(&self, address: u16) -> u8;
+
+    fn write_ram(&mut self, address: u16, value: u8);
+
+    /// Called after every instruction with the number of cycles it took, for
+    /// hardware such as clocks that runs independently of the CPU
+    fn tick(&mut self, _cycles: u8) {}
+
+    /// Whether the cartridge keeps its data alive with a battery
+    fn has_battery(&self) -> bool {
+        false
+    }
+
+    /// Loads data previously returned by `export_battery_data`
+    fn import_battery_data(&mut self, _data: &[u8]) -> Result<(), LoadError> {
+        Ok(())
+    }
+
+    /// The data that should be written to a save file
+    fn export_battery_data(&self) -> Vec<u8> {
+        self.external_ram().to_vec()
+    }
+
+    /// The state of the real time clock on its own, laid out like the block
+    /// that follows the RAM in `export_battery_data`. `None` for cartridges
+    /// without a clock.
+    fn export_rtc_data(&self) -> Option<Vec<u8>> {
+        None
+    }
+
+    /// Loads clock state previously returned by `export_rtc_data`
+    fn import_rtc_data(&mut self, _data: &[u8]) -> Result<(), LoadError> {
+        Ok(())
+    }
+
+    /// All external RAM banks laid out back to back. The returned buffer must
+    /// not be reallocated while the cartridge is loaded as frontends may hold
+    /// on to it.
+    fn external_ram(&self) -> &[u8] {
+        &[]
+    }
+
+    fn external_ram_mut(&mut self) -> &mut [u8] {
+        &mut []
+    }
+
+    /// Whether the rumble motor is currently switched on
+    fn rumble_active(&self) -> bool {
+        false
+    }
+}
+
+/// Creates the cartridge hardware described by the ROM header
+pub fn create_cartridge(rom_buf: &[u8]) -> Result<Box<dyn Cartridge>, LoadError> {
+    let info = parse_header(rom_buf).ok_or(LoadError::TruncatedRom {
+        expected: HEADER_END,
+        actual: rom_buf.len(),
+    })?;
+
+    Ok(match info.cartridge_type.mapper() {
+        Some(Mapper::None) => {
+            check_sizes(rom_buf, &info, 0x00, 0x05)?;
+            Box::new(RomOnly::new(rom_buf))
+        }
+        Some(Mapper::Mbc1) => {
+            let mut mbc1 = MemoryBankController1::new();
+            mbc1.initialize(rom_buf, &info)?;
+            Box::new(mbc1)
+        }
+        Some(Mapper::Mbc2) => {
+            let mut mbc2 = MemoryBankController2::new();
+            mbc2.initialize(rom_buf, &info)?;
+            Box::new(mbc2)
+        }
+        Some(Mapper::Mbc3) => {
+            let mut mbc3 = MemoryBankController3::new();
+            mbc3.initialize(rom_buf, &info)?;
+            Box::new(mbc3)
+        }
+        Some(Mapper::Mbc5) => {
+            let mut mbc5 = MemoryBankController5::new();
+            mbc5.initialize(rom_buf, &info)?;
+            Box::new(mbc5)
+        }
+        _ => return Err(LoadError::UnsupportedMapper(info.cartridge_type.code())),
+    })
+}
+
+/// Checks the size codes in the header against the largest ones the mapper
+/// supports and makes sure the ROM image is as long as the header claims.
+/// Returns the ROM and RAM sizes in bytes.
+pub fn check_sizes(
+    rom_buf: &[u8],
+    info: &CartridgeInfo,
+    max_rom_size_code: u8,
+    max_ram_size_code: u8,
+) -> Result<(usize, usize), LoadError> {
+    let rom_size = match info.rom_size {
+        Some(size) if info.rom_size_code <= max_rom_size_code => size,
+        _ => return Err(LoadError::InvalidRomSize(info.rom_size_code)),
+    };
+    let ram_size = match info.ram_size {
+        Some(size) if info.ram_size_code <= max_ram_size_code => size,
+        _ => return Err(LoadError::InvalidRamSize(info.ram_size_code)),
+    };
+
+    if rom_buf.len() < rom_size {
+        return Err(LoadError::TruncatedRom {
+            expected: rom_size,
+            actual: rom_buf.len(),
+        });
+    }
+
+    Ok((rom_size, ram_size))
+}
+
+/// Checks that save data is exactly as large as the external RAM
+pub fn check_save_size(data: &[u8], ram_size: usize) -> Result<(), LoadError> {
+    if data.len() != ram_size {
+        return Err(LoadError::SaveSizeMismatch {
+            expected: ram_size,
+            actual: data.len(),
+        });
+    }
+    Ok(())
+}
+
+/// A 32KB cartridge without a memory bank controller
+pub struct RomOnly {
+    rom: Vec<u8>,
+}
+
+impl RomOnly {
+    pub fn new(rom_buf: &[u8]) -> RomOnly {
+        RomOnly {
+            rom: rom_buf[..0x8000].to_vec(),
+        }
+    }
+}
+
+impl Cartridge for RomOnly {
+    fn read_rom(&self, address: u16) -> u8 {
+        self.rom[address as usize]
+    }
+
+    fn write_register(&mut self, _address: u16, _value: u8) {}
+
+    fn read_ram(&self, _address: u16) -> u8 {
+        0xFF
+    }
+
+    fn write_ram(&mut self, _address: u16, _value: u8) {}
+}
+
+impl SaveState for RomOnly {
+    fn save_state(&self, _writer: &mut StateWriter) {}
+
+    fn load_state(&mut self, _reader: &mut StateReader) -> Result<(), SaveStateError> {
+        Ok(())
+    }
+}
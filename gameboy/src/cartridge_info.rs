@@ -0,0 +1,384 @@
+const TITLE_START: usize = 0x134;
+const MANUFACTURER_CODE_START: usize = 0x13F;
+const CGB_FLAG: usize = 0x143;
+const NEW_LICENSEE_CODE: usize = 0x144;
+const SGB_FLAG: usize = 0x146;
+const CARTRIDGE_TYPE: usize = 0x147;
+const ROM_SIZE: usize = 0x148;
+const RAM_SIZE: usize = 0x149;
+const OLD_LICENSEE_CODE: usize = 0x14B;
+const VERSION: usize = 0x14C;
+const HEADER_CHECKSUM: usize = 0x14D;
+const GLOBAL_CHECKSUM: usize = 0x14E;
+pub const HEADER_END: usize = 0x150;
+
+// An old licensee code of 0x33 means the new two character code is used
+const USE_NEW_LICENSEE_CODE: u8 = 0x33;
+
+pub const NINTENDO_LOGO_START: usize = 0x104;
+pub const NINTENDO_LOGO: [u8; 48] = [
+    0xCE, 0xED, 0x66, 0x66, 0xCC, 0x0D, 0x00, 0x0B, 0x03, 0x73, 0x00, 0x83, 0x00, 0x0C, 0x00, 0x0D,
+    0x00, 0x08, 0x11, 0x1F, 0x88, 0x89, 0x00, 0x0E, 0xDC, 0xCC, 0x6E, 0xE6, 0xDD, 0xDD, 0xD9, 0x99,
+    0xBB, 0xBB, 0x67, 0x63, 0x6E, 0x0E, 0xEC, 0xCC, 0xDD, 0xDC, 0x99, 0x9F, 0xBB, 0xB9, 0x33, 0x3E,
+];
+
+/// The memory bank controller or other mapper chip on a cartridge
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Mapper {
+    None,
+    Mbc1,
+    Mbc2,
+    Mbc3,
+    Mbc5,
+    Mbc6,
+    Mbc7,
+    Mmm01,
+    PocketCamera,
+    Tama5,
+    HuC1,
+    HuC3,
+}
+
+/// The hardware listed at 0x147 of the cartridge header
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CartridgeType {
+    RomOnly,
+    Mbc1,
+    Mbc1Ram,
+    Mbc1RamBattery,
+    Mbc2,
+    Mbc2Battery,
+    RomRam,
+    RomRamBattery,
+    Mmm01,
+    Mmm01Ram,
+    Mmm01RamBattery,
+    Mbc3TimerBattery,
+    Mbc3TimerRamBattery,
+    Mbc3,
+    Mbc3Ram,
+    Mbc3RamBattery,
+    Mbc5,
+    Mbc5Ram,
+    Mbc5RamBattery,
+    Mbc5Rumble,
+    Mbc5RumbleRam,
+    Mbc5RumbleRamBattery,
+    Mbc6,
+    Mbc7SensorRumbleRamBattery,
+    PocketCamera,
+    Tama5,
+    HuC3,
+    HuC1RamBattery,
+    Unknown(u8),
+}
+
+impl CartridgeType {
+    pub fn from_code(code: u8) -> CartridgeType {
+        match code {
+            0x00 => CartridgeType::RomOnly,
+            0x01 => CartridgeType::Mbc1,
+            0x02 => CartridgeType::Mbc1Ram,
+            0x03 => CartridgeType::Mbc1RamBattery,
+            0x05 => CartridgeType::Mbc2,
+            0x06 => CartridgeType::Mbc2Battery,
+            0x08 => CartridgeType::RomRam,
+            0x09 => CartridgeType::RomRamBattery,
+            0x0B => CartridgeType::Mmm01,
+            0x0C => CartridgeType::Mmm01Ram,
+            0x0D => CartridgeType::Mmm01RamBattery,
+            0x0F => CartridgeType::Mbc3TimerBattery,
+            0x10 => CartridgeType::Mbc3TimerRamBattery,
+            0x11 => CartridgeType::Mbc3,
+            0x12 => CartridgeType::Mbc3Ram,
+            0x13 => CartridgeType::Mbc3RamBattery,
+            0x19 => CartridgeType::Mbc5,
+            0x1A => CartridgeType::Mbc5Ram,
+            0x1B => CartridgeType::Mbc5RamBattery,
+            0x1C => CartridgeType::Mbc5Rumble,
+            0x1D => CartridgeType::Mbc5RumbleRam,
+            0x1E => CartridgeType::Mbc5RumbleRamBattery,
+            0x20 => CartridgeType::Mbc6,
+            0x22 => CartridgeType::Mbc7SensorRumbleRamBattery,
+            0xFC => CartridgeType::PocketCamera,
+            0xFD => CartridgeType::Tama5,
+            0xFE => CartridgeType::HuC3,
+            0xFF => CartridgeType::HuC1RamBattery,
+            x => CartridgeType::Unknown(x),
+        }
+    }
+
+    /// The value stored in the header for this type
+    pub fn code(&self) -> u8 {
+        match self {
+            CartridgeType::RomOnly => 0x00,
+            CartridgeType::Mbc1 => 0x01,
+            CartridgeType::Mbc1Ram => 0x02,
+            CartridgeType::Mbc1RamBattery => 0x03,
+            CartridgeType::Mbc2 => 0x05,
+            CartridgeType::Mbc2Battery => 0x06,
+            CartridgeType::RomRam => 0x08,
+            CartridgeType::RomRamBattery => 0x09,
+            CartridgeType::Mmm01 => 0x0B,
+            CartridgeType::Mmm01Ram => 0x0C,
+            CartridgeType::Mmm01RamBattery => 0x0D,
+            CartridgeType::Mbc3TimerBattery => 0x0F,
+            CartridgeType::Mbc3TimerRamBattery => 0x10,
+            CartridgeType::Mbc3 => 0x11,
+            CartridgeType::Mbc3Ram => 0x12,
+            CartridgeType::Mbc3RamBattery => 0x13,
+            CartridgeType::Mbc5 => 0x19,
+            CartridgeType::Mbc5Ram => 0x1A,
+            CartridgeType::Mbc5RamBattery => 0x1B,
+            CartridgeType::Mbc5Rumble => 0x1C,
+            CartridgeType::Mbc5RumbleRam => 0x1D,
+            CartridgeType::Mbc5RumbleRamBattery => 0x1E,
+            CartridgeType::Mbc6 => 0x20,
+            CartridgeType::Mbc7SensorRumbleRamBattery => 0x22,
+            CartridgeType::PocketCamera => 0xFC,
+            CartridgeType::Tama5 => 0xFD,
+            CartridgeType::HuC3 => 0xFE,
+            CartridgeType::HuC1RamBattery => 0xFF,
+            CartridgeType::Unknown(x) => *x,
+        }
+    }
+
+    /// The mapper chip, or `None` for unknown types
+    pub fn mapper(&self) -> Option<Mapper> {
+        Some(match self {
+            CartridgeType::RomOnly | CartridgeType::RomRam | CartridgeType::RomRamBattery => {
+                Mapper::None
+            }
+            CartridgeType::Mbc1 | CartridgeType::Mbc1Ram | CartridgeType::Mbc1RamBattery => {
+                Mapper::Mbc1
+            }
+            CartridgeType::Mbc2 | CartridgeType::Mbc2Battery => Mapper::Mbc2,
+            CartridgeType::Mmm01 | CartridgeType::Mmm01Ram | CartridgeType::Mmm01RamBattery => {
+                Mapper::Mmm01
+            }
+            CartridgeType::Mbc3TimerBattery
+            | CartridgeType::Mbc3TimerRamBattery
+            | CartridgeType::Mbc3
+            | CartridgeType::Mbc3Ram
+            | CartridgeType::Mbc3RamBattery => Mapper::Mbc3,
+            CartridgeType::Mbc5
+            | CartridgeType::Mbc5Ram
+            | CartridgeType::Mbc5RamBattery
+            | CartridgeType::Mbc5Rumble
+            | CartridgeType::Mbc5RumbleRam
+            | CartridgeType::Mbc5RumbleRamBattery => Mapper::Mbc5,
+            CartridgeType::Mbc6 => Mapper::Mbc6,
+            CartridgeType::Mbc7SensorRumbleRamBattery => Mapper::Mbc7,
+            CartridgeType::PocketCamera => Mapper::PocketCamera,
+            CartridgeType::Tama5 => Mapper::Tama5,
+            CartridgeType::HuC3 => Mapper::HuC3,
+            CartridgeType::HuC1RamBattery => Mapper::HuC1,
+            CartridgeType::Unknown(_) => return None,
+        })
+    }
+
+    pub fn has_battery(&self) -> bool {
+        matches!(
+            self,
+            CartridgeType::Mbc1RamBattery
+                | CartridgeType::Mbc2Battery
+                | CartridgeType::RomRamBattery
+                | CartridgeType::Mmm01RamBattery
+                | CartridgeType::Mbc3TimerBattery
+                | CartridgeType::Mbc3TimerRamBattery
+                | CartridgeType::Mbc3RamBattery
+                | CartridgeType::Mbc5RamBattery
+                | CartridgeType::Mbc5RumbleRamBattery
+                | CartridgeType::Mbc7SensorRumbleRamBattery
+                | CartridgeType::HuC1RamBattery
+        )
+    }
+
+    pub fn has_timer(&self) -> bool {
+        matches!(
+            self,
+            CartridgeType::Mbc3TimerBattery | CartridgeType::Mbc3TimerRamBattery
+        )
+    }
+
+    pub fn has_rumble(&self) -> bool {
+        matches!(
+            self,
+            CartridgeType::Mbc5Rumble
+                | CartridgeType::Mbc5RumbleRam
+                | CartridgeType::Mbc5RumbleRamBattery
+                | CartridgeType::Mbc7SensorRumbleRamBattery
+        )
+    }
+}
+
+/// How the game uses the Game Boy Color, from 0x143 of the header
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CgbSupport {
+    /// A Game Boy game
+    None,
+    /// Runs on both the Game Boy and Game Boy Color
+    Enhanced,
+    /// Only runs on the Game Boy Color
+    Required,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Licensee {
+    /// The single byte code at 0x14B used by older games
+    Old(u8),
+    /// The two character code at 0x144-0x145
+    New(String),
+}
+
+/// Everything stored in the cartridge header at 0x100-0x14F
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CartridgeInfo {
+    pub title: String,
+    /// Four character code only present in newer headers, it takes the place
+    /// of the end of the title
+    pub manufacturer_code: Option<String>,
+    pub cgb_support: CgbSupport,
+    pub sgb_support: bool,
+    pub licensee: Licensee,
+    pub cartridge_type: CartridgeType,
+    pub rom_size_code: u8,
+    pub ram_size_code: u8,
+    /// The ROM size in bytes, `None` if the size code is unknown
+    pub rom_size: Option<usize>,
+    /// The external RAM size in bytes, `None` if the size code is unknown.
+    /// This does not include the RAM built into MBC2 chips.
+    pub ram_size: Option<usize>,
+    pub version: u8,
+    pub header_checksum: u8,
+    pub global_checksum: u16,
+    /// Whether the header checksum matches, the boot ROM locks up if it does not
+    pub header_checksum_valid: bool,
+    /// Whether the sum of all ROM bytes matches, real hardware never checks
+    /// this but a mismatch suggests a bad dump
+    pub global_checksum_valid: bool,
+    /// Whether the header contains the Nintendo logo, which the boot ROM
+    /// requires to start the game
+    pub logo_valid: bool,
+}
+
+/// Parses the header of a ROM image. Returns `None` if the ROM is too short
+/// to contain a header.
+pub fn parse_header(rom_buf: &[u8]) -> Option<CartridgeInfo> {
+    if rom_buf.len() < HEADER_END {
+        return None;
+    }
+
+    let cgb_support = match rom_buf[CGB_FLAG] {
+        0xC0 => CgbSupport::Required,
+        x if x & 0x80 != 0 => CgbSupport::Enhanced,
+        _ => CgbSupport::None,
+    };
+
+    // Newer games shortened the title to make room for the manufacturer code
+    // and the CGB flag
+    let manufacturer_code = &rom_buf[MANUFACTURER_CODE_START..CGB_FLAG];
+    let has_manufacturer_code = cgb_support != CgbSupport::None
+        && manufacturer_code
+            .iter()
+            .all(|b| b.is_ascii_uppercase() || b.is_ascii_digit());
+    let title_end = if has_manufacturer_code {
+        MANUFACTURER_CODE_START
+    } else if cgb_support != CgbSupport::None {
+        CGB_FLAG
+    } else {
+        NEW_LICENSEE_CODE
+    };
+
+    let licensee = match rom_buf[OLD_LICENSEE_CODE] {
+        USE_NEW_LICENSEE_CODE => Licensee::New(ascii_string(
+            &rom_buf[NEW_LICENSEE_CODE..NEW_LICENSEE_CODE + 2],
+        )),
+        x => Licensee::Old(x),
+    };
+
+    let rom_size_code = rom_buf[ROM_SIZE];
+    let ram_size_code = rom_buf[RAM_SIZE];
+    let header_checksum = rom_buf[HEADER_CHECKSUM];
+    let global_checksum =
+        ((rom_buf[GLOBAL_CHECKSUM] as u16) << 8) | rom_buf[GLOBAL_CHECKSUM + 1] as u16;
+
+    Some(CartridgeInfo {
+        title: ascii_string(&rom_buf[TITLE_START..title_end]),
+        manufacturer_code: if has_manufacturer_code {
+            Some(ascii_string(manufacturer_code))
+        } else {
+            None
+        },
+        cgb_support,
+        sgb_support: rom_buf[SGB_FLAG] == 0x03,
+        licensee,
+        cartridge_type: CartridgeType::from_code(rom_buf[CARTRIDGE_TYPE]),
+        rom_size_code,
+        ram_size_code,
+        rom_size: rom_size_from_code(rom_size_code),
+        ram_size: ram_size_from_code(ram_size_code),
+        version: rom_buf[VERSION],
+        header_checksum,
+        global_checksum,
+        header_checksum_valid: compute_header_checksum(rom_buf) == header_checksum,
+        global_checksum_valid: compute_global_checksum(rom_buf) == global_checksum,
+        logo_valid: rom_buf[NINTENDO_LOGO_START..NINTENDO_LOGO_START + NINTENDO_LOGO.len()]
+            == NINTENDO_LOGO,
+    })
+}
+
+pub fn rom_size_from_code(code: u8) -> Option<usize> {
+    match code {
+        0x00..=0x08 => Some(0x8000 << code),
+        0x52 => Some(72 * 0x4000),
+        0x53 => Some(80 * 0x4000),
+        0x54 => Some(96 * 0x4000),
+        _ => None,
+    }
+}
+
+pub fn ram_size_from_code(code: u8) -> Option<usize> {
+    match code {
+        0 => Some(0),
+        1 => Some(0x800),
+        2 => Some(0x2000),
+        3 => Some(0x8000),
+        4 => Some(0x20000),
+        5 => Some(0x10000),
+        _ => None,
+    }
+}
+
+/// The checksum of 0x134-0x14C checked by the boot ROM
+pub fn compute_header_checksum(rom_buf: &[u8]) -> u8 {
+    rom_buf[TITLE_START..HEADER_CHECKSUM]
+        .iter()
+        .fold(0u8, |sum, b| sum.wrapping_sub(*b).wrapping_sub(1))
+}
+
+/// The sum of every byte in the ROM apart from the checksum itself
+pub fn compute_global_checksum(rom_buf: &[u8]) -> u16 {
+    rom_buf
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| *i != GLOBAL_CHECKSUM && *i != GLOBAL_CHECKSUM + 1)
+        .fold(0u16, |sum, (_, b)| sum.wrapping_add(*b as u16))
+}
+
+// Header strings are padded with zeros and may contain garbage on some dumps
+fn ascii_string(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .take_while(|b| **b != 0)
+        .map(|b| {
+            if b.is_ascii_graphic() || *b == b' ' {
+                *b as char
+            } else {
+                '?'
+            }
+        })
+        .collect::<String>()
+        .trim_end()
+        .to_string()
+}
@@ -1,5 +1,6 @@
 use crate::game_boy::GameBoy;
 use crate::memory::Register;
+use crate::save_state::{SaveState, SaveStateError, StateReader, StateWriter};
 
 const CLOCK_SPEED: u64 = 4_194_304;
 const DIVIDER_TICK: u64 = CLOCK_SPEED / 16_384;
@@ -55,3 +56,14 @@ impl Clock {
         self.ticks += num_cycle as u64;
     }
 }
+
+impl SaveState for Clock {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u64(self.ticks);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.ticks = reader.read_u64()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,292 @@
+use crate::cartridge_info::{CartridgeInfo, Licensee};
+
+const TITLE_START: usize = 0x134;
+const TITLE_END: usize = 0x144;
+// Titles that share a checksum are told apart by their fourth letter
+const FOURTH_LETTER: usize = TITLE_START + 3;
+
+/// The colors the CGB uses for a game made for the original Game Boy. Each
+/// set holds four 15-bit BGR colors, one per shade from lightest to darkest.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct CompatibilityPalette {
+    pub background: [u16; 4],
+    pub object0: [u16; 4],
+    pub object1: [u16; 4],
+}
+
+/// The button combos that can be held during the CGB boot animation to pick
+/// one of the built in palettes
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PaletteShortcut {
+    Up,
+    UpA,
+    UpB,
+    Left,
+    LeftA,
+    LeftB,
+    Down,
+    DownA,
+    DownB,
+    Right,
+    RightA,
+    RightB,
+}
+
+impl PaletteShortcut {
+    pub const ALL: [PaletteShortcut; 12] = [
+        PaletteShortcut::Up,
+        PaletteShortcut::UpA,
+        PaletteShortcut::UpB,
+        PaletteShortcut::Left,
+        PaletteShortcut::LeftA,
+        PaletteShortcut::LeftB,
+        PaletteShortcut::Down,
+        PaletteShortcut::DownA,
+        PaletteShortcut::DownB,
+        PaletteShortcut::Right,
+        PaletteShortcut::RightA,
+        PaletteShortcut::RightB,
+    ];
+
+    pub fn palette(self) -> CompatibilityPalette {
+        match self {
+            PaletteShortcut::Up => uniform(BROWN),
+            PaletteShortcut::UpA => CompatibilityPalette {
+                background: RED,
+                object0: GREEN,
+                object1: BLUE,
+            },
+            PaletteShortcut::UpB => CompatibilityPalette {
+                background: DARK_BROWN,
+                object0: BROWN,
+                object1: BROWN,
+            },
+            PaletteShortcut::Left => CompatibilityPalette {
+                background: BLUE,
+                object0: RED,
+                object1: GREEN,
+            },
+            PaletteShortcut::LeftA => CompatibilityPalette {
+                background: DARK_BLUE,
+                object0: RED,
+                object1: BROWN,
+            },
+            PaletteShortcut::LeftB => uniform(GRAYSCALE),
+            PaletteShortcut::Down => uniform(PASTEL),
+            PaletteShortcut::DownA => uniform(ORANGE),
+            PaletteShortcut::DownB => CompatibilityPalette {
+                background: YELLOW,
+                object0: BLUE,
+                object1: GREEN,
+            },
+            PaletteShortcut::Right => uniform(LIME),
+            PaletteShortcut::RightA => CompatibilityPalette {
+                background: DARK_GREEN,
+                object0: RED,
+                object1: RED,
+            },
+            PaletteShortcut::RightB => uniform(INVERTED),
+        }
+    }
+}
+
+/// Picks the palette the CGB boot ROM would use for a game. Only games
+/// published by Nintendo are looked up by the checksum of their title, every
+/// other game gets the default palette. The lookup uses the complete tables
+/// of the boot ROM, so it matches the hardware for every title.
+pub fn title_palette(info: &CartridgeInfo, rom: &[u8]) -> CompatibilityPalette {
+    let is_nintendo = match &info.licensee {
+        Licensee::Old(code) => *code == 0x01,
+        Licensee::New(code) => code == "01",
+    };
+    if !is_nintendo || rom.len() < TITLE_END {
+        return combination_palette(DEFAULT_COMBINATION);
+    }
+
+    let checksum = title_checksum(rom);
+    let combination = TITLE_CHECKSUMS
+        .iter()
+        .position(|entry| *entry == checksum)
+        .and_then(|index| {
+            if index < FIRST_SHARED_CHECKSUM {
+                return Some(TITLE_COMBINATIONS[index]);
+            }
+            // A shared checksum has one candidate letter per row of the
+            // letter table
+            (index - FIRST_SHARED_CHECKSUM..FOURTH_LETTERS.len())
+                .step_by(SHARED_CHECKSUMS)
+                .find(|letter| FOURTH_LETTERS[*letter] == rom[FOURTH_LETTER])
+                .map(|letter| TITLE_COMBINATIONS[FIRST_SHARED_CHECKSUM + letter])
+        })
+        .unwrap_or(DEFAULT_COMBINATION);
+    combination_palette(combination)
+}
+
+/// The sum of the 16 title bytes at 0x134-0x143
+pub fn title_checksum(rom: &[u8]) -> u8 {
+    rom[TITLE_START..TITLE_END]
+        .iter()
+        .fold(0u8, |sum, b| sum.wrapping_add(*b))
+}
+
+fn combination_palette(combination: u8) -> CompatibilityPalette {
+    let [object0, object1, background] = PALETTE_COMBINATIONS[combination as usize];
+    let colors = |offset: u8| {
+        let offset = offset as usize;
+        let mut palette = [0; 4];
+        palette.copy_from_slice(&BOOT_ROM_COLORS[offset..offset + 4]);
+        palette
+    };
+    CompatibilityPalette {
+        background: colors(background),
+        object0: colors(object0),
+        object1: colors(object1),
+    }
+}
+
+const DEFAULT_COMBINATION: u8 = 0;
+
+// The checksums from this index on are shared by several titles
+const FIRST_SHARED_CHECKSUM: usize = 0x41;
+const SHARED_CHECKSUMS: usize = TITLE_CHECKSUMS.len() - FIRST_SHARED_CHECKSUM;
+
+const TITLE_CHECKSUMS: [u8; 79] = [
+    0x00, 0x88, 0x16, 0x36, 0xD1, 0xDB, 0xF2, 0x3C, 0x8C, 0x92, 0x3D, 0x5C, 0x58, 0xC9, 0x3E, 0x70,
+    0x1D, 0x59, 0x69, 0x19, 0x35, 0xA8, 0x14, 0xAA, 0x75, 0x95, 0x99, 0x34, 0x6F, 0x15, 0xFF, 0x97,
+    0x4B, 0x90, 0x17, 0x10, 0x39, 0xF7, 0xF6, 0xA2, 0x49, 0x4E, 0x43, 0x68, 0xE0, 0x8B, 0xF0, 0xCE,
+    0x0C, 0x29, 0xE8, 0xB7, 0x86, 0x9A, 0x52, 0x01, 0x9D, 0x71, 0x9C, 0xBD, 0x5D, 0x6D, 0x67, 0x3F,
+    0xE8, 0xB3, 0x46, 0x28, 0xA5, 0xC6, 0xD3, 0x27, 0x61, 0x18, 0x66, 0x6A, 0xBF, 0x0D, 0xF4,
+];
+
+// Rows of one letter per shared checksum, in the order of the checksums
+const FOURTH_LETTERS: &[u8; 29] = b"BEFAARBEKEK R-URAR INAILICE R";
+
+// The palette combination of every checksum below 0x41, followed by the one
+// of every entry of the letter table
+const TITLE_COMBINATIONS: [u8; 94] = [
+    0, 4, 5, 35, 34, 3, 31, 15, 10, 5, 19, 36, 7, 37, 30, 44, 21, 32, 31, 20, 5, 33, 13, 14, 5, 29,
+    5, 18, 9, 3, 2, 26, 25, 25, 41, 42, 26, 45, 42, 45, 36, 38, 26, 42, 30, 41, 34, 34, 5, 42, 6,
+    5, 33, 25, 42, 42, 40, 2, 16, 25, 42, 42, 5, 0, 39, 36, 22, 25, 6, 32, 12, 36, 11, 39, 18, 39,
+    24, 31, 50, 17, 46, 6, 27, 0, 47, 41, 41, 0, 0, 34, 23, 18, 29, 28,
+];
+
+// Offsets into BOOT_ROM_COLORS of the object 0, object 1 and background
+// colors. A few of them don't start on a palette boundary.
+const PALETTE_COMBINATIONS: [[u8; 3]; 51] = [
+    [16, 16, 116],
+    [72, 72, 72],
+    [80, 80, 80],
+    [96, 96, 96],
+    [36, 36, 36],
+    [0, 0, 0],
+    [108, 108, 108],
+    [20, 20, 20],
+    [48, 48, 48],
+    [104, 104, 104],
+    [64, 32, 32],
+    [16, 112, 112],
+    [16, 8, 8],
+    [12, 16, 16],
+    [16, 116, 116],
+    [112, 16, 112],
+    [8, 68, 8],
+    [64, 64, 32],
+    [16, 16, 28],
+    [16, 16, 72],
+    [16, 16, 80],
+    [76, 76, 36],
+    [15, 15, 44],
+    [68, 68, 8],
+    [16, 16, 8],
+    [16, 16, 12],
+    [112, 112, 0],
+    [12, 12, 0],
+    [0, 0, 4],
+    [72, 88, 72],
+    [80, 88, 80],
+    [96, 88, 96],
+    [64, 88, 32],
+    [68, 16, 52],
+    [111, 0, 56],
+    [111, 16, 60],
+    [76, 88, 36],
+    [64, 112, 40],
+    [16, 92, 112],
+    [68, 88, 8],
+    [16, 0, 8],
+    [16, 112, 12],
+    [112, 12, 0],
+    [12, 112, 16],
+    [84, 112, 16],
+    [12, 112, 0],
+    [100, 12, 112],
+    [0, 112, 32],
+    [16, 12, 112],
+    [112, 12, 24],
+    [16, 112, 116],
+];
+
+// The 30 palettes of the boot ROM, already in the 15-bit BGR format
+#[rustfmt::skip]
+const BOOT_ROM_COLORS: [u16; 120] = [
+    0x7FFF, 0x32BF, 0x00D0, 0x0000,
+    0x639F, 0x4279, 0x15B0, 0x04CB,
+    0x7FFF, 0x6E31, 0x454A, 0x0000,
+    0x7FFF, 0x1BEF, 0x0200, 0x0000,
+    0x7FFF, 0x421F, 0x1CF2, 0x0000,
+    0x7FFF, 0x5294, 0x294A, 0x0000,
+    0x7FFF, 0x03FF, 0x012F, 0x0000,
+    0x7FFF, 0x03EF, 0x01D6, 0x0000,
+    0x7FFF, 0x42B5, 0x3DC8, 0x0000,
+    0x7E74, 0x03FF, 0x0180, 0x0000,
+    0x67FF, 0x77AC, 0x1A13, 0x2D6B,
+    0x7ED6, 0x4BFF, 0x2175, 0x0000,
+    0x53FF, 0x4A5F, 0x7E52, 0x0000,
+    0x4FFF, 0x7ED2, 0x3A4C, 0x1CE0,
+    0x03ED, 0x7FFF, 0x255F, 0x0000,
+    0x036A, 0x021F, 0x03FF, 0x7FFF,
+    0x7FFF, 0x01DF, 0x0112, 0x0000,
+    0x231F, 0x035F, 0x00F2, 0x0009,
+    0x7FFF, 0x03EA, 0x011F, 0x0000,
+    0x299F, 0x001A, 0x000C, 0x0000,
+    0x7FFF, 0x027F, 0x001F, 0x0000,
+    0x7FFF, 0x03E0, 0x0206, 0x0120,
+    0x7FFF, 0x7EEB, 0x001F, 0x7C00,
+    0x7FFF, 0x3FFF, 0x7E00, 0x001F,
+    0x7FFF, 0x03FF, 0x001F, 0x0000,
+    0x03FF, 0x001F, 0x000C, 0x0000,
+    0x7FFF, 0x033F, 0x0193, 0x0000,
+    0x0000, 0x4200, 0x037F, 0x7FFF,
+    0x7FFF, 0x7E8C, 0x7C00, 0x0000,
+    0x7FFF, 0x1BEF, 0x6180, 0x0000,
+];
+
+fn uniform(colors: [u16; 4]) -> CompatibilityPalette {
+    CompatibilityPalette {
+        background: colors,
+        object0: colors,
+        object1: colors,
+    }
+}
+
+// Converts a 24-bit RGB color to the 15-bit BGR format of the palette RAM
+const fn rgb(color: u32) -> u16 {
+    let red = (color >> 19) & 0x1F;
+    let green = (color >> 11) & 0x1F;
+    let blue = (color >> 3) & 0x1F;
+    (red | (green << 5) | (blue << 10)) as u16
+}
+
+const BROWN: [u16; 4] = [rgb(0xFFFFFF), rgb(0xFFAD63), rgb(0x843100), rgb(0x000000)];
+const DARK_BROWN: [u16; 4] = [rgb(0xFFE6C5), rgb(0xCE9C84), rgb(0x846B29), rgb(0x5A3108)];
+const RED: [u16; 4] = [rgb(0xFFFFFF), rgb(0xFF8484), rgb(0x943A3A), rgb(0x000000)];
+const GREEN: [u16; 4] = [rgb(0xFFFFFF), rgb(0x7BFF31), rgb(0x008400), rgb(0x000000)];
+const BLUE: [u16; 4] = [rgb(0xFFFFFF), rgb(0x63A5FF), rgb(0x0000FF), rgb(0x000000)];
+const DARK_BLUE: [u16; 4] = [rgb(0xFFFFFF), rgb(0x8C8CDE), rgb(0x52528C), rgb(0x000000)];
+const GRAYSCALE: [u16; 4] = [rgb(0xFFFFFF), rgb(0xA5A5A5), rgb(0x525252), rgb(0x000000)];
+const PASTEL: [u16; 4] = [rgb(0xFFFFA5), rgb(0xFF9494), rgb(0x9494FF), rgb(0x000000)];
+const ORANGE: [u16; 4] = [rgb(0xFFFFFF), rgb(0xFFFF00), rgb(0xFF0000), rgb(0x000000)];
+const YELLOW: [u16; 4] = [rgb(0xFFFFFF), rgb(0xFFFF00), rgb(0x7B4A00), rgb(0x000000)];
+const LIME: [u16; 4] = [rgb(0xFFFFFF), rgb(0x52FF00), rgb(0xFF4200), rgb(0x000000)];
+const DARK_GREEN: [u16; 4] = [rgb(0xFFFFFF), rgb(0x7BFF31), rgb(0x0063C5), rgb(0x000000)];
+const INVERTED: [u16; 4] = [rgb(0x000000), rgb(0x008484), rgb(0xFFDE00), rgb(0xFFFFFF)];
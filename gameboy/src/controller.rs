@@ -1,11 +1,16 @@
 use crate::game_boy::GameBoy;
 use crate::memory::Register;
+use crate::save_state::{SaveState, SaveStateError, StateReader, StateWriter};
+use crate::Button;
 
 const JOYPAD_REG_ADDR: u16 = 0xFF00;
 
+// The Super Game Boy multiplayer adapter connects up to four joypads
+pub const MAX_PLAYERS: usize = 4;
+
 pub struct Controller {
-    direction_states: u8,
-    button_states: u8,
+    direction_states: [u8; MAX_PLAYERS],
+    button_states: [u8; MAX_PLAYERS],
 }
 
 fn set_bit(val: u8, mask: u8, set: bool) -> u8 {
@@ -19,53 +24,91 @@ fn set_bit(val: u8, mask: u8, set: bool) -> u8 {
 impl Controller {
     pub fn new() -> Controller {
         Controller {
-            direction_states: 0x0F,
-            button_states: 0x0F,
+            direction_states: [0x0F; MAX_PLAYERS],
+            button_states: [0x0F; MAX_PLAYERS],
         }
     }
 
+    /// Updates a button of one of the joypads, the first player uses the
+    /// joypad built into the Game Boy
+    pub fn button_changed(&mut self, player: usize, button: Button, pressed: bool) {
+        let (states, mask) = match button {
+            Button::Down => (&mut self.direction_states, 0b1000),
+            Button::Up => (&mut self.direction_states, 0b0100),
+            Button::Left => (&mut self.direction_states, 0b0010),
+            Button::Right => (&mut self.direction_states, 0b0001),
+            Button::Start => (&mut self.button_states, 0b1000),
+            Button::Select => (&mut self.button_states, 0b0100),
+            Button::B => (&mut self.button_states, 0b0010),
+            Button::A => (&mut self.button_states, 0b0001),
+        };
+        states[player] = set_bit(states[player], mask, !pressed);
+    }
+
     pub fn down_changed(&mut self, pressed: bool) {
-        self.direction_states = set_bit(self.direction_states, 0b1000, !pressed);
+        self.button_changed(0, Button::Down, pressed);
     }
 
     pub fn up_changed(&mut self, pressed: bool) {
-        self.direction_states = set_bit(self.direction_states, 0b0100, !pressed);
+        self.button_changed(0, Button::Up, pressed);
     }
 
     pub fn left_changed(&mut self, pressed: bool) {
-        self.direction_states = set_bit(self.direction_states, 0b0010, !pressed);
+        self.button_changed(0, Button::Left, pressed);
     }
 
     pub fn right_changed(&mut self, pressed: bool) {
-        self.direction_states = set_bit(self.direction_states, 0b0001, !pressed);
+        self.button_changed(0, Button::Right, pressed);
     }
 
     pub fn start_changed(&mut self, pressed: bool) {
-        self.button_states = set_bit(self.button_states, 0b1000, !pressed);
+        self.button_changed(0, Button::Start, pressed);
     }
 
     pub fn select_changed(&mut self, pressed: bool) {
-        self.button_states = set_bit(self.button_states, 0b0100, !pressed);
+        self.button_changed(0, Button::Select, pressed);
     }
 
     pub fn b_changed(&mut self, pressed: bool) {
-        self.button_states = set_bit(self.button_states, 0b0010, !pressed);
+        self.button_changed(0, Button::B, pressed);
     }
 
     pub fn a_changed(&mut self, pressed: bool) {
-        self.button_states = set_bit(self.button_states, 0b0001, !pressed);
+        self.button_changed(0, Button::A, pressed);
     }
 
-    pub fn update_joypad_register(&self, gb: &mut GameBoy) {
+    /// Shows the state of the selected buttons of a joypad in P1. With
+    /// neither group selected the Super Game Boy puts the number of the
+    /// current joypad there instead, counting down from 0xF.
+    pub fn update_joypad_register(&self, gb: &mut GameBoy, player: usize) {
         let joypad_select = gb.memory.get_register(Register::Joypad) & 0xF0;
         if joypad_select & 0x20 == 0x00 {
             gb.memory
-                .set_register(Register::Joypad, joypad_select | self.button_states);
+                .set_register(Register::Joypad, joypad_select | self.button_states[player]);
         }
 
         if joypad_select & 0x10 == 0x00 {
+            gb.memory.set_register(
+                Register::Joypad,
+                joypad_select | self.direction_states[player],
+            );
+        }
+
+        if joypad_select & 0x30 == 0x30 && player > 0 {
             gb.memory
-                .set_register(Register::Joypad, joypad_select | self.direction_states);
+                .set_register(Register::Joypad, joypad_select | (0x0F - player as u8));
         }
     }
 }
+
+impl SaveState for Controller {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bytes(&self.direction_states);
+        writer.write_bytes(&self.button_states);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        reader.read_bytes_into(&mut self.direction_states)?;
+        reader.read_bytes_into(&mut self.button_states)
+    }
+}
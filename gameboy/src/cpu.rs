@@ -1,6 +1,7 @@
 use crate::cb_instructions::get_cb_instruction_set;
 use crate::instructions::get_instruction_set;
 use crate::instructions::Instruction;
+use crate::save_state::{SaveState, SaveStateError, StateReader, StateWriter};
 use crate::util::concat_bytes;
 use crate::util::get_lower;
 use crate::util::get_upper;
@@ -67,6 +68,9 @@ pub struct Cpu {
     pub interrupt_enable_master: bool,
     pub flag: FlagRegister,
     pub is_halted: bool,
+    // Set by illegal opcodes, the CPU stops responding until the system is
+    // reset
+    pub is_locked: bool,
 }
 
 impl fmt::Display for Cpu {
@@ -88,6 +92,7 @@ impl Cpu {
             interrupt_enable_master: false,
             flag: FlagRegister::new(),
             is_halted: false,
+            is_locked: false,
         }
     }
 
@@ -164,6 +169,52 @@ impl Cpu {
         self.sp = 0xFFFE;
         self.pc = 0x0000;
     }
+
+    /// The registers left behind by the CGB boot ROM, games check for A being
+    /// 0x11 to detect the CGB
+    pub fn power_on_cgb(&mut self) {
+        self.set_af(0x1180);
+        self.bc = 0x0000;
+        self.de = 0xFF56;
+        self.hl = 0x000D;
+        self.sp = 0xFFFE;
+    }
+
+    /// The registers left behind by the Super Game Boy boot ROM
+    pub fn power_on_sgb(&mut self) {
+        self.set_af(0x0100);
+        self.bc = 0x0014;
+        self.de = 0x0000;
+        self.hl = 0xC060;
+        self.sp = 0xFFFE;
+    }
+}
+
+impl SaveState for Cpu {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u16(self.get_af());
+        writer.write_u16(self.bc);
+        writer.write_u16(self.de);
+        writer.write_u16(self.hl);
+        writer.write_u16(self.sp);
+        writer.write_u16(self.pc);
+        writer.write_bool(self.interrupt_enable_master);
+        writer.write_bool(self.is_halted);
+        writer.write_bool(self.is_locked);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.set_af(reader.read_u16()?);
+        self.bc = reader.read_u16()?;
+        self.de = reader.read_u16()?;
+        self.hl = reader.read_u16()?;
+        self.sp = reader.read_u16()?;
+        self.pc = reader.read_u16()?;
+        self.interrupt_enable_master = reader.read_bool()?;
+        self.is_halted = reader.read_bool()?;
+        self.is_locked = reader.read_bool()?;
+        Ok(())
+    }
 }
 
 pub struct InstructionSet {
@@ -1,3 +1,4 @@
+use crate::cartridge::LoadError;
 use crate::cpu::Cpu;
 use crate::memory::Memory;
 
@@ -21,16 +22,16 @@ impl GameBoy {
         self.memory.power_on();
     }
 
-    pub fn load_boot_rom(&mut self, boot_buf: &[u8]) {
-        self.memory.load_boot_rom(boot_buf);
+    pub fn load_boot_rom(&mut self, boot_buf: &[u8]) -> Result<(), LoadError> {
+        self.memory.load_boot_rom(boot_buf)
     }
 
-    pub fn load_rom(&mut self, rom_buf: &[u8]) {
-        self.memory.load_rom(rom_buf);
+    pub fn load_rom(&mut self, rom_buf: &[u8]) -> Result<(), LoadError> {
+        self.memory.load_rom(rom_buf)
     }
 
-    pub fn load_save_data(&mut self, save_buf: &[u8]) {
-        self.memory.load_external_ram(save_buf);
+    pub fn load_save_data(&mut self, save_buf: &[u8]) -> Result<(), LoadError> {
+        self.memory.load_external_ram(save_buf)
     }
 
     pub fn request_exit(&mut self) {
@@ -1,5 +1,6 @@
 use crate::game_boy::GameBoy;
 use crate::memory::Register;
+use crate::save_state::{SaveState, SaveStateError, StateReader, StateWriter};
 
 const LCD_STATUS_FLAG_MASK: u8 = 0b1111_1000;
 const LCD_STATUS_COINCIDENCE_INT: u8 = 0b0100_0000;
@@ -25,6 +26,34 @@ enum TileAddressingMode {
     Signed,
 }
 
+// CGB background map attributes, stored in VRAM bank 1 at the same address as
+// the tile index
+const BG_ATTRIBUTE_PALETTE: u8 = 0b0000_0111;
+const BG_ATTRIBUTE_VRAM_BANK: u8 = 0b0000_1000;
+const BG_ATTRIBUTE_FLIP_X: u8 = 0b0010_0000;
+const BG_ATTRIBUTE_FLIP_Y: u8 = 0b0100_0000;
+const BG_ATTRIBUTE_PRIORITY: u8 = 0b1000_0000;
+
+const SPRITE_ATTRIBUTE_CGB_PALETTE: u8 = 0b0000_0111;
+const SPRITE_ATTRIBUTE_VRAM_BANK: u8 = 0b0000_1000;
+
+// One row of 8 pixels of a background or window tile
+#[derive(Copy, Clone)]
+struct TileRow {
+    pattern: u16,
+    attributes: u8,
+}
+
+impl TileRow {
+    fn palette_index(&self, x: u16) -> u8 {
+        if self.attributes & BG_ATTRIBUTE_FLIP_X != 0 {
+            get_palette_index(self.pattern, (x % 8) as u8)
+        } else {
+            get_palette_index(self.pattern, (7 - (x % 8)) as u8)
+        }
+    }
+}
+
 pub struct Gpu {
     pub window_buf: Box<[u8; BUFFER_SIZE]>,
     frame_step: u32,
@@ -32,6 +61,9 @@ pub struct Gpu {
     pub scan_lines_rendered: u64,
     sprites: [Sprite; 40],
     sprite_order: [usize; 10],
+    // The shade of every pixel of the last frame drawn in DMG mode, which the
+    // Super Game Boy colors itself
+    shades: Vec<u8>,
 }
 
 #[derive(Debug, Copy, Clone)]
@@ -78,7 +110,12 @@ impl Sprite {
         };
 
         let tile_pattern_addr = get_sprite_tile_addr(self.tile_pattern_index);
-        self.pattern = gb.memory.get_word(tile_pattern_addr + (sprite_y * 2));
+        let bank = if gb.memory.is_cgb() {
+            self.vram_bank()
+        } else {
+            0
+        };
+        self.pattern = read_tile_row(gb, bank, tile_pattern_addr + (sprite_y * 2));
     }
 
     pub fn left(&self) -> i16 {
@@ -105,6 +142,18 @@ impl Sprite {
         self.attributes & 0x10 == 0x10
     }
 
+    fn get_cgb_palette(&self) -> u8 {
+        self.attributes & SPRITE_ATTRIBUTE_CGB_PALETTE
+    }
+
+    fn vram_bank(&self) -> usize {
+        if self.attributes & SPRITE_ATTRIBUTE_VRAM_BANK != 0 {
+            1
+        } else {
+            0
+        }
+    }
+
     pub fn above_bg(&self) -> bool {
         self.attributes & 0x80 == 0x00
     }
@@ -150,9 +199,15 @@ impl Gpu {
             total_render_ns: 0,
             sprites,
             sprite_order,
+            shades: vec![0; HORIZONTAL_RES as usize * VERTICAL_RES as usize],
         }
     }
 
+    /// The shades from 0 to 3 that make up the last frame of a DMG game
+    pub fn shades(&self) -> &[u8] {
+        &self.shades
+    }
+
     /// Updates GPU state and returns whether the frame buffer has a completed
     /// frame
     pub fn update(&mut self, gb: &mut GameBoy, framebuffer: &mut [u8], ticks: u8) -> bool {
@@ -204,6 +259,7 @@ impl Gpu {
                 interrupt_flags |= 0b10;
             }
             self.draw_scan_line(gb, framebuffer, scan_line);
+            gb.memory.hblank_started();
         }
 
         let mut coincidence_flag = status & LCD_STATUS_COINCIDENCE;
@@ -246,6 +302,9 @@ impl Gpu {
 
     pub fn draw_scan_line(&mut self, gb: &GameBoy, framebuffer: &mut [u8], scan_line: u8) {
         let start = time::Instant::now();
+        let is_cgb = gb.memory.is_cgb();
+        // DMG games on the CGB look up their shades in the color palettes
+        let is_compatibility_mode = gb.memory.is_compatibility_mode();
         let window_y = (scan_line as i16) - (window_y_offset(gb) as i16);
 
         let bg_palette = bg_palette(gb);
@@ -258,12 +317,22 @@ impl Gpu {
             y_bg -= 256;
         }
 
-        let sprite_count =
-            get_sprites_in_scan_line(gb, &mut self.sprites, &mut self.sprite_order, scan_line);
+        // The DMG draws sprites further left on top, the CGB uses OAM order
+        let sprites_sorted_by_x = !is_cgb;
+        let sprite_count = get_sprites_in_scan_line(
+            gb,
+            &mut self.sprites,
+            &mut self.sprite_order,
+            scan_line,
+            sprites_sorted_by_x,
+        );
         let is_window_enabled = window_enabled(gb);
         let window_map_id = window_tile_map(gb);
         let window_x_offset = window_x_offset(gb) as i16;
+        // On the CGB this bit no longer hides the background, instead it
+        // makes sprites draw over it regardless of priority
         let is_bg_enabled = bg_enabled(gb);
+        let sprites_always_on_top = is_cgb && !is_bg_enabled;
         let bg_map_id = bg_tile_map(gb);
         let sprite_palette1 = gb.memory.get_register(Register::ObjectPalette1Data);
         let sprite_palette0 = gb.memory.get_register(Register::ObjectPalette0Data);
@@ -272,84 +341,111 @@ impl Gpu {
         } else {
             TileAddressingMode::Signed
         };
+        let empty_row = TileRow {
+            pattern: 0,
+            attributes: 0,
+        };
         let mut current_bg_tile_index = 0u16;
-        let mut current_bg_tile_pattern = 0u16;
+        let mut current_bg_tile = empty_row;
         let mut current_window_tile_index = 0u16;
-        let mut current_window_tile_pattern = 0u16;
+        let mut current_window_tile = empty_row;
         let mut start_sprite_index = 0usize;
 
         for x in 0..HORIZONTAL_RES {
-            let mut draw_bg = true;
+            let shade_index = (scan_line as usize * HORIZONTAL_RES as usize) + x as usize;
+            // The window covers the background and takes its place when
+            // deciding whether sprites are visible
             let window_x = (x as i16) - window_x_offset + 7;
-            if is_window_enabled && window_y >= 0 && window_x >= 0 {
-                let window_x = window_x as u16;
-                let window_y = window_y as u16;
-                let next_window_tile_index = get_tile_index(window_x, window_y);
-                if x == 0 || current_window_tile_index != next_window_tile_index {
-                    current_window_tile_index = next_window_tile_index;
-                    current_window_tile_pattern = get_tile_pattern(
-                        gb,
-                        window_map_id,
-                        tile_addressing_mode,
-                        current_window_tile_index,
-                        window_y,
-                    );
-                }
+            let (bg_palette_index, bg_attributes) =
+                if is_window_enabled && window_y >= 0 && window_x >= 0 {
+                    let window_x = window_x as u16;
+                    let window_y = window_y as u16;
+                    let next_window_tile_index = get_tile_index(window_x, window_y);
+                    if x == 0 || current_window_tile_index != next_window_tile_index {
+                        current_window_tile_index = next_window_tile_index;
+                        current_window_tile = get_tile_row(
+                            gb,
+                            window_map_id,
+                            tile_addressing_mode,
+                            current_window_tile_index,
+                            window_y,
+                        );
+                    }
 
-                let window_palette_index =
-                    get_palette_index(current_window_tile_pattern, (7 - (window_x % 8)) as u8);
-                let window_color_id = get_palette_color(bg_palette, window_palette_index);
-                set_pixel(framebuffer, x, scan_line, window_color_id);
-                draw_bg = false;
-            }
+                    (
+                        current_window_tile.palette_index(window_x),
+                        current_window_tile.attributes,
+                    )
+                } else if is_bg_enabled || is_cgb {
+                    let mut x_bg = (x as u16) + scroll_x;
+                    if x_bg > 255 {
+                        x_bg -= 256;
+                    }
 
-            let bg_palette_index = if is_bg_enabled {
-                let mut x_bg = (x as u16) + scroll_x;
-                if x_bg > 255 {
-                    x_bg -= 256;
-                }
+                    let next_bg_tile_index = get_tile_index(x_bg, y_bg);
+                    if x == 0 || current_bg_tile_index != next_bg_tile_index {
+                        current_bg_tile_index = next_bg_tile_index;
+                        current_bg_tile = get_tile_row(
+                            gb,
+                            bg_map_id,
+                            tile_addressing_mode,
+                            current_bg_tile_index,
+                            y_bg,
+                        );
+                    }
 
-                let next_bg_tile_index = get_tile_index(x_bg, y_bg);
-                if x == 0 || current_bg_tile_index != get_tile_index(x_bg, y_bg) {
-                    current_bg_tile_index = next_bg_tile_index;
-                    current_bg_tile_pattern = get_tile_pattern(
-                        gb,
-                        bg_map_id,
-                        tile_addressing_mode,
-                        current_bg_tile_index,
-                        y_bg,
-                    );
-                }
-                get_palette_index(current_bg_tile_pattern, (7 - (x_bg % 8)) as u8)
-            } else {
-                0 //white
-            };
+                    (
+                        current_bg_tile.palette_index(x_bg),
+                        current_bg_tile.attributes,
+                    )
+                } else {
+                    (0, 0) //white
+                };
+
+            let bg_has_priority = bg_attributes & BG_ATTRIBUTE_PRIORITY != 0;
 
             // Only first 10 sprites are rendered
+            let mut draw_bg = true;
             for i in start_sprite_index..sprite_count {
                 let sprite = &self.sprites[self.sprite_order[i]];
 
                 if sprite.left() > x as i16 {
-                    break;
+                    if sprites_sorted_by_x {
+                        break;
+                    }
+                    continue;
                 }
 
                 if sprite.right() <= x as i16 {
-                    start_sprite_index = i + 1;
+                    if sprites_sorted_by_x {
+                        start_sprite_index = i + 1;
+                    }
                     continue;
                 }
 
-                if sprite.left() <= (x as i16)
-                    && sprite.right() > (x as i16)
-                    && (sprite.above_bg() || bg_palette_index == 0)
-                {
-                    let sprite_pattern = sprite.get_tile_pattern();
-                    let sprite_x = if sprite.is_mirrored_horizontally() {
-                        (((x as i16) - sprite.left()) % 8) as u8
+                let sprite_pattern = sprite.get_tile_pattern();
+                let sprite_x = if sprite.is_mirrored_horizontally() {
+                    (((x as i16) - sprite.left()) % 8) as u8
+                } else {
+                    (7 - (((x as i16) - sprite.left()) % 8)) as u8
+                };
+                let sprite_palette_index = get_palette_index(sprite_pattern, sprite_x);
+                if sprite_palette_index == 0 {
+                    continue;
+                }
+
+                // The highest priority opaque sprite pixel hides the sprites
+                // below it even when the background is drawn over it
+                let visible = sprites_always_on_top
+                    || bg_palette_index == 0
+                    || (sprite.above_bg() && !bg_has_priority);
+                if visible {
+                    if is_cgb {
+                        let color = gb
+                            .memory
+                            .obj_palette_color(sprite.get_cgb_palette(), sprite_palette_index);
+                        set_color_pixel(framebuffer, x, scan_line, color);
                     } else {
-                        (7 - (((x as i16) - sprite.left()) % 8)) as u8
-                    };
-                    let sprite_palette_index = get_palette_index(sprite_pattern, sprite_x);
-                    if sprite_palette_index != 0 {
                         let sprite_palette = if sprite.get_palette() {
                             sprite_palette1
                         } else {
@@ -357,16 +453,37 @@ impl Gpu {
                         };
                         let sprite_color_id =
                             get_palette_color(sprite_palette, sprite_palette_index);
-                        set_pixel(framebuffer, x, scan_line, sprite_color_id);
-                        draw_bg = false;
-                        break;
+                        self.shades[shade_index] = sprite_color_id;
+                        if is_compatibility_mode {
+                            let color = gb
+                                .memory
+                                .obj_palette_color(sprite.get_palette() as u8, sprite_color_id);
+                            set_color_pixel(framebuffer, x, scan_line, color);
+                        } else {
+                            set_pixel(framebuffer, x, scan_line, sprite_color_id);
+                        }
                     }
+                    draw_bg = false;
                 }
+                break;
             }
 
             if draw_bg {
-                let bg_color_id = get_palette_color(bg_palette, bg_palette_index);
-                set_pixel(framebuffer, x, scan_line, bg_color_id);
+                if is_cgb {
+                    let color = gb
+                        .memory
+                        .bg_palette_color(bg_attributes & BG_ATTRIBUTE_PALETTE, bg_palette_index);
+                    set_color_pixel(framebuffer, x, scan_line, color);
+                } else {
+                    let bg_color_id = get_palette_color(bg_palette, bg_palette_index);
+                    self.shades[shade_index] = bg_color_id;
+                    if is_compatibility_mode {
+                        let color = gb.memory.bg_palette_color(0, bg_color_id);
+                        set_color_pixel(framebuffer, x, scan_line, color);
+                    } else {
+                        set_pixel(framebuffer, x, scan_line, bg_color_id);
+                    }
+                }
             }
         }
 
@@ -375,6 +492,42 @@ impl Gpu {
     }
 }
 
+impl SaveState for Gpu {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u32(self.frame_step);
+        for sprite in self.sprites.iter() {
+            writer.write_u16(sprite.y_pos as u16);
+            writer.write_u16(sprite.x_pos as u16);
+            writer.write_u8(sprite.tile_pattern_index);
+            writer.write_u8(sprite.attributes);
+            writer.write_u16(sprite.height as u16);
+            writer.write_u16(sprite.pattern);
+        }
+        for index in self.sprite_order.iter() {
+            writer.write_u8(*index as u8);
+        }
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.frame_step = reader.read_u32()?;
+        for sprite in self.sprites.iter_mut() {
+            sprite.y_pos = reader.read_u16()? as i16;
+            sprite.x_pos = reader.read_u16()? as i16;
+            sprite.tile_pattern_index = reader.read_u8()?;
+            sprite.attributes = reader.read_u8()?;
+            sprite.height = reader.read_u16()? as i16;
+            sprite.pattern = reader.read_u16()?;
+        }
+        for index in self.sprite_order.iter_mut() {
+            *index = reader.read_u8()? as usize;
+            if *index >= self.sprites.len() {
+                return Err(SaveStateError::InvalidData("sprite index out of range"));
+            }
+        }
+        Ok(())
+    }
+}
+
 fn set_pixel(framebuffer: &mut [u8], x: u8, y: u8, color_id: u8) {
     let color = get_color(color_id);
     let pixel_index = 4 * ((y as usize * HORIZONTAL_RES as usize) + x as usize);
@@ -384,6 +537,21 @@ fn set_pixel(framebuffer: &mut [u8], x: u8, y: u8, color_id: u8) {
     framebuffer[pixel_index + 3] = 255u8;
 }
 
+// Writes a 15-bit BGR color from the CGB palette RAM
+fn set_color_pixel(framebuffer: &mut [u8], x: u8, y: u8, color: u16) {
+    let pixel_index = 4 * ((y as usize * HORIZONTAL_RES as usize) + x as usize);
+    framebuffer[pixel_index] = expand_color_channel(color >> 10);
+    framebuffer[pixel_index + 1] = expand_color_channel(color >> 5);
+    framebuffer[pixel_index + 2] = expand_color_channel(color);
+    framebuffer[pixel_index + 3] = 255u8;
+}
+
+// Scales a 5-bit channel to 8 bits so that 0x1F becomes 0xFF
+fn expand_color_channel(channel: u16) -> u8 {
+    let channel = (channel & 0x1F) as u8;
+    (channel << 3) | (channel >> 2)
+}
+
 fn get_color(color_id: u8) -> u8 {
     match color_id {
         3 => 0u8,
@@ -398,6 +566,7 @@ fn get_sprites_in_scan_line(
     sprites: &mut [Sprite; 40],
     order: &mut [usize; 10],
     scan_line: u8,
+    sort_by_x: bool,
 ) -> usize {
     let sprite_size = sprite_size(gb);
     let mut sprite_count = 0usize;
@@ -417,7 +586,7 @@ fn get_sprites_in_scan_line(
         for i_sort in 0..sprite_count {
             let i_other_sprite = order[i_sort];
             let other_sprite = &sprites[i_other_sprite];
-            if sprite.x_pos < other_sprite.x_pos {
+            if sort_by_x && sprite.x_pos < other_sprite.x_pos {
                 inserted = true;
                 sprite_count += 1;
 
@@ -454,19 +623,43 @@ fn get_tile_index(x: u16, y: u16) -> u16 {
     ((y as u16) / 8 * 32) + ((x / 8) as u16)
 }
 
-fn get_tile_pattern(
+fn get_tile_row(
     gb: &GameBoy,
     map_id: bool,
     mode: TileAddressingMode,
     tile_index: u16,
     y: u16,
-) -> u16 {
+) -> TileRow {
     let tile_map_addr = if map_id { 0x9C00 } else { 0x9800 };
-    let tile_pattern_index = gb.memory.get_byte(tile_map_addr + tile_index);
+    let tile_pattern_index = gb.memory.get_vram_byte(0, tile_map_addr + tile_index);
+    let attributes = if gb.memory.is_cgb() {
+        gb.memory.get_vram_byte(1, tile_map_addr + tile_index)
+    } else {
+        0
+    };
+
     let base_tile_pattern_addr = get_bg_tile_addr(mode, tile_pattern_index);
-    let pattern_y = (y % 8) as u16;
-    let tile_pattern = gb.memory.get_word(base_tile_pattern_addr + (pattern_y * 2));
-    tile_pattern
+    let pattern_y = if attributes & BG_ATTRIBUTE_FLIP_Y != 0 {
+        7 - (y % 8)
+    } else {
+        y % 8
+    };
+    let bank = if attributes & BG_ATTRIBUTE_VRAM_BANK != 0 {
+        1
+    } else {
+        0
+    };
+    TileRow {
+        pattern: read_tile_row(gb, bank, base_tile_pattern_addr + (pattern_y * 2)),
+        attributes,
+    }
+}
+
+// Tile rows are two bytes, the low bits of each pixel first
+fn read_tile_row(gb: &GameBoy, bank: usize, address: u16) -> u16 {
+    let lower = gb.memory.get_vram_byte(bank, address);
+    let upper = gb.memory.get_vram_byte(bank, address + 1);
+    ((upper as u16) << 8) | lower as u16
 }
 
 fn display_enabled(gb: &GameBoy) -> bool {
@@ -345,6 +345,11 @@ fn halt(gb: &mut GameBoy, _: u8, _: u8) {
 }
 
 fn stop(gb: &mut GameBoy, a1: u8, _: u8) {
+    // On the CGB, STOP is also used to switch between normal and double speed
+    if gb.memory.switch_speed() {
+        return;
+    }
+
     // gb.cpu.is_halted = true;
     println!("{:04X} STOPPED {}", gb.cpu.pc, a1);
 }
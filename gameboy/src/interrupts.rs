@@ -9,6 +9,10 @@ const SERIAL: u8 = 0x08;
 const JOYPAD: u8 = 0x10;
 
 pub fn check_interrupts(gb: &mut GameBoy) {
+    if gb.cpu.is_locked {
+        return;
+    }
+
     let enabled = gb.memory.get_register(Register::InterruptEnable);
     let flag = gb.memory.get_register(Register::InterruptFlag);
     let interrupts = enabled & flag;
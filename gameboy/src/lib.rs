@@ -1,27 +1,50 @@
+pub mod blip_buffer;
 pub mod c_bindings;
+pub mod cartridge;
+pub mod cartridge_info;
 pub mod cb_instructions;
 pub mod clock;
+pub mod compatibility_palette;
 pub mod controller;
 pub mod cpu;
 pub mod game_boy;
 pub mod gpu;
 pub mod instructions;
 pub mod interrupts;
+pub mod linked_systems;
 pub mod math;
 pub mod mbc1;
+pub mod mbc2;
+pub mod mbc3;
+pub mod mbc5;
 pub mod memory;
+pub mod printer;
+pub mod save_state;
+pub mod serial;
+pub mod sgb;
 pub mod sound;
+pub mod sound_registers;
+pub mod tcp_link;
 pub mod tests;
 pub mod util;
+pub mod wav;
 
-use sound::SoundController;
+use sound::{AudioChannel, AudioSample, SoundController};
 
+use crate::cartridge::{Cartridge, LoadError};
+use crate::cartridge_info::{parse_header, CartridgeInfo, CgbSupport};
 use crate::clock::Clock;
+use crate::compatibility_palette::{title_palette, CompatibilityPalette};
 use crate::controller::Controller;
 use crate::cpu::InstructionSet;
 use crate::gpu::Gpu;
+use crate::memory::Register;
+use crate::serial::{Serial, SerialDevice};
+use crate::sgb::SuperGameBoy;
 
 use crate::game_boy::GameBoy;
+use crate::save_state::{SaveState, SaveStateError, StateReader, StateWriter};
+
 pub struct System {
     gameboy: GameBoy,
     gpu: Gpu,
@@ -29,9 +52,18 @@ pub struct System {
     instruction_set: InstructionSet,
     clock: Clock,
     controller: Controller,
+    serial: Serial,
+    sgb: Option<SuperGameBoy>,
+    // What the LCD shows before the Super Game Boy adds its border
+    lcd_framebuffer: Vec<u8>,
+    cartridge_info: Option<CartridgeInfo>,
     debug_mode: bool,
     checkpoint: time::Instant,
     frame_count: u32,
+    frame_cycles: u32,
+    rumble_cycles: u32,
+    rumble_strength: f32,
+    events: Vec<SystemEvent>,
 }
 
 #[derive(Copy, Clone, PartialEq, Eq)]
@@ -58,50 +90,118 @@ pub struct InputEvent {
     pub state: ButtonState,
 }
 
+/// Something that happened during emulation that the frontend may want to
+/// report, collected with `System::take_events`
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SystemEvent {
+    /// The CPU executed an illegal opcode and locked up like real hardware
+    /// does. Video and sound keep running but the game will not continue.
+    CpuLocked { address: u16, opcode: u8 },
+}
+
 pub struct InitializationOptions<'a> {
     pub boot_rom: Option<&'a [u8]>,
     pub game_rom: &'a [u8],
     pub external_ram: Option<&'a [u8]>,
+    /// Overrides the cartridge hardware that would otherwise be picked from
+    /// the memory bank controller type in the ROM header
+    pub cartridge: Option<Box<dyn Cartridge>>,
+    /// Runs games made for the original Game Boy in the compatibility mode of
+    /// the CGB, colorized with the palette its boot ROM picks for the title
+    pub cgb_compatibility: bool,
+    /// Runs games with Super Game Boy support on the SGB, which adds a border
+    /// and colors the screen. Frames are then 256x224 pixels.
+    pub super_game_boy: bool,
     pub debug_mode: bool,
     pub sound_frequency: u32,
 }
 
 impl System {
-    pub fn new(options: InitializationOptions) -> Self {
+    /// Builds a system running the given game. Fails if the ROM, boot ROM or
+    /// save data can't be loaded.
+    pub fn new(options: InitializationOptions) -> Result<Self, LoadError> {
         let mut gameboy = GameBoy::new();
         let instruction_set = InstructionSet::new();
         let clock = Clock::new();
         let gpu = Gpu::new();
         let sound = SoundController::new(options.sound_frequency);
         let controller = Controller::new();
+        let serial = Serial::new();
 
         gameboy.power_on();
 
+        // Games that use CGB features run on CGB hardware
+        let cartridge_info = parse_header(options.game_rom);
+        let cgb_mode = cartridge_info
+            .as_ref()
+            .is_some_and(|info| info.cgb_support != CgbSupport::None);
+        gameboy.memory.set_cgb_mode(cgb_mode);
+        let sgb_mode = !cgb_mode
+            && options.super_game_boy
+            && cartridge_info.as_ref().is_some_and(|info| info.sgb_support);
+        let compatibility_mode = !cgb_mode && !sgb_mode && options.cgb_compatibility;
+        if compatibility_mode {
+            if let Some(info) = &cartridge_info {
+                let palette = title_palette(info, options.game_rom);
+                gameboy.memory.set_compatibility_palette(&palette);
+            }
+        }
+
         if let Some(boot_rom) = options.boot_rom {
-            gameboy.load_boot_rom(&boot_rom);
+            gameboy.load_boot_rom(boot_rom)?;
         } else {
             gameboy.memory.set_byte(0xFF50, 1);
+            if cgb_mode || compatibility_mode {
+                gameboy.cpu.power_on_cgb();
+            } else if sgb_mode {
+                gameboy.cpu.power_on_sgb();
+            }
             gameboy.cpu.pc = 0x100;
         }
 
-        gameboy.load_rom(options.game_rom);
+        match options.cartridge {
+            Some(cartridge) => gameboy.memory.set_cartridge(cartridge),
+            None => gameboy.load_rom(options.game_rom)?,
+        }
         if let Some(external_ram) = options.external_ram {
             if gameboy.memory.use_battery() {
-                gameboy.load_save_data(external_ram);
+                gameboy.load_save_data(external_ram)?;
             }
         }
 
-        Self {
+        Ok(Self {
             gameboy,
             instruction_set,
             gpu,
             sound,
             clock,
             controller,
+            serial,
+            sgb: if sgb_mode {
+                Some(SuperGameBoy::new())
+            } else {
+                None
+            },
+            lcd_framebuffer: if sgb_mode {
+                vec![0; 4 * Self::screen_width() as usize * Self::screen_height() as usize]
+            } else {
+                Vec::new()
+            },
+            cartridge_info,
             debug_mode: options.debug_mode,
             checkpoint: time::Instant::now(),
             frame_count: 0,
-        }
+            frame_cycles: 0,
+            rumble_cycles: 0,
+            rumble_strength: 0.0,
+            events: Vec::new(),
+        })
+    }
+
+    /// The header of the loaded game, `None` if the ROM is too short to have
+    /// one
+    pub fn cartridge_info(&self) -> Option<&CartridgeInfo> {
+        self.cartridge_info.as_ref()
     }
 
     pub fn exit_requested(&self) -> bool {
@@ -113,7 +213,8 @@ impl System {
     }
 
     /// Returns the external RAM bank data if the current loaded
-    /// game supports that feature
+    /// game supports that feature. For cartridges with a real time clock
+    /// the clock state is appended after the RAM banks.
     pub fn copy_external_ram_banks(&self) -> Option<Vec<u8>> {
         if self.gameboy.memory.use_battery() {
             Some(self.gameboy.memory.get_external_ram_banks())
@@ -122,6 +223,114 @@ impl System {
         }
     }
 
+    /// The state of the real time clock of the cartridge, `None` if it has
+    /// none. The same data is part of `copy_external_ram_banks`, this lets
+    /// frontends keep it separately.
+    pub fn copy_rtc_data(&self) -> Option<Vec<u8>> {
+        self.gameboy.memory.export_rtc_data()
+    }
+
+    /// Restores clock state returned by `copy_rtc_data`, catching up with
+    /// the time that passed since
+    pub fn import_rtc_data(&mut self, data: &[u8]) -> Result<(), LoadError> {
+        self.gameboy.memory.import_rtc_data(data)
+    }
+
+    /// How strongly the cartridge rumble motor was driven during the last
+    /// frame, from 0.0 (off) to 1.0 (on for the whole frame). Games control
+    /// the strength by switching the motor on and off rapidly.
+    pub fn rumble_strength(&self) -> f32 {
+        self.rumble_strength
+    }
+
+    /// Captures the complete machine state so it can later be restored with
+    /// `load_state`
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut writer = StateWriter::new();
+        writer.write_header();
+        self.write_components(&mut writer);
+        writer.into_inner()
+    }
+
+    /// Restores a state produced by `save_state` for the same game. If the
+    /// state is rejected the machine is left untouched.
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), SaveStateError> {
+        let mut reader = StateReader::new(data);
+        reader.read_header()?;
+
+        let mut backup = StateWriter::new();
+        self.write_components(&mut backup);
+        let backup = backup.into_inner();
+
+        let result = self.read_components(&mut reader).and_then(|_| {
+            if reader.is_at_end() {
+                Ok(())
+            } else {
+                Err(SaveStateError::InvalidData("unexpected trailing data"))
+            }
+        });
+
+        if result.is_err() {
+            self.read_components(&mut StateReader::new(&backup))
+                .expect("Failed to restore machine state after rejected save state");
+        }
+
+        result
+    }
+
+    fn write_components(&self, writer: &mut StateWriter) {
+        self.gameboy.cpu.save_state(writer);
+        self.gameboy.memory.save_state(writer);
+        self.gpu.save_state(writer);
+        self.sound.save_state(writer);
+        self.clock.save_state(writer);
+        self.controller.save_state(writer);
+        self.serial.save_state(writer);
+        writer.write_bool(self.sgb.is_some());
+        if let Some(sgb) = self.sgb.as_ref() {
+            sgb.save_state(writer);
+        }
+    }
+
+    fn read_components(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.gameboy.cpu.load_state(reader)?;
+        self.gameboy.memory.load_state(reader)?;
+        self.gpu.load_state(reader)?;
+        self.sound.load_state(reader)?;
+        self.clock.load_state(reader)?;
+        self.controller.load_state(reader)?;
+        self.serial.load_state(reader)?;
+        if reader.read_bool()? != self.sgb.is_some() {
+            return Err(SaveStateError::InvalidData(
+                "Super Game Boy state does not match the system",
+            ));
+        }
+        match self.sgb.as_mut() {
+            Some(sgb) => sgb.load_state(reader),
+            None => Ok(()),
+        }
+    }
+
+    /// A view of the battery backed external RAM that stays valid for the
+    /// lifetime of the system. Frontends may write save data into it directly.
+    pub fn external_ram_mut(&mut self) -> Option<&mut [u8]> {
+        if self.gameboy.memory.use_battery() {
+            Some(self.gameboy.memory.external_ram_mut())
+        } else {
+            None
+        }
+    }
+
+    /// A view of the video RAM
+    pub fn video_ram_mut(&mut self) -> &mut [u8] {
+        self.gameboy.memory.video_ram_mut()
+    }
+
+    /// A view of the internal work RAM
+    pub fn work_ram_mut(&mut self) -> &mut [u8] {
+        self.gameboy.memory.work_ram_mut()
+    }
+
     pub fn screen_width() -> u32 {
         160
     }
@@ -130,17 +339,127 @@ impl System {
         144
     }
 
+    /// The width of the frames produced by `run_single_frame`, which is wider
+    /// than the LCD when the Super Game Boy border is shown
+    pub fn framebuffer_width(&self) -> u32 {
+        if self.sgb.is_some() {
+            crate::sgb::SCREEN_WIDTH
+        } else {
+            Self::screen_width()
+        }
+    }
+
+    pub fn framebuffer_height(&self) -> u32 {
+        if self.sgb.is_some() {
+            crate::sgb::SCREEN_HEIGHT
+        } else {
+            Self::screen_height()
+        }
+    }
+
+    /// Plugs a device into the link port, replacing the one that was
+    /// connected before. Nothing is connected to begin with.
+    pub fn connect_serial_device(
+        &mut self,
+        device: Box<dyn SerialDevice>,
+    ) -> Box<dyn SerialDevice> {
+        self.serial.connect(device)
+    }
+
+    /// Leaves one of the sound channels out of the sound output
+    pub fn set_channel_muted(&mut self, channel: AudioChannel, muted: bool) {
+        self.sound.set_muted(channel, muted);
+    }
+
+    pub fn is_channel_muted(&self, channel: AudioChannel) -> bool {
+        self.sound.is_muted(channel)
+    }
+
+    /// While any channel is soloed only the soloed channels can be heard
+    pub fn set_channel_soloed(&mut self, channel: AudioChannel, soloed: bool) {
+        self.sound.set_soloed(channel, soloed);
+    }
+
+    pub fn is_channel_soloed(&self, channel: AudioChannel) -> bool {
+        self.sound.is_soloed(channel)
+    }
+
+    /// Starts or stops capturing the output of each sound channel on its own,
+    /// before panning, master volume, muting and soloing apply
+    pub fn set_channel_capture(&mut self, enabled: bool) {
+        self.sound.set_capture_enabled(enabled);
+    }
+
+    /// Returns the mono samples a channel played since the last call while
+    /// capturing, at the same rate as the sound output
+    pub fn take_channel_samples<S: AudioSample>(&mut self, channel: AudioChannel) -> Vec<S> {
+        self.sound.take_captured_samples(channel)
+    }
+
+    /// The rate of the sound output in samples per second
+    pub fn sound_frequency(&self) -> u32 {
+        self.sound.frequency()
+    }
+
+    /// Whether the game runs on the Super Game Boy
+    pub fn is_sgb(&self) -> bool {
+        self.sgb.is_some()
+    }
+
+    /// Applies input to one of the joypads of the Super Game Boy multiplayer
+    /// adapter, numbered from 0 to 3. The first player is the one controlled by
+    /// `run_single_frame`.
+    pub fn player_input(&mut self, player: usize, events: &[InputEvent]) {
+        for event in events {
+            let is_pressed = event.state == ButtonState::Pressed;
+            self.controller
+                .button_changed(player, event.button, is_pressed);
+        }
+    }
+
+    /// Colors a game made for the original Game Boy with the given palette,
+    /// like holding one of the button combos during the CGB boot animation.
+    /// Has no effect on games that use CGB features.
+    pub fn set_compatibility_palette(&mut self, palette: &CompatibilityPalette) {
+        if !self.is_cgb() {
+            self.gameboy.memory.set_compatibility_palette(palette);
+        }
+    }
+
+    /// Whether the loaded game runs on Game Boy Color hardware
+    pub fn is_cgb(&self) -> bool {
+        self.gameboy.memory.is_cgb()
+    }
+
+    /// Whether the CPU locked up after executing an illegal opcode
+    pub fn is_cpu_locked(&self) -> bool {
+        self.gameboy.cpu.is_locked
+    }
+
+    /// Returns the events that happened since the last call
+    pub fn take_events(&mut self) -> Vec<SystemEvent> {
+        std::mem::take(&mut self.events)
+    }
+
+    /// The number of frames the LCD draws per second, one frame being
+    /// 70224 cycles of the 4.194304 MHz system clock
+    pub fn refresh_rate() -> f64 {
+        4_194_304.0 / 70_224.0
+    }
+
     // pub fn num_samples(&self) -> usize {
     //     (self.sound.last_sample_output + 1) as usize
     // }
 
     /// Continue execution until a new frame is ready
     /// Returns whether the game is still running
-    pub fn run_single_frame(
+    /// Sound is added to `sound_buffer` as interleaved stereo samples at the
+    /// sound frequency, either `i16` or `f32`
+    pub fn run_single_frame<S: AudioSample>(
         &mut self,
         events: &[InputEvent],
         framebuffer: &mut [u8],
-        sound_buffer: &mut Vec<u8>,
+        sound_buffer: &mut Vec<S>,
     ) -> bool {
         // self.sound.last_sample_output = -1;
         // self.sound.total_cycle_count = 0;
@@ -160,58 +479,116 @@ impl System {
         }
 
         loop {
-            let cycles_elapsed = self.execute_next_instruction();
+            let (_, frame_end) = self.step(framebuffer, sound_buffer);
+            if self.gameboy.exit_requested() {
+                return false;
+            }
+            if frame_end {
+                return true;
+            }
+        }
+    }
 
-            self.clock.tick(&mut self.gameboy, cycles_elapsed);
+    /// Runs the next instruction and the hardware alongside it. Returns the
+    /// cycles that passed at the normal speed and whether a frame was
+    /// finished.
+    pub(crate) fn step<S: AudioSample>(
+        &mut self,
+        framebuffer: &mut [u8],
+        sound_buffer: &mut Vec<S>,
+    ) -> (u8, bool) {
+        // The CPU waits while DMA transfers to VRAM and speed switches run
+        let cycles_elapsed = match self.gameboy.memory.take_stall_cycles() {
+            0 => self.execute_next_instruction(),
+            stall_cycles => stall_cycles,
+        };
 
-            self.sound
-                .update(&mut self.gameboy, sound_buffer, cycles_elapsed);
-            let frame_end = self
-                .gpu
-                .update(&mut self.gameboy, framebuffer, cycles_elapsed);
+        // The timers follow the CPU clock, everything else keeps running at
+        // the same pace in double speed mode
+        self.clock.tick(&mut self.gameboy, cycles_elapsed);
+        self.serial.tick(&mut self.gameboy, cycles_elapsed);
+        let device_cycles = if self.gameboy.memory.is_double_speed() {
+            cycles_elapsed / 2
+        } else {
+            cycles_elapsed
+        };
+        self.gameboy.memory.tick_cartridge(device_cycles);
 
-            self.controller.update_joypad_register(&mut self.gameboy);
-            crate::interrupts::check_interrupts(&mut self.gameboy);
-            self.gameboy.memory.reset_triggers();
+        self.frame_cycles += device_cycles as u32;
+        if self.gameboy.memory.rumble_active() {
+            self.rumble_cycles += device_cycles as u32;
+        }
 
-            if self.debug_mode {
-                println!("{}", self.gameboy.cpu);
-            }
+        self.sound
+            .update(&mut self.gameboy, sound_buffer, device_cycles);
+        let frame_end = if self.sgb.is_some() {
+            self.gpu
+                .update(&mut self.gameboy, &mut self.lcd_framebuffer, device_cycles)
+        } else {
+            self.gpu
+                .update(&mut self.gameboy, framebuffer, device_cycles)
+        };
 
-            if self.gameboy.exit_requested() {
-                return false;
+        let player = match self.sgb.as_mut() {
+            Some(sgb) => {
+                if self.gameboy.memory.joypad_written() {
+                    sgb.joypad_written(self.gameboy.memory.get_register(Register::Joypad));
+                }
+                sgb.current_player()
             }
+            None => 0,
+        };
+        self.controller
+            .update_joypad_register(&mut self.gameboy, player);
+        crate::interrupts::check_interrupts(&mut self.gameboy);
+        self.gameboy.memory.reset_triggers();
 
-            if frame_end {
-                self.frame_count += 1;
-                // if self.frame_count > 10 {
-                //     panic!("yeet");
-                // }
-
-                // if self.checkpoint.elapsed().whole_nanoseconds() >= 1_000_000_000 {
-                //     let average = self.gpu.total_render_ns / self.gpu.scan_lines_rendered as i128;
-                //     let frame_average =
-                //         self.checkpoint.elapsed().whole_nanoseconds() / self.frame_count as i128;
-
-                //     self.gpu.total_render_ns = 0;
-                //     self.gpu.scan_lines_rendered = 0;
-                //     self.frame_count = 0;
-                //     self.checkpoint = time::Instant::now();
-                //     println!(
-                //         "Average per scan line = {}ns, per frame = {}us",
-                //         average,
-                //         frame_average / 1000
-                //     );
-                // }
-
-                // println!("last samp {}", self.sound.last_sample_output);
-                return true;
+        if self.debug_mode {
+            println!("{}", self.gameboy.cpu);
+        }
+
+        if self.gameboy.exit_requested() {
+            return (device_cycles, false);
+        }
+
+        if frame_end {
+            if let Some(sgb) = self.sgb.as_mut() {
+                sgb.frame_finished(self.gpu.shades());
+                sgb.render(framebuffer);
             }
+
+            self.frame_count += 1;
+            self.rumble_strength = self.rumble_cycles as f32 / self.frame_cycles as f32;
+            self.rumble_cycles = 0;
+            self.frame_cycles = 0;
+            // if self.frame_count > 10 {
+            //     panic!("yeet");
+            // }
+
+            // if self.checkpoint.elapsed().whole_nanoseconds() >= 1_000_000_000 {
+            //     let average = self.gpu.total_render_ns / self.gpu.scan_lines_rendered as i128;
+            //     let frame_average =
+            //         self.checkpoint.elapsed().whole_nanoseconds() / self.frame_count as i128;
+
+            //     self.gpu.total_render_ns = 0;
+            //     self.gpu.scan_lines_rendered = 0;
+            //     self.frame_count = 0;
+            //     self.checkpoint = time::Instant::now();
+            //     println!(
+            //         "Average per scan line = {}ns, per frame = {}us",
+            //         average,
+            //         frame_average / 1000
+            //     );
+            // }
+
+            // println!("last samp {}", self.sound.last_sample_output);
         }
+
+        (device_cycles, frame_end)
     }
 
     fn execute_next_instruction(&mut self) -> u8 {
-        if self.gameboy.cpu.is_halted {
+        if self.gameboy.cpu.is_halted || self.gameboy.cpu.is_locked {
             return 4;
         }
 
@@ -237,18 +614,15 @@ impl System {
 
         let instruction = match instruction {
             Option::None => {
-                // pause();
-                if use_cb {
-                    panic!(
-                        "CB{:02X} instruction not implemented\n{}",
-                        opcode, self.gameboy.cpu
-                    )
-                } else {
-                    panic!(
-                        "{:02X} instruction not implemented\n{}",
-                        opcode, self.gameboy.cpu
-                    )
-                }
+                // Opcodes without an instruction hang the CPU while the rest
+                // of the hardware keeps running
+                let address = self.gameboy.cpu.pc;
+                self.gameboy.cpu.is_locked = true;
+                self.events.push(SystemEvent::CpuLocked {
+                    address,
+                    opcode: self.gameboy.memory.get_byte(address),
+                });
+                return 4;
             }
             Option::Some(x) => x,
         };
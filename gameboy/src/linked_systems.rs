@@ -0,0 +1,195 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use crate::memory::Register;
+use crate::serial::SerialDevice;
+use crate::{InputEvent, System};
+
+// Cycles of the 4.194304 MHz system clock in one frame
+const FRAME_CYCLES: u64 = 70224;
+
+/// Several systems running in lock step in the same thread, with link cables
+/// between their serial ports. Whichever system is furthest behind runs the
+/// next instruction, so the order everything happens in only depends on the
+/// games and their inputs and every run gives the same result.
+pub struct LinkedSystems {
+    systems: Vec<LinkedSystem>,
+    cables: Vec<Cable>,
+    elapsed_cycles: u64,
+}
+
+struct LinkedSystem {
+    system: System,
+    framebuffer: Vec<u8>,
+    sound_buffer: Vec<i16>,
+    elapsed_cycles: u64,
+}
+
+struct Cable {
+    systems: [usize; 2],
+    ports: Rc<RefCell<[Port; 2]>>,
+}
+
+// The state of the serial port on one end of a cable
+#[derive(Default)]
+struct Port {
+    // The byte in SB while waiting for the other side to clock a transfer
+    ready: Option<u8>,
+    // A byte clocked in by the other side, delivered on the next poll
+    incoming: Option<u8>,
+}
+
+// What gets plugged into the serial port of a linked system
+struct Plug {
+    ports: Rc<RefCell<[Port; 2]>>,
+    side: usize,
+}
+
+impl SerialDevice for Plug {
+    fn transfer(&mut self, outgoing: u8) -> u8 {
+        let mut ports = self.ports.borrow_mut();
+        let other = &mut ports[1 - self.side];
+        match other.ready.take() {
+            Some(incoming) => {
+                other.incoming = Some(outgoing);
+                incoming
+            }
+            None => 0xFF,
+        }
+    }
+
+    fn poll_external_transfer(&mut self, _outgoing: Option<u8>) -> Option<u8> {
+        self.ports.borrow_mut()[self.side].incoming.take()
+    }
+}
+
+impl LinkedSystems {
+    pub fn new(systems: Vec<System>) -> LinkedSystems {
+        LinkedSystems {
+            systems: systems
+                .into_iter()
+                .map(|system| LinkedSystem {
+                    framebuffer: vec![
+                        0;
+                        (system.framebuffer_width() * system.framebuffer_height() * 4)
+                            as usize
+                    ],
+                    system,
+                    sound_buffer: Vec::new(),
+                    elapsed_cycles: 0,
+                })
+                .collect(),
+            cables: Vec::new(),
+            elapsed_cycles: 0,
+        }
+    }
+
+    /// Connects the serial ports of two of the systems with a link cable,
+    /// unplugging any cable they were connected to before
+    pub fn connect(&mut self, first: usize, second: usize) {
+        assert!(first != second, "A system can't be linked to itself");
+        self.cables
+            .retain(|cable| !cable.systems.iter().any(|&i| i == first || i == second));
+
+        let ports = Rc::new(RefCell::new([Port::default(), Port::default()]));
+        for (side, &index) in [first, second].iter().enumerate() {
+            self.systems[index]
+                .system
+                .connect_serial_device(Box::new(Plug {
+                    ports: ports.clone(),
+                    side,
+                }));
+        }
+        self.cables.push(Cable {
+            systems: [first, second],
+            ports,
+        });
+        self.update_ports(first);
+        self.update_ports(second);
+    }
+
+    pub fn len(&self) -> usize {
+        self.systems.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.systems.is_empty()
+    }
+
+    pub fn system(&self, index: usize) -> &System {
+        &self.systems[index].system
+    }
+
+    pub fn system_mut(&mut self, index: usize) -> &mut System {
+        &mut self.systems[index].system
+    }
+
+    /// The last frame drawn by one of the systems
+    pub fn framebuffer(&self, index: usize) -> &[u8] {
+        &self.systems[index].framebuffer
+    }
+
+    /// Returns the sound one of the systems played since the last call
+    pub fn take_sound_samples(&mut self, index: usize) -> Vec<i16> {
+        std::mem::take(&mut self.systems[index].sound_buffer)
+    }
+
+    /// Runs every system for the length of one frame. `events` holds the
+    /// input for each system in order, systems past its end get no input.
+    /// Returns whether all games are still running.
+    pub fn run_single_frame(&mut self, events: &[&[InputEvent]]) -> bool {
+        for (linked, events) in self.systems.iter_mut().zip(events) {
+            linked.system.player_input(0, events);
+        }
+
+        self.elapsed_cycles += FRAME_CYCLES;
+        loop {
+            // Ties go to the system added first to keep the order fixed
+            let index = match self
+                .systems
+                .iter()
+                .enumerate()
+                .filter(|(_, linked)| linked.elapsed_cycles < self.elapsed_cycles)
+                .min_by_key(|(_, linked)| linked.elapsed_cycles)
+            {
+                Some((index, _)) => index,
+                None => return true,
+            };
+
+            let linked = &mut self.systems[index];
+            let (cycles, _) = linked
+                .system
+                .step(&mut linked.framebuffer, &mut linked.sound_buffer);
+            linked.elapsed_cycles += cycles as u64;
+            if linked.system.gameboy.exit_requested() {
+                return false;
+            }
+            self.update_ports(index);
+        }
+    }
+
+    // Lets the other end of the cables see whether a system is waiting for a
+    // transfer right away, rather than when its serial port next polls
+    fn update_ports(&self, index: usize) {
+        let memory = &self.systems[index].system.gameboy.memory;
+        let control = memory.get_register(Register::SerialControl);
+        let ready = if control & 0x81 == 0x80 {
+            Some(memory.get_register(Register::SerialData))
+        } else {
+            None
+        };
+
+        for cable in self.cables.iter() {
+            for (side, _) in cable
+                .systems
+                .iter()
+                .enumerate()
+                .filter(|(_, &i)| i == index)
+            {
+                let port = &mut cable.ports.borrow_mut()[side];
+                // A byte that was clocked in already finishes the transfer
+                port.ready = ready.filter(|_| port.incoming.is_none());
+            }
+        }
+    }
+}
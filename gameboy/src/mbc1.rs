@@ -1,19 +1,34 @@
+use crate::cartridge::{check_save_size, check_sizes, Cartridge, LoadError};
+use crate::cartridge_info::{CartridgeInfo, NINTENDO_LOGO, NINTENDO_LOGO_START};
+use crate::save_state::{SaveState, SaveStateError, StateReader, StateWriter};
+
 enum BankingMode {
     Rom,
     Ram,
 }
 
 const ROM_BANK_SELECT_LOWER_BIT_MASK: u8 = 0b0001_1111;
-const ROM_BANK_SELECT_UPPER_BIT_MASK: u8 = 0b0110_0000;
+const UPPER_BANK_SELECT_MASK: u8 = 0b0000_0011;
+
+// MBC1M multicarts leave bit 4 of the lower bank register unconnected and
+// wire the upper bank bits one position lower
+const MULTICART_ROM_BANK_SELECT_LOWER_BIT_MASK: u8 = 0b0000_1111;
+const MULTICART_BANK_SIZE: usize = 0x10;
 
 pub struct MemoryBankController1 {
-    pub use_battery: bool,
+    use_battery: bool,
     ram_banks_enabled: bool,
-    selected_rom_bank: u8,
-    selected_ram_bank: u8,
+    // Raw value of the 5-bit register at 0x2000-0x3FFF
+    rom_bank_lower: u8,
+    // Raw value of the 2-bit register at 0x4000-0x5FFF, used as the upper ROM
+    // bank bits or the RAM bank depending on the banking mode
+    upper_bank: u8,
     banking_mode: BankingMode,
-    rom_banks: Vec<Vec<u8>>,
-    ram_banks: Vec<Vec<u8>>,
+    is_multicart: bool,
+    num_rom_banks: usize,
+    rom: Vec<u8>,
+    ram_bank_size: usize,
+    ram: Vec<u8>,
 }
 
 impl MemoryBankController1 {
@@ -21,149 +36,178 @@ impl MemoryBankController1 {
         MemoryBankController1 {
             use_battery: false,
             ram_banks_enabled: false,
-            selected_rom_bank: 1,
-            selected_ram_bank: 0,
+            rom_bank_lower: 1,
+            upper_bank: 0,
             banking_mode: BankingMode::Rom,
-            rom_banks: vec![],
-            ram_banks: vec![],
+            is_multicart: false,
+            num_rom_banks: 0,
+            rom: vec![],
+            ram_bank_size: 0,
+            ram: vec![],
         }
     }
 
-    pub fn initialize(&mut self, rom_buf: &[u8]) {
-        let mbc_type = rom_buf[0x147];
-        let num_rom_banks: u32 = match rom_buf[0x148] {
-            0 => 2,
-            1 => 4,
-            2 => 8,
-            3 => 16,
-            4 => 32,
-            5 => 64,
-            6 => 128,
-            x => panic!("ROM specified incorrect size at 0x148: {}", x),
-        };
-
-        let ram_size = rom_buf[0x149];
-        let num_ram_banks = match ram_size {
-            0 => 0,
-            1 => 1, //2KB instead of 8KB
-            2 => 1,
-            3 => 4,
-            4 => 16,
-            x => panic!("ROM specified incorrect size for external RAM: {}", x),
-        };
+    pub fn initialize(&mut self, rom_buf: &[u8], info: &CartridgeInfo) -> Result<(), LoadError> {
+        let (rom_size, ram_size) = check_sizes(rom_buf, info, 0x06, 0x04)?;
+        let num_rom_banks = rom_size / 0x4000;
 
-        self.use_battery = mbc_type == 0x03;
+        self.use_battery = info.cartridge_type.has_battery();
 
-        println!("Game uses memory banking {}", mbc_type);
+        println!("Game uses memory banking {}", info.cartridge_type.code());
         println!("ROM banks = {}", num_rom_banks);
-        println!("RAM banks = {}", num_ram_banks);
-
-        for i_bank in 1..num_rom_banks {
-            let start = i_bank * 0x4000;
-            let mut bank = vec![0; 0x4000];
-            for i in start..(start + 0x4000) {
-                bank[(i - start) as usize] = rom_buf[i as usize];
-            }
-            self.rom_banks.push(bank);
-        }
+        println!("RAM size = {}", ram_size);
 
-        for _i_bank in 0..num_ram_banks {
-            self.ram_banks
-                .push(vec![0; if ram_size == 1 { 0x800 } else { 0x2000 }]);
-        }
-    }
+        self.num_rom_banks = num_rom_banks;
+        self.rom = rom_buf[..rom_size].to_vec();
+        self.is_multicart = is_multicart(&self.rom);
 
-    pub fn get_byte(&self, address: u16) -> Option<u8> {
-        if address >= 0x4000 && address < 0x8000 {
-            return Some(
-                self.rom_banks[(self.selected_rom_bank - 1) as usize][(address - 0x4000) as usize],
-            );
-        }
+        // 2KB carts only have part of a bank
+        self.ram_bank_size = ram_size.min(0x2000);
+        self.ram = vec![0; ram_size];
+        Ok(())
+    }
 
-        if address >= 0xA000 && address < 0xC000 {
-            return Some(
-                self.ram_banks[self.selected_ram_bank as usize][(address - 0xA000) as usize],
-            );
+    fn upper_bank_bits(&self) -> usize {
+        if self.is_multicart {
+            (self.upper_bank as usize) << 4
+        } else {
+            (self.upper_bank as usize) << 5
         }
+    }
 
-        None
+    fn read_rom_bank(&self, bank: usize, address: u16) -> u8 {
+        // Bank bits beyond the size of the ROM are not connected
+        let bank = bank % self.num_rom_banks;
+        self.rom[(bank * 0x4000) + (address as usize & 0x3FFF)]
     }
 
-    pub fn set_byte(&mut self, address: u16, b: u8) -> bool {
-        if address >= 0x2000 && address < 0x4000 {
-            let requested_bank = (b & ROM_BANK_SELECT_LOWER_BIT_MASK)
-                | (self.selected_rom_bank & ROM_BANK_SELECT_UPPER_BIT_MASK);
-            self.selected_rom_bank = map_to_rom_bank(requested_bank);
-            // println!("Selected ROM bank {}", self.selected_rom_bank);
-            return true;
+    fn ram_index(&self, address: u16) -> Option<usize> {
+        let offset = (address - 0xA000) as usize;
+        if !self.ram_banks_enabled || self.ram.is_empty() || offset >= self.ram_bank_size {
+            return None;
         }
 
-        if address >= 0xA000 && address < 0xC000 {
-            //RAM banks
-            if (self.selected_ram_bank as usize) < self.ram_banks.len() {
-                self.ram_banks[self.selected_ram_bank as usize][(address - 0xA000) as usize] = b;
-            }
-            return true;
-        }
+        let bank = match self.banking_mode {
+            BankingMode::Rom => 0,
+            BankingMode::Ram => self.upper_bank as usize,
+        };
+        let num_ram_banks = self.ram.len() / self.ram_bank_size;
+        Some(((bank % num_ram_banks) * self.ram_bank_size) + offset)
+    }
+}
 
-        if address >= 0x4000 && address < 0x6000 {
-            match self.banking_mode {
-                BankingMode::Ram => {
-                    self.selected_ram_bank = b & 0b11;
-                }
-                BankingMode::Rom => {
-                    println!("Selecting upper bits of ROM: {:02X}", b);
-                    let requested_bank = (self.selected_rom_bank & ROM_BANK_SELECT_LOWER_BIT_MASK)
-                        | ((b << 5) & ROM_BANK_SELECT_UPPER_BIT_MASK);
-                    self.selected_rom_bank = map_to_rom_bank(requested_bank);
-                }
-            }
-            return true;
+impl Cartridge for MemoryBankController1 {
+    fn read_rom(&self, address: u16) -> u8 {
+        if address < 0x4000 {
+            let bank = match self.banking_mode {
+                BankingMode::Rom => 0,
+                BankingMode::Ram => self.upper_bank_bits(),
+            };
+            return self.read_rom_bank(bank, address);
         }
 
-        if address >= 0x6000 && address < 0x8000 {
-            self.banking_mode = if b & 1 > 0 {
+        let lower_bits = if self.is_multicart {
+            self.rom_bank_lower & MULTICART_ROM_BANK_SELECT_LOWER_BIT_MASK
+        } else {
+            self.rom_bank_lower
+        };
+        self.read_rom_bank(self.upper_bank_bits() | lower_bits as usize, address)
+    }
+
+    fn write_register(&mut self, address: u16, value: u8) {
+        if address < 0x2000 {
+            self.ram_banks_enabled = value & 0x0F == 0x0A;
+        } else if address < 0x4000 {
+            // Bank 0 can't be selected here, 0 is translated to 1 before the
+            // upper bits are applied which makes banks 0x20, 0x40 and 0x60
+            // unreachable from 0x4000-0x7FFF
+            self.rom_bank_lower = match value & ROM_BANK_SELECT_LOWER_BIT_MASK {
+                0 => 1,
+                x => x,
+            };
+        } else if address < 0x6000 {
+            self.upper_bank = value & UPPER_BANK_SELECT_MASK;
+        } else {
+            self.banking_mode = if value & 1 > 0 {
                 BankingMode::Ram
             } else {
                 BankingMode::Rom
             };
-
-            return true;
         }
-
-        false
     }
 
-    pub fn load_external_ram(&mut self, save_buf: &[u8]) {
-        println!("Loading external RAM {0}", save_buf.len());
-        let num_ram_banks = self.ram_banks.len();
-        for i_bank in 0..num_ram_banks {
-            let bank = &mut self.ram_banks[i_bank];
-            for i in 0..bank.len() {
-                bank[i] = save_buf[(i_bank * bank.len()) + i];
-            }
+    fn read_ram(&self, address: u16) -> u8 {
+        match self.ram_index(address) {
+            Some(index) => self.ram[index],
+            None => 0xFF,
         }
     }
 
-    pub fn get_external_ram_banks(&self) -> Vec<u8> {
-        let num_ram_banks = self.ram_banks.len();
-        let mut result = vec![0; num_ram_banks * self.ram_banks[0].len()];
-        for i_bank in 0..num_ram_banks {
-            let bank = &self.ram_banks[i_bank];
-            for i in 0..bank.len() {
-                result[(i_bank * bank.len()) + i] = bank[i];
-            }
+    fn write_ram(&mut self, address: u16, value: u8) {
+        if let Some(index) = self.ram_index(address) {
+            self.ram[index] = value;
         }
-        result
     }
+
+    fn has_battery(&self) -> bool {
+        self.use_battery
+    }
+
+    fn import_battery_data(&mut self, data: &[u8]) -> Result<(), LoadError> {
+        println!("Loading external RAM {0}", data.len());
+        check_save_size(data, self.ram.len())?;
+        self.ram.copy_from_slice(data);
+        Ok(())
+    }
+
+    fn external_ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    fn external_ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+}
+
+/// MBC1M multicarts are 1MB ROMs made of four 256KB games, each with its own
+/// header. They are detected by finding the Nintendo logo at the start of the
+/// second game in addition to the first.
+fn is_multicart(rom: &[u8]) -> bool {
+    let game_size = MULTICART_BANK_SIZE * 0x4000;
+    if rom.len() != 4 * game_size {
+        return false;
+    }
+
+    let logo_end = NINTENDO_LOGO_START + NINTENDO_LOGO.len();
+    let logo = &rom[NINTENDO_LOGO_START..logo_end];
+    let second_game_logo = &rom[game_size + NINTENDO_LOGO_START..game_size + logo_end];
+    logo.iter().any(|b| *b != 0) && logo == second_game_logo
 }
 
-fn map_to_rom_bank(requested_bank: u8) -> u8 {
-    match requested_bank {
-        0x00 => 0x01,
-        0x20 => 0x21,
-        0x40 => 0x41,
-        0x60 => 0x61,
-        x => x,
+impl SaveState for MemoryBankController1 {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.ram_banks_enabled);
+        writer.write_u8(self.rom_bank_lower);
+        writer.write_u8(self.upper_bank);
+        writer.write_u8(match self.banking_mode {
+            BankingMode::Rom => 0,
+            BankingMode::Ram => 1,
+        });
+        writer.write_bytes(&self.ram);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.ram_banks_enabled = reader.read_bool()?;
+        self.rom_bank_lower = reader.read_u8()?;
+        if self.rom_bank_lower == 0 || self.rom_bank_lower > ROM_BANK_SELECT_LOWER_BIT_MASK {
+            return Err(SaveStateError::InvalidData("MBC1 ROM bank out of range"));
+        }
+        self.upper_bank = reader.read_u8()? & UPPER_BANK_SELECT_MASK;
+        self.banking_mode = match reader.read_u8()? {
+            0 => BankingMode::Rom,
+            1 => BankingMode::Ram,
+            _ => return Err(SaveStateError::InvalidData("unknown MBC1 banking mode")),
+        };
+        reader.read_bytes_into(&mut self.ram)
     }
 }
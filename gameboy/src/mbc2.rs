@@ -0,0 +1,130 @@
+use crate::cartridge::{check_save_size, check_sizes, Cartridge, LoadError};
+use crate::cartridge_info::CartridgeInfo;
+use crate::save_state::{SaveState, SaveStateError, StateReader, StateWriter};
+
+const RAM_SIZE: usize = 0x200;
+const ROM_BANK_REGISTER_BIT: u16 = 0x0100;
+
+pub struct MemoryBankController2 {
+    use_battery: bool,
+    ram_enabled: bool,
+    selected_rom_bank: u8,
+    num_rom_banks: usize,
+    rom: Vec<u8>,
+    // 512 half-byte cells, only the lower nibble of each byte is used
+    ram: Vec<u8>,
+}
+
+impl Default for MemoryBankController2 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MemoryBankController2 {
+    pub fn new() -> MemoryBankController2 {
+        MemoryBankController2 {
+            use_battery: false,
+            ram_enabled: false,
+            selected_rom_bank: 1,
+            num_rom_banks: 0,
+            rom: vec![],
+            ram: vec![0; RAM_SIZE],
+        }
+    }
+
+    pub fn initialize(&mut self, rom_buf: &[u8], info: &CartridgeInfo) -> Result<(), LoadError> {
+        let (rom_size, _) = check_sizes(rom_buf, info, 0x03, 0x05)?;
+        let num_rom_banks = rom_size / 0x4000;
+
+        self.use_battery = info.cartridge_type.has_battery();
+
+        self.num_rom_banks = num_rom_banks;
+        self.rom = rom_buf[..rom_size].to_vec();
+        Ok(())
+    }
+}
+
+impl Cartridge for MemoryBankController2 {
+    fn read_rom(&self, address: u16) -> u8 {
+        let bank = if address < 0x4000 {
+            0
+        } else {
+            self.selected_rom_bank as usize % self.num_rom_banks
+        };
+        self.rom[(bank * 0x4000) + (address as usize & 0x3FFF)]
+    }
+
+    fn write_register(&mut self, address: u16, value: u8) {
+        if address >= 0x4000 {
+            return;
+        }
+
+        // Bit 8 of the address selects between the two registers
+        if address & ROM_BANK_REGISTER_BIT == 0 {
+            self.ram_enabled = value & 0x0F == 0x0A;
+        } else {
+            self.selected_rom_bank = match value & 0x0F {
+                0 => 1,
+                x => x,
+            };
+        }
+    }
+
+    fn read_ram(&self, address: u16) -> u8 {
+        if !self.ram_enabled {
+            return 0xFF;
+        }
+
+        // The upper four bits are not connected and read back as 1s
+        0xF0 | self.ram[ram_index(address)]
+    }
+
+    fn write_ram(&mut self, address: u16, value: u8) {
+        if self.ram_enabled {
+            self.ram[ram_index(address)] = value & 0x0F;
+        }
+    }
+
+    fn has_battery(&self) -> bool {
+        self.use_battery
+    }
+
+    fn import_battery_data(&mut self, data: &[u8]) -> Result<(), LoadError> {
+        check_save_size(data, RAM_SIZE)?;
+        for (cell, value) in self.ram.iter_mut().zip(data.iter()) {
+            *cell = value & 0x0F;
+        }
+        Ok(())
+    }
+
+    fn external_ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    fn external_ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+}
+
+// The 512 cells are mirrored throughout 0xA000-0xBFFF
+fn ram_index(address: u16) -> usize {
+    (address as usize - 0xA000) % RAM_SIZE
+}
+
+impl SaveState for MemoryBankController2 {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.ram_enabled);
+        writer.write_u8(self.selected_rom_bank);
+        writer.write_bytes(&self.ram);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.ram_enabled = reader.read_bool()?;
+        self.selected_rom_bank = reader.read_u8()?;
+        if self.selected_rom_bank == 0 || self.selected_rom_bank > 0x0F {
+            return Err(SaveStateError::InvalidData("MBC2 ROM bank out of range"));
+        }
+        reader.read_bytes_into(&mut self.ram)
+    }
+}
@@ -0,0 +1,415 @@
+use crate::cartridge::{check_sizes, Cartridge, LoadError};
+use crate::cartridge_info::CartridgeInfo;
+use crate::save_state::{SaveState, SaveStateError, StateReader, StateWriter};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const CYCLES_PER_SECOND: u32 = 4_194_304;
+// Size of the RTC block appended to the RAM in battery saves. Uses the layout
+// shared by other emulators: the five clock registers followed by the five
+// latched registers, each as a 32-bit value, then a 64-bit UNIX timestamp.
+const RTC_SAVE_SIZE: usize = 48;
+
+const RTC_DAY_HIGH_BIT: u8 = 0b0000_0001;
+const RTC_HALT_BIT: u8 = 0b0100_0000;
+const RTC_DAY_CARRY_BIT: u8 = 0b1000_0000;
+
+#[derive(Copy, Clone, Default)]
+struct RtcRegisters {
+    seconds: u8,
+    minutes: u8,
+    hours: u8,
+    day_low: u8,
+    day_high: u8,
+}
+
+impl RtcRegisters {
+    fn get(&self, register: u8) -> u8 {
+        match register {
+            0x08 => self.seconds,
+            0x09 => self.minutes,
+            0x0A => self.hours,
+            0x0B => self.day_low,
+            _ => self.day_high,
+        }
+    }
+
+    fn set(&mut self, register: u8, value: u8) {
+        match register {
+            0x08 => self.seconds = value & 0b0011_1111,
+            0x09 => self.minutes = value & 0b0011_1111,
+            0x0A => self.hours = value & 0b0001_1111,
+            0x0B => self.day_low = value,
+            _ => self.day_high = value & (RTC_DAY_HIGH_BIT | RTC_HALT_BIT | RTC_DAY_CARRY_BIT),
+        }
+    }
+
+    fn halted(&self) -> bool {
+        self.day_high & RTC_HALT_BIT != 0
+    }
+
+    fn days(&self) -> u16 {
+        (((self.day_high & RTC_DAY_HIGH_BIT) as u16) << 8) | self.day_low as u16
+    }
+
+    fn set_days(&mut self, days: u16) {
+        self.day_low = (days & 0xFF) as u8;
+        self.day_high =
+            (self.day_high & !RTC_DAY_HIGH_BIT) | ((days >> 8) as u8 & RTC_DAY_HIGH_BIT);
+    }
+
+    fn tick_second(&mut self) {
+        // Out of range values count up to the limit of their bit width before
+        // wrapping to 0, without carrying into the next register.
+        if self.seconds == 59 {
+            self.seconds = 0;
+        } else {
+            self.seconds = (self.seconds + 1) & 0b0011_1111;
+            return;
+        }
+
+        if self.minutes == 59 {
+            self.minutes = 0;
+        } else {
+            self.minutes = (self.minutes + 1) & 0b0011_1111;
+            return;
+        }
+
+        if self.hours == 23 {
+            self.hours = 0;
+        } else {
+            self.hours = (self.hours + 1) & 0b0001_1111;
+            return;
+        }
+
+        let days = self.days();
+        if days == 0x1FF {
+            self.set_days(0);
+            self.day_high |= RTC_DAY_CARRY_BIT;
+        } else {
+            self.set_days(days + 1);
+        }
+    }
+
+    fn advance(&mut self, seconds: u64) {
+        if self.halted() || seconds == 0 {
+            return;
+        }
+
+        if self.seconds >= 60 || self.minutes >= 60 || self.hours >= 24 {
+            for _ in 0..seconds {
+                self.tick_second();
+            }
+            return;
+        }
+
+        let elapsed = self.seconds as u64
+            + (self.minutes as u64 * 60)
+            + (self.hours as u64 * 3600)
+            + (self.days() as u64 * 86400)
+            + seconds;
+        self.seconds = (elapsed % 60) as u8;
+        self.minutes = ((elapsed / 60) % 60) as u8;
+        self.hours = ((elapsed / 3600) % 24) as u8;
+
+        let days = elapsed / 86400;
+        if days > 0x1FF {
+            self.day_high |= RTC_DAY_CARRY_BIT;
+        }
+        self.set_days((days & 0x1FF) as u16);
+    }
+
+    fn write_save(&self, buf: &mut Vec<u8>) {
+        for register in 0x08..=0x0C {
+            buf.extend_from_slice(&(self.get(register) as u32).to_le_bytes());
+        }
+    }
+
+    fn read_save(&mut self, buf: &[u8]) {
+        for (i, register) in (0x08..=0x0C).enumerate() {
+            self.set(register, buf[i * 4]);
+        }
+    }
+}
+
+impl SaveState for RtcRegisters {
+    fn save_state(&self, writer: &mut StateWriter) {
+        for register in 0x08..=0x0C {
+            writer.write_u8(self.get(register));
+        }
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        for register in 0x08..=0x0C {
+            self.set(register, reader.read_u8()?);
+        }
+        Ok(())
+    }
+}
+
+pub struct MemoryBankController3 {
+    use_battery: bool,
+    has_rtc: bool,
+    ram_and_rtc_enabled: bool,
+    selected_rom_bank: u8,
+    // Values 0x00-0x03 select a RAM bank, 0x08-0x0C select an RTC register
+    selected_ram_bank: u8,
+    num_rom_banks: usize,
+    rom: Vec<u8>,
+    ram_bank_size: usize,
+    ram: Vec<u8>,
+    rtc: RtcRegisters,
+    latched_rtc: RtcRegisters,
+    latch_armed: bool,
+    rtc_cycles: u32,
+}
+
+impl Default for MemoryBankController3 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MemoryBankController3 {
+    pub fn new() -> MemoryBankController3 {
+        MemoryBankController3 {
+            use_battery: false,
+            has_rtc: false,
+            ram_and_rtc_enabled: false,
+            selected_rom_bank: 1,
+            selected_ram_bank: 0,
+            num_rom_banks: 0,
+            rom: vec![],
+            ram_bank_size: 0,
+            ram: vec![],
+            rtc: RtcRegisters::default(),
+            latched_rtc: RtcRegisters::default(),
+            latch_armed: false,
+            rtc_cycles: 0,
+        }
+    }
+
+    pub fn initialize(&mut self, rom_buf: &[u8], info: &CartridgeInfo) -> Result<(), LoadError> {
+        let (rom_size, ram_size) = check_sizes(rom_buf, info, 0x06, 0x03)?;
+        let num_rom_banks = rom_size / 0x4000;
+
+        self.use_battery = info.cartridge_type.has_battery();
+        self.has_rtc = info.cartridge_type.has_timer();
+
+        self.num_rom_banks = num_rom_banks;
+        self.rom = rom_buf[..rom_size].to_vec();
+
+        // 2KB carts only have part of a bank
+        self.ram_bank_size = ram_size.min(0x2000);
+        self.ram = vec![0; ram_size];
+        Ok(())
+    }
+
+    fn rtc_selected(&self) -> bool {
+        self.has_rtc && self.selected_ram_bank >= 0x08 && self.selected_ram_bank <= 0x0C
+    }
+
+    fn ram_index(&self, address: u16) -> Option<usize> {
+        let offset = (address - 0xA000) as usize;
+        if offset >= self.ram_bank_size || self.selected_ram_bank > 0x03 {
+            return None;
+        }
+
+        let index = (self.selected_ram_bank as usize * self.ram_bank_size) + offset;
+        if index < self.ram.len() {
+            Some(index)
+        } else {
+            None
+        }
+    }
+}
+
+impl Cartridge for MemoryBankController3 {
+    fn read_rom(&self, address: u16) -> u8 {
+        let bank = if address < 0x4000 {
+            0
+        } else {
+            self.selected_rom_bank as usize % self.num_rom_banks
+        };
+        self.rom[(bank * 0x4000) + (address as usize & 0x3FFF)]
+    }
+
+    fn write_register(&mut self, address: u16, value: u8) {
+        if address < 0x2000 {
+            self.ram_and_rtc_enabled = value & 0x0F == 0x0A;
+        } else if address < 0x4000 {
+            self.selected_rom_bank = match value & 0b0111_1111 {
+                0 => 1,
+                x => x,
+            };
+        } else if address < 0x6000 {
+            self.selected_ram_bank = value;
+        } else {
+            // Writing 0 followed by 1 copies the clock into the latched registers
+            if self.latch_armed && value == 1 {
+                self.latched_rtc = self.rtc;
+            }
+            self.latch_armed = value == 0;
+        }
+    }
+
+    fn read_ram(&self, address: u16) -> u8 {
+        if !self.ram_and_rtc_enabled {
+            return 0xFF;
+        }
+
+        if self.rtc_selected() {
+            return self.latched_rtc.get(self.selected_ram_bank);
+        }
+
+        match self.ram_index(address) {
+            Some(index) => self.ram[index],
+            None => 0xFF,
+        }
+    }
+
+    fn write_ram(&mut self, address: u16, value: u8) {
+        if !self.ram_and_rtc_enabled {
+            return;
+        }
+
+        if self.rtc_selected() {
+            if self.selected_ram_bank == 0x08 {
+                self.rtc_cycles = 0;
+            }
+            self.rtc.set(self.selected_ram_bank, value);
+            self.latched_rtc.set(self.selected_ram_bank, value);
+        } else if let Some(index) = self.ram_index(address) {
+            self.ram[index] = value;
+        }
+    }
+
+    /// Advances the real time clock by the number of emulated cycles
+    fn tick(&mut self, cycles: u8) {
+        if !self.has_rtc || self.rtc.halted() {
+            return;
+        }
+
+        self.rtc_cycles += cycles as u32;
+        if self.rtc_cycles >= CYCLES_PER_SECOND {
+            self.rtc_cycles -= CYCLES_PER_SECOND;
+            self.rtc.tick_second();
+        }
+    }
+
+    fn has_battery(&self) -> bool {
+        self.use_battery
+    }
+
+    /// Loads the external RAM and, for cartridges with a clock, the RTC
+    /// block that follows it. The clock is advanced by the time that passed
+    /// since the save was written, as the cartridge battery would have kept it
+    /// running.
+    fn import_battery_data(&mut self, data: &[u8]) -> Result<(), LoadError> {
+        let ram_size = self.ram.len();
+        let rtc_buf = &data[ram_size.min(data.len())..];
+        let has_rtc_block = rtc_buf.len() == RTC_SAVE_SIZE || rtc_buf.len() == RTC_SAVE_SIZE - 4;
+        if data.len() < ram_size || !(rtc_buf.is_empty() || (self.has_rtc && has_rtc_block)) {
+            return Err(LoadError::SaveSizeMismatch {
+                expected: self.export_battery_data().len(),
+                actual: data.len(),
+            });
+        }
+        self.ram.copy_from_slice(&data[..ram_size]);
+
+        if rtc_buf.is_empty() {
+            return Ok(());
+        }
+        self.import_rtc_data(rtc_buf)
+    }
+
+    fn export_battery_data(&self) -> Vec<u8> {
+        let mut result = self.ram.clone();
+        if let Some(rtc) = self.export_rtc_data() {
+            result.extend_from_slice(&rtc);
+        }
+        result
+    }
+
+    fn export_rtc_data(&self) -> Option<Vec<u8>> {
+        if !self.has_rtc {
+            return None;
+        }
+        let mut result = Vec::with_capacity(RTC_SAVE_SIZE);
+        self.rtc.write_save(&mut result);
+        self.latched_rtc.write_save(&mut result);
+        result.extend_from_slice(&unix_timestamp().to_le_bytes());
+        Some(result)
+    }
+
+    /// The clock is advanced by the time that passed since the data was
+    /// exported, as the cartridge battery would have kept it running
+    fn import_rtc_data(&mut self, rtc_buf: &[u8]) -> Result<(), LoadError> {
+        if !self.has_rtc || (rtc_buf.len() != RTC_SAVE_SIZE && rtc_buf.len() != RTC_SAVE_SIZE - 4) {
+            return Err(LoadError::SaveSizeMismatch {
+                expected: RTC_SAVE_SIZE,
+                actual: rtc_buf.len(),
+            });
+        }
+
+        self.rtc.read_save(&rtc_buf[0..20]);
+        self.latched_rtc.read_save(&rtc_buf[20..40]);
+
+        let mut timestamp = [0; 8];
+        let timestamp_size = rtc_buf.len() - 40;
+        timestamp[..timestamp_size].copy_from_slice(&rtc_buf[40..]);
+        let saved_at = u64::from_le_bytes(timestamp);
+        let now = unix_timestamp();
+        if now > saved_at {
+            self.rtc.advance(now - saved_at);
+        }
+        Ok(())
+    }
+
+    fn external_ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    fn external_ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+}
+
+fn unix_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+impl SaveState for MemoryBankController3 {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.ram_and_rtc_enabled);
+        writer.write_u8(self.selected_rom_bank);
+        writer.write_u8(self.selected_ram_bank);
+        writer.write_bytes(&self.ram);
+        self.rtc.save_state(writer);
+        self.latched_rtc.save_state(writer);
+        writer.write_bool(self.latch_armed);
+        writer.write_u32(self.rtc_cycles);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.ram_and_rtc_enabled = reader.read_bool()?;
+        self.selected_rom_bank = reader.read_u8()?;
+        if self.selected_rom_bank == 0 || self.selected_rom_bank > 0x7F {
+            return Err(SaveStateError::InvalidData("MBC3 ROM bank out of range"));
+        }
+        self.selected_ram_bank = reader.read_u8()?;
+        reader.read_bytes_into(&mut self.ram)?;
+        self.rtc.load_state(reader)?;
+        self.latched_rtc.load_state(reader)?;
+        self.latch_armed = reader.read_bool()?;
+        self.rtc_cycles = reader.read_u32()?;
+        if self.rtc_cycles >= CYCLES_PER_SECOND {
+            return Err(SaveStateError::InvalidData(
+                "MBC3 RTC cycle count out of range",
+            ));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,157 @@
+use crate::cartridge::{check_save_size, check_sizes, Cartridge, LoadError};
+use crate::cartridge_info::CartridgeInfo;
+use crate::save_state::{SaveState, SaveStateError, StateReader, StateWriter};
+
+const RUMBLE_MOTOR_BIT: u8 = 0b0000_1000;
+
+pub struct MemoryBankController5 {
+    use_battery: bool,
+    has_rumble: bool,
+    ram_banks_enabled: bool,
+    // 9-bit bank number, unlike the other controllers bank 0 can be mapped
+    // into 0x4000-0x7FFF
+    selected_rom_bank: u16,
+    selected_ram_bank: u8,
+    rumble_active: bool,
+    num_rom_banks: usize,
+    rom: Vec<u8>,
+    ram_bank_size: usize,
+    ram: Vec<u8>,
+}
+
+impl Default for MemoryBankController5 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MemoryBankController5 {
+    pub fn new() -> MemoryBankController5 {
+        MemoryBankController5 {
+            use_battery: false,
+            has_rumble: false,
+            ram_banks_enabled: false,
+            selected_rom_bank: 1,
+            selected_ram_bank: 0,
+            rumble_active: false,
+            num_rom_banks: 0,
+            rom: vec![],
+            ram_bank_size: 0,
+            ram: vec![],
+        }
+    }
+
+    pub fn initialize(&mut self, rom_buf: &[u8], info: &CartridgeInfo) -> Result<(), LoadError> {
+        let (rom_size, ram_size) = check_sizes(rom_buf, info, 0x08, 0x05)?;
+        let num_rom_banks = rom_size / 0x4000;
+
+        self.use_battery = info.cartridge_type.has_battery();
+        self.has_rumble = info.cartridge_type.has_rumble();
+
+        self.num_rom_banks = num_rom_banks;
+        self.rom = rom_buf[..rom_size].to_vec();
+        // 2KB carts only have part of a bank
+        self.ram_bank_size = ram_size.min(0x2000);
+        self.ram = vec![0; ram_size];
+        Ok(())
+    }
+
+    fn ram_index(&self, address: u16) -> Option<usize> {
+        let offset = (address - 0xA000) as usize;
+        if !self.ram_banks_enabled || offset >= self.ram_bank_size {
+            return None;
+        }
+
+        let index = (self.selected_ram_bank as usize * self.ram_bank_size) + offset;
+        if index < self.ram.len() {
+            Some(index)
+        } else {
+            None
+        }
+    }
+}
+
+impl Cartridge for MemoryBankController5 {
+    fn read_rom(&self, address: u16) -> u8 {
+        let bank = if address < 0x4000 {
+            0
+        } else {
+            self.selected_rom_bank as usize % self.num_rom_banks
+        };
+        self.rom[(bank * 0x4000) + (address as usize & 0x3FFF)]
+    }
+
+    fn write_register(&mut self, address: u16, value: u8) {
+        if address < 0x2000 {
+            self.ram_banks_enabled = value == 0x0A;
+        } else if address < 0x3000 {
+            self.selected_rom_bank = (self.selected_rom_bank & 0x100) | value as u16;
+        } else if address < 0x4000 {
+            self.selected_rom_bank = (self.selected_rom_bank & 0xFF) | (((value & 1) as u16) << 8);
+        } else if address < 0x6000 {
+            if self.has_rumble {
+                // The rumble motor is wired to bit 3 of the RAM bank register
+                self.rumble_active = value & RUMBLE_MOTOR_BIT != 0;
+                self.selected_ram_bank = value & 0b0111;
+            } else {
+                self.selected_ram_bank = value & 0b1111;
+            }
+        }
+    }
+
+    fn read_ram(&self, address: u16) -> u8 {
+        match self.ram_index(address) {
+            Some(index) => self.ram[index],
+            None => 0xFF,
+        }
+    }
+
+    fn write_ram(&mut self, address: u16, value: u8) {
+        if let Some(index) = self.ram_index(address) {
+            self.ram[index] = value;
+        }
+    }
+
+    fn has_battery(&self) -> bool {
+        self.use_battery
+    }
+
+    fn import_battery_data(&mut self, data: &[u8]) -> Result<(), LoadError> {
+        check_save_size(data, self.ram.len())?;
+        self.ram.copy_from_slice(data);
+        Ok(())
+    }
+
+    fn external_ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    fn external_ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+
+    fn rumble_active(&self) -> bool {
+        self.rumble_active
+    }
+}
+
+impl SaveState for MemoryBankController5 {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.ram_banks_enabled);
+        writer.write_u16(self.selected_rom_bank);
+        writer.write_u8(self.selected_ram_bank);
+        writer.write_bool(self.rumble_active);
+        writer.write_bytes(&self.ram);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.ram_banks_enabled = reader.read_bool()?;
+        self.selected_rom_bank = reader.read_u16()?;
+        if self.selected_rom_bank > 0x1FF {
+            return Err(SaveStateError::InvalidData("MBC5 ROM bank out of range"));
+        }
+        self.selected_ram_bank = reader.read_u8()?;
+        self.rumble_active = reader.read_bool()?;
+        reader.read_bytes_into(&mut self.ram)
+    }
+}
@@ -1,4 +1,10 @@
-use crate::mbc1::MemoryBankController1;
+use crate::cartridge::{create_cartridge, Cartridge, LoadError, RomOnly};
+use crate::compatibility_palette::CompatibilityPalette;
+use crate::save_state::{SaveState, SaveStateError, StateReader, StateWriter};
+use crate::sound_registers::{
+    is_sound_register, read_mask, write_while_off, WaveRamAccess, SOUND_POWER,
+    SOUND_REGISTERS_START, WAVE_RAM_END, WAVE_RAM_START,
+};
 use crate::util::concat_bytes;
 use crate::util::get_lower;
 use crate::util::get_upper;
@@ -8,6 +14,8 @@ use crate::util::get_upper;
 pub enum Register {
     SpriteData = 0xFE00,
     Joypad = 0xFF00,
+    SerialData = 0xFF01,
+    SerialControl = 0xFF02,
     Divider = 0xFF04,
     TimerCounter = 0xFF05,
     TimerModulo = 0xFF06,
@@ -31,6 +39,9 @@ pub enum Register {
     Channel3FrequencyLo = 0xFF1D,
     Channel3FrequencyHi = 0xFF1E,
 
+    Channel4Length = 0xFF20,
+    Channel4VolumeEnvelope = 0xFF21,
+    Channel4PolynomialCounter = 0xFF22,
     Channel4TriggerLength = 0xFF23,
 
     ChannelControl = 0xFF24,
@@ -50,17 +61,67 @@ pub enum Register {
     WindowY = 0xFF4A,
     WindowX = 0xFF4B,
 
+    SpeedSwitch = 0xFF4D,
+    VramBank = 0xFF4F,
+    HdmaSourceHi = 0xFF51,
+    HdmaSourceLo = 0xFF52,
+    HdmaDestinationHi = 0xFF53,
+    HdmaDestinationLo = 0xFF54,
+    HdmaControl = 0xFF55,
+    ColorBackgroundPaletteIndex = 0xFF68,
+    ColorBackgroundPaletteData = 0xFF69,
+    ColorObjectPaletteIndex = 0xFF6A,
+    ColorObjectPaletteData = 0xFF6B,
+    WramBank = 0xFF70,
+
     InterruptEnable = 0xFFFF,
 }
 
+const VRAM_BANK_SIZE: usize = 0x2000;
+const WRAM_BANK_SIZE: usize = 0x1000;
+const PALETTE_RAM_SIZE: usize = 64;
+// Bit 7 of BCPS and OCPS increments the index after every data write
+const PALETTE_AUTO_INCREMENT: u8 = 0b1000_0000;
+// Bit 0 of KEY1 arms a speed switch for the next STOP, bit 7 is the current speed
+const SPEED_SWITCH_ARMED: u8 = 0b0000_0001;
+const DOUBLE_SPEED: u8 = 0b1000_0000;
+// The CPU is stopped for 2050 M-cycles while the speed changes
+const SPEED_SWITCH_CYCLES: u32 = 8200;
+// Bit 7 of HDMA5 selects a transfer of one block per HBlank
+const HDMA_HBLANK_MODE: u8 = 0b1000_0000;
+const HDMA_BLOCK_SIZE: u16 = 0x10;
+// Copying a block stalls the CPU for 8 M-cycles at normal speed, the same
+// length of time takes twice as many cycles in double speed mode
+const HDMA_BLOCK_CYCLES: u32 = 32;
+// Stalls are handed out in small steps so the rest of the hardware can keep up
+const STALL_STEP: u32 = 4;
+
 pub struct Memory {
     mem: Vec<u8>,
     boot_rom: Vec<u8>,
-    mbc1: Option<MemoryBankController1>,
+    cartridge: Box<dyn Cartridge>,
+    cgb_mode: bool,
+    // DMG games on the CGB use the color palette RAM for their shades
+    compatibility_mode: bool,
+    // Two banks on the CGB, only the first is used in DMG mode
+    vram: Vec<u8>,
+    // Eight banks on the CGB, the DMG only has the first two
+    wram: Vec<u8>,
+    bg_palette_ram: [u8; PALETTE_RAM_SIZE],
+    obj_palette_ram: [u8; PALETTE_RAM_SIZE],
+    hdma_source: u16,
+    hdma_destination: u16,
+    hdma_blocks_remaining: u8,
+    hdma_hblank_active: bool,
+    // Cycles the CPU still has to wait for a DMA transfer or speed switch
+    stall_cycles: u32,
     channel_1_triggered: bool,
     channel_2_triggered: bool,
     channel_3_triggered: bool,
     channel_4_triggered: bool,
+    joypad_written: bool,
+    serial_triggered: bool,
+    wave_ram_access: WaveRamAccess,
 }
 
 #[repr(C)]
@@ -77,11 +138,26 @@ impl Memory {
         Memory {
             mem: vec![0; 0x10000],
             boot_rom: vec![0; 0x100],
-            mbc1: None,
+            cartridge: Box::new(RomOnly::new(&[0; 0x8000])),
+            cgb_mode: false,
+            compatibility_mode: false,
+            vram: vec![0; 2 * VRAM_BANK_SIZE],
+            wram: vec![0; 8 * WRAM_BANK_SIZE],
+            // The CGB boot ROM sets every background color to white
+            bg_palette_ram: [0xFF; PALETTE_RAM_SIZE],
+            obj_palette_ram: [0xFF; PALETTE_RAM_SIZE],
+            hdma_source: 0,
+            hdma_destination: 0,
+            hdma_blocks_remaining: 0,
+            hdma_hblank_active: false,
+            stall_cycles: 0,
             channel_1_triggered: false,
             channel_2_triggered: false,
             channel_3_triggered: false,
             channel_4_triggered: false,
+            joypad_written: false,
+            serial_triggered: false,
+            wave_ram_access: WaveRamAccess::Free,
         }
     }
 
@@ -119,49 +195,227 @@ impl Memory {
         self.mem[0xFFFF] = 0x00;
     }
 
-    pub fn load_boot_rom(&mut self, boot_buf: &[u8]) {
-        for i in 0..boot_buf.len() {
-            self.boot_rom[i] = boot_buf[i];
+    /// Switches between the DMG and the CGB memory map. In CGB mode VRAM and
+    /// WRAM are banked and the color palette registers are available.
+    pub fn set_cgb_mode(&mut self, enabled: bool) {
+        self.cgb_mode = enabled;
+    }
+
+    pub fn is_cgb(&self) -> bool {
+        self.cgb_mode
+    }
+
+    /// Colorizes a DMG game the way the CGB does. The palette is stored in the
+    /// color palette RAM where BGP, OBP0 and OBP1 pick their colors from.
+    pub fn set_compatibility_palette(&mut self, palette: &CompatibilityPalette) {
+        self.compatibility_mode = true;
+        write_palette_colors(&mut self.bg_palette_ram, 0, &palette.background);
+        write_palette_colors(&mut self.obj_palette_ram, 0, &palette.object0);
+        write_palette_colors(&mut self.obj_palette_ram, 1, &palette.object1);
+    }
+
+    pub fn is_compatibility_mode(&self) -> bool {
+        self.compatibility_mode
+    }
+
+    /// Whether the CGB is running in double speed mode, where the CPU and the
+    /// timers run twice as fast as the LCD and the sound hardware
+    pub fn is_double_speed(&self) -> bool {
+        self.cgb_mode && self.mem[Register::SpeedSwitch as usize] & DOUBLE_SPEED != 0
+    }
+
+    /// Called when the CPU executes STOP. Changes the CPU speed if a switch was
+    /// armed through KEY1 and returns whether it did.
+    pub fn switch_speed(&mut self) -> bool {
+        let key1 = self.mem[Register::SpeedSwitch as usize];
+        if !self.cgb_mode || key1 & SPEED_SWITCH_ARMED == 0 {
+            return false;
         }
+
+        self.mem[Register::SpeedSwitch as usize] = (key1 ^ DOUBLE_SPEED) & DOUBLE_SPEED;
+        self.mem[Register::Divider as usize] = 0;
+        self.stall_cycles += SPEED_SWITCH_CYCLES;
+        true
+    }
+
+    /// Takes the next few cycles of a pending CPU stall, or returns 0 when the
+    /// CPU is free to run
+    pub fn take_stall_cycles(&mut self) -> u8 {
+        let cycles = self.stall_cycles.min(STALL_STEP);
+        self.stall_cycles -= cycles;
+        cycles as u8
     }
 
-    pub fn load_rom(&mut self, rom_buf: &[u8]) {
-        let mbc_type = rom_buf[0x147];
-        println!("mbc type = {:02X}", mbc_type);
+    /// Called by the GPU at the start of every HBlank period to copy the next
+    /// block of an HBlank DMA transfer
+    pub fn hblank_started(&mut self) {
+        if self.hdma_hblank_active {
+            self.transfer_hdma_block();
+            self.hdma_hblank_active = self.hdma_blocks_remaining > 0;
+        }
+    }
 
-        // TODO: 19 is actually MBC3 but we will use MBC1 for now
-        let use_mbc1 = mbc_type >= 1 && mbc_type <= 3 || mbc_type == 19;
-        if use_mbc1 {
-            let mut mbc1 = MemoryBankController1::new();
-            mbc1.initialize(rom_buf);
-            self.mbc1 = Some(mbc1);
+    fn start_hdma(&mut self, control: u8) {
+        if self.hdma_hblank_active && control & HDMA_HBLANK_MODE == 0 {
+            // Stops the HBlank transfer, the remaining length can still be read
+            self.hdma_hblank_active = false;
+            return;
         }
 
-        let rom_size = if use_mbc1 { 0x4000 } else { 0x8000 };
-        for i in 0..rom_size {
-            self.mem[i] = rom_buf[i];
+        self.hdma_blocks_remaining = (control & 0x7F) + 1;
+        if control & HDMA_HBLANK_MODE != 0 {
+            self.hdma_hblank_active = true;
+            // With the LCD off there won't be an HBlank, the first block is
+            // copied right away
+            if self.mem[Register::LcdControl as usize] & 0b1000_0000 == 0 {
+                self.hblank_started();
+            }
+        } else {
+            while self.hdma_blocks_remaining > 0 {
+                self.transfer_hdma_block();
+            }
         }
     }
 
-    pub fn load_external_ram(&mut self, save_buf: &[u8]) {
-        match self.mbc1 {
-            Some(ref mut mbc1) => mbc1.load_external_ram(save_buf),
-            None => panic!("No external RAM banks"),
+    fn transfer_hdma_block(&mut self) {
+        for _ in 0..HDMA_BLOCK_SIZE {
+            let value = self.get_byte(self.hdma_source);
+            let index = self.vram_index(0x8000 | (self.hdma_destination & 0x1FFF));
+            self.vram[index] = value;
+            self.hdma_source = self.hdma_source.wrapping_add(1);
+            self.hdma_destination = self.hdma_destination.wrapping_add(1);
+        }
+
+        self.hdma_blocks_remaining -= 1;
+        self.stall_cycles += if self.is_double_speed() {
+            HDMA_BLOCK_CYCLES * 2
+        } else {
+            HDMA_BLOCK_CYCLES
         };
     }
 
-    pub fn use_battery(&self) -> bool {
-        match self.mbc1 {
-            Some(ref mbc1) => mbc1.use_battery,
-            None => false,
+    fn hdma_status(&self) -> u8 {
+        if self.hdma_blocks_remaining == 0 {
+            return 0xFF;
         }
+
+        let remaining = self.hdma_blocks_remaining - 1;
+        if self.hdma_hblank_active {
+            remaining
+        } else {
+            HDMA_HBLANK_MODE | remaining
+        }
+    }
+
+    pub fn load_boot_rom(&mut self, boot_buf: &[u8]) -> Result<(), LoadError> {
+        if boot_buf.len() > self.boot_rom.len() {
+            return Err(LoadError::InvalidBootRomSize(boot_buf.len()));
+        }
+
+        self.boot_rom[..boot_buf.len()].copy_from_slice(boot_buf);
+        Ok(())
+    }
+
+    pub fn load_rom(&mut self, rom_buf: &[u8]) -> Result<(), LoadError> {
+        self.cartridge = create_cartridge(rom_buf)?;
+        Ok(())
+    }
+
+    pub fn set_cartridge(&mut self, cartridge: Box<dyn Cartridge>) {
+        self.cartridge = cartridge;
     }
 
+    pub fn load_external_ram(&mut self, save_buf: &[u8]) -> Result<(), LoadError> {
+        self.cartridge.import_battery_data(save_buf)
+    }
+
+    pub fn use_battery(&self) -> bool {
+        self.cartridge.has_battery()
+    }
+
+    /// Returns the data kept alive by the cartridge battery: the external RAM
+    /// banks followed by the clock state for cartridges with an RTC
     pub fn get_external_ram_banks(&self) -> Vec<u8> {
-        match self.mbc1 {
-            Some(ref mbc1) => mbc1.get_external_ram_banks(),
-            None => panic!("No external RAM banks"),
+        self.cartridge.export_battery_data()
+    }
+
+    pub fn export_rtc_data(&self) -> Option<Vec<u8>> {
+        self.cartridge.export_rtc_data()
+    }
+
+    pub fn import_rtc_data(&mut self, data: &[u8]) -> Result<(), LoadError> {
+        self.cartridge.import_rtc_data(data)
+    }
+
+    pub fn external_ram(&self) -> &[u8] {
+        self.cartridge.external_ram()
+    }
+
+    pub fn external_ram_mut(&mut self) -> &mut [u8] {
+        self.cartridge.external_ram_mut()
+    }
+
+    /// Whether the rumble motor of the cartridge is currently switched on
+    pub fn rumble_active(&self) -> bool {
+        self.cartridge.rumble_active()
+    }
+
+    /// Advances hardware on the cartridge that runs independently of the CPU
+    pub fn tick_cartridge(&mut self, cycles: u8) {
+        self.cartridge.tick(cycles);
+    }
+
+    /// The video RAM, both banks in CGB mode
+    pub fn video_ram_mut(&mut self) -> &mut [u8] {
+        let banks = if self.cgb_mode { 2 } else { 1 };
+        &mut self.vram[..banks * VRAM_BANK_SIZE]
+    }
+
+    /// The internal work RAM, the two banks at 0xC000-0xDFFF or all eight
+    /// banks in CGB mode
+    pub fn work_ram_mut(&mut self) -> &mut [u8] {
+        let banks = if self.cgb_mode { 8 } else { 2 };
+        &mut self.wram[..banks * WRAM_BANK_SIZE]
+    }
+
+    /// Reads from a VRAM bank regardless of the bank selected by VBK
+    pub fn get_vram_byte(&self, bank: usize, address: u16) -> u8 {
+        self.vram[(bank * VRAM_BANK_SIZE) + (address as usize - 0x8000)]
+    }
+
+    /// A 15-bit BGR color from the CGB background palette RAM
+    pub fn bg_palette_color(&self, palette: u8, color: u8) -> u16 {
+        palette_color(&self.bg_palette_ram, palette, color)
+    }
+
+    /// A 15-bit BGR color from the CGB object palette RAM
+    pub fn obj_palette_color(&self, palette: u8, color: u8) -> u16 {
+        palette_color(&self.obj_palette_ram, palette, color)
+    }
+
+    fn vram_index(&self, address: u16) -> usize {
+        let bank = if self.cgb_mode {
+            self.mem[Register::VramBank as usize] & 1
+        } else {
+            0
+        };
+        (bank as usize * VRAM_BANK_SIZE) + (address as usize - 0x8000)
+    }
+
+    // Also handles the echo of 0xC000-0xDDFF at 0xE000-0xFDFF
+    fn wram_index(&self, address: u16) -> usize {
+        let offset = (address as usize - 0xC000) & 0x1FFF;
+        if offset < WRAM_BANK_SIZE {
+            return offset;
         }
+
+        // Selecting bank 0 maps bank 1
+        let bank = if self.cgb_mode {
+            (self.mem[Register::WramBank as usize] & 0b111).max(1)
+        } else {
+            1
+        };
+        (bank as usize * WRAM_BANK_SIZE) + (offset - WRAM_BANK_SIZE)
     }
 
     pub fn get_register(&self, reg: Register) -> u8 {
@@ -173,31 +427,72 @@ impl Memory {
             return self.boot_rom[address as usize];
         }
 
-        match self.mbc1 {
-            Some(ref mbc1) => match mbc1.get_byte(address) {
-                Some(x) => {
-                    return x;
-                }
-                None => (),
-            },
-            None => (),
+        if address < 0x8000 {
+            return self.cartridge.read_rom(address);
         }
 
-        if address >= 0xE000 && address < 0xFE00 {
-            return self.get_unchecked(address - 0x2000);
+        if address >= 0xA000 && address < 0xC000 {
+            return self.cartridge.read_ram(address);
         }
 
-        if address == Register::Channel2LengthDuty as u16 {
-            println!("Reading the duty register");
+        if address >= 0x8000 && address < 0xA000 {
+            return self.vram[self.vram_index(address)];
         }
-        if address == Register::Channel2FrequencyLo as u16 {
-            println!("Reading the freq lo register");
+
+        if address >= 0xC000 && address < 0xFE00 {
+            return self.wram[self.wram_index(address)];
         }
-        if address == Register::Channel2FrequencyHi as u16 {
-            println!("Reading the freq hi register");
+
+        if self.cgb_mode {
+            if address == Register::VramBank as u16 {
+                return 0xFE | self.mem[address as usize];
+            }
+            if address == Register::WramBank as u16 {
+                return 0xF8 | self.mem[address as usize];
+            }
+            if address == Register::SpeedSwitch as u16 {
+                return 0x7E | self.mem[address as usize];
+            }
+            if address >= Register::HdmaSourceHi as u16
+                && address <= Register::HdmaDestinationLo as u16
+            {
+                // The DMA addresses are write only
+                return 0xFF;
+            }
+            if address == Register::HdmaControl as u16 {
+                return self.hdma_status();
+            }
+            if address == Register::ColorBackgroundPaletteIndex as u16
+                || address == Register::ColorObjectPaletteIndex as u16
+            {
+                return 0x40 | self.mem[address as usize];
+            }
+            if address == Register::ColorBackgroundPaletteData as u16 {
+                let index = self.get_register(Register::ColorBackgroundPaletteIndex);
+                return self.bg_palette_ram[(index & 0x3F) as usize];
+            }
+            if address == Register::ColorObjectPaletteData as u16 {
+                let index = self.get_register(Register::ColorObjectPaletteIndex);
+                return self.obj_palette_ram[(index & 0x3F) as usize];
+            }
         }
-        if address == Register::Channel2VolumeEnvelope as u16 {
-            println!("Reading the envelope register");
+
+        if address == Register::SerialControl as u16 {
+            // Unused bits read as 1, the clock speed bit only exists on the CGB
+            let unused = if self.cgb_mode { 0x7C } else { 0x7E };
+            return unused | self.mem[address as usize];
+        }
+
+        if is_sound_register(address) {
+            return read_mask(address) | self.mem[address as usize];
+        }
+
+        if (WAVE_RAM_START..WAVE_RAM_END).contains(&address) {
+            return match self.wave_ram_access {
+                WaveRamAccess::Free => self.mem[address as usize],
+                WaveRamAccess::Byte(index) => self.mem[(WAVE_RAM_START + index as u16) as usize],
+                WaveRamAccess::Blocked => 0xFF,
+            };
         }
 
         self.get_unchecked(address)
@@ -220,13 +515,97 @@ impl Memory {
         //     println!("IE {:08b}", b);
         // }
 
-        match self.mbc1 {
-            Some(ref mut mbc1) => {
-                if mbc1.set_byte(address, b) {
-                    return;
-                }
+        if address < 0x8000 {
+            self.cartridge.write_register(address, b);
+            return;
+        }
+
+        if address >= 0xA000 && address < 0xC000 {
+            self.cartridge.write_ram(address, b);
+            return;
+        }
+
+        //TODO: investigate if this behavior is correct, it seems to break the boot ROM
+        // if address >= 0x8000 && address < 0xA000 {
+        //     // Cannot write VRAM during LCD mode 3 accessing VRAM
+        //     let lcd_mode = self.mem[0xFF41] & 0b11;
+        //     if lcd_mode == 3 {
+        //         return;
+        //     }
+        // }
+
+        if address >= 0x8000 && address < 0xA000 {
+            let index = self.vram_index(address);
+            self.vram[index] = b;
+            return;
+        }
+
+        if address >= 0xC000 && address < 0xFE00 {
+            let index = self.wram_index(address);
+            self.wram[index] = b;
+            return;
+        }
+
+        if self.cgb_mode {
+            if address == Register::VramBank as u16 {
+                self.mem[address as usize] = b & 0b1;
+                return;
+            }
+            if address == Register::WramBank as u16 {
+                self.mem[address as usize] = b & 0b111;
+                return;
+            }
+            if address == Register::SpeedSwitch as u16 {
+                let speed = self.mem[address as usize] & DOUBLE_SPEED;
+                self.mem[address as usize] = speed | (b & SPEED_SWITCH_ARMED);
+                return;
+            }
+            if address == Register::HdmaSourceHi as u16 {
+                self.hdma_source = concat_bytes(b, get_lower(self.hdma_source));
+                return;
+            }
+            if address == Register::HdmaSourceLo as u16 {
+                self.hdma_source = concat_bytes(get_upper(self.hdma_source), b & 0xF0);
+                return;
+            }
+            if address == Register::HdmaDestinationHi as u16 {
+                // The destination is always in VRAM
+                self.hdma_destination =
+                    concat_bytes(0x80 | (b & 0x1F), get_lower(self.hdma_destination));
+                return;
+            }
+            if address == Register::HdmaDestinationLo as u16 {
+                self.hdma_destination = concat_bytes(get_upper(self.hdma_destination), b & 0xF0);
+                return;
+            }
+            if address == Register::HdmaControl as u16 {
+                self.start_hdma(b);
+                return;
+            }
+            if address == Register::ColorBackgroundPaletteIndex as u16
+                || address == Register::ColorObjectPaletteIndex as u16
+            {
+                self.mem[address as usize] = b & 0b1011_1111;
+                return;
+            }
+            if address == Register::ColorBackgroundPaletteData as u16 {
+                let index = write_palette_ram(
+                    &mut self.bg_palette_ram,
+                    self.mem[Register::ColorBackgroundPaletteIndex as usize],
+                    b,
+                );
+                self.mem[Register::ColorBackgroundPaletteIndex as usize] = index;
+                return;
+            }
+            if address == Register::ColorObjectPaletteData as u16 {
+                let index = write_palette_ram(
+                    &mut self.obj_palette_ram,
+                    self.mem[Register::ColorObjectPaletteIndex as usize],
+                    b,
+                );
+                self.mem[Register::ColorObjectPaletteIndex as usize] = index;
+                return;
             }
-            None => (),
         }
 
         // blarrg's test roms store whether the machine is color or not at D800
@@ -237,6 +616,29 @@ impl Memory {
         //     return;
         // }
 
+        if address == Register::SoundEnable as u16 {
+            self.write_sound_enable(b);
+            return;
+        }
+
+        if is_sound_register(address) && !self.is_sound_powered() {
+            if let Some(value) = write_while_off(address, b, self.cgb_mode) {
+                self.mem[address as usize] = value;
+            }
+            return;
+        }
+
+        if (WAVE_RAM_START..WAVE_RAM_END).contains(&address) {
+            match self.wave_ram_access {
+                WaveRamAccess::Free => self.mem[address as usize] = b,
+                WaveRamAccess::Byte(index) => {
+                    self.mem[(WAVE_RAM_START + index as u16) as usize] = b
+                }
+                WaveRamAccess::Blocked => {}
+            }
+            return;
+        }
+
         if address == 0xFF07 {
             println!("TAC set to {}", b);
         }
@@ -272,20 +674,6 @@ impl Memory {
             }
         }
 
-        //TODO: investigate if this behavior is correct, it seems to break the boot ROM
-        // if address >= 0x8000 && address < 0xA000 {
-        //     // Cannot write VRAM during LCD mode 3 accessing VRAM
-        //     let lcd_mode = self.mem[0xFF41] & 0b11;
-        //     if lcd_mode == 3 {
-        //         return;
-        //     }
-        // }
-
-        if address >= 0xE000 && address < 0xFE00 {
-            self.mem[(address - 0x2000) as usize] = b;
-            return;
-        }
-
         if address == 0xFF44 {
             self.mem[address as usize] = 0;
             return;
@@ -295,16 +683,11 @@ impl Memory {
             // OAM DMA transfer
             for trans_addr in 0x00..0xA0 {
                 self.mem[(0xFE00 + (trans_addr as u16)) as usize] =
-                    self.mem[concat_bytes(b, trans_addr) as usize];
+                    self.get_byte(concat_bytes(b, trans_addr));
             }
             // return;
         }
 
-        if address < 0x8000 {
-            //Read only
-            return;
-        }
-
         if address == Register::Channel1FrequencyHi as u16 {
             if b & 0b1000_0000 != 0 {
                 self.channel_1_triggered = true;
@@ -326,15 +709,37 @@ impl Memory {
             }
         }
 
-        if address == Register::SoundEnable as u16 {
-            println!("Writing to FF26 {}", b);
-            self.mem[address as usize] = b & 0b1000_0000;
-            return;
+        if address == Register::Joypad as u16 {
+            self.joypad_written = true;
+        }
+
+        if address == Register::SerialControl as u16 && b & 0b1000_0000 != 0 {
+            self.serial_triggered = true;
         }
 
         self.mem[address as usize] = b;
     }
 
+    // Only the power bit can be written, the channel status bits are kept up
+    // to date by the sound controller. Powering off clears every register.
+    fn write_sound_enable(&mut self, b: u8) {
+        let address = Register::SoundEnable as usize;
+        if b & SOUND_POWER != 0 {
+            self.mem[address] |= SOUND_POWER;
+        } else {
+            self.mem[SOUND_REGISTERS_START as usize..=address].fill(0);
+        }
+    }
+
+    pub fn is_sound_powered(&self) -> bool {
+        self.mem[Register::SoundEnable as usize] & SOUND_POWER != 0
+    }
+
+    /// Sets how the CPU sees wave RAM while channel 3 reads from it
+    pub fn set_wave_ram_access(&mut self, access: WaveRamAccess) {
+        self.wave_ram_access = access;
+    }
+
     pub fn set_owned_byte(&mut self, address: u16, value: u8) {
         self.set_unchecked(address, value);
     }
@@ -378,10 +783,115 @@ impl Memory {
         self.channel_4_triggered
     }
 
+    /// Whether P1 was written since the last reset, the Super Game Boy
+    /// receives its commands through it
+    pub fn joypad_written(&self) -> bool {
+        self.joypad_written
+    }
+
+    /// Whether a serial transfer was started since the last reset
+    pub fn serial_triggered(&self) -> bool {
+        self.serial_triggered
+    }
+
     pub fn reset_triggers(&mut self) {
         self.channel_1_triggered = false;
         self.channel_2_triggered = false;
         self.channel_3_triggered = false;
         self.channel_4_triggered = false;
+        self.joypad_written = false;
+        self.serial_triggered = false;
+    }
+}
+
+// Palettes are stored as four little endian 15-bit colors of 2 bytes each
+fn palette_color(palette_ram: &[u8; PALETTE_RAM_SIZE], palette: u8, color: u8) -> u16 {
+    let index = ((palette as usize & 0b111) * 8) + (color as usize * 2);
+    concat_bytes(palette_ram[index + 1], palette_ram[index])
+}
+
+fn write_palette_colors(
+    palette_ram: &mut [u8; PALETTE_RAM_SIZE],
+    palette: usize,
+    colors: &[u16; 4],
+) {
+    for (i, color) in colors.iter().enumerate() {
+        let index = (palette * 8) + (i * 2);
+        palette_ram[index] = get_lower(*color);
+        palette_ram[index + 1] = get_upper(*color);
+    }
+}
+
+// Returns the new value of the index register
+fn write_palette_ram(
+    palette_ram: &mut [u8; PALETTE_RAM_SIZE],
+    index_register: u8,
+    value: u8,
+) -> u8 {
+    let index = index_register & 0x3F;
+    palette_ram[index as usize] = value;
+    if index_register & PALETTE_AUTO_INCREMENT != 0 {
+        PALETTE_AUTO_INCREMENT | ((index + 1) & 0x3F)
+    } else {
+        index_register
+    }
+}
+
+impl SaveState for Memory {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bytes(&self.mem);
+        writer.write_bytes(&self.vram);
+        writer.write_bytes(&self.wram);
+        writer.write_bytes(&self.bg_palette_ram);
+        writer.write_bytes(&self.obj_palette_ram);
+        writer.write_u16(self.hdma_source);
+        writer.write_u16(self.hdma_destination);
+        writer.write_u8(self.hdma_blocks_remaining);
+        writer.write_bool(self.hdma_hblank_active);
+        writer.write_u32(self.stall_cycles);
+        writer.write_bool(self.channel_1_triggered);
+        writer.write_bool(self.channel_2_triggered);
+        writer.write_bool(self.channel_3_triggered);
+        writer.write_bool(self.channel_4_triggered);
+        writer.write_bool(self.joypad_written);
+        writer.write_bool(self.serial_triggered);
+        writer.write_u8(self.wave_ram_access.to_u8());
+
+        let mut cartridge_writer = StateWriter::new();
+        self.cartridge.save_state(&mut cartridge_writer);
+        writer.write_bytes(&cartridge_writer.into_inner());
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        reader.read_bytes_into(&mut self.mem)?;
+        reader.read_bytes_into(&mut self.vram)?;
+        reader.read_bytes_into(&mut self.wram)?;
+        reader.read_bytes_into(&mut self.bg_palette_ram)?;
+        reader.read_bytes_into(&mut self.obj_palette_ram)?;
+        self.hdma_source = reader.read_u16()?;
+        self.hdma_destination = reader.read_u16()?;
+        self.hdma_blocks_remaining = reader.read_u8()?;
+        self.hdma_hblank_active = reader.read_bool()?;
+        if self.hdma_blocks_remaining > 0x80 {
+            return Err(SaveStateError::InvalidData("HDMA length out of range"));
+        }
+        self.stall_cycles = reader.read_u32()?;
+        self.channel_1_triggered = reader.read_bool()?;
+        self.channel_2_triggered = reader.read_bool()?;
+        self.channel_3_triggered = reader.read_bool()?;
+        self.channel_4_triggered = reader.read_bool()?;
+        self.joypad_written = reader.read_bool()?;
+        self.serial_triggered = reader.read_bool()?;
+        self.wave_ram_access = WaveRamAccess::from_u8(reader.read_u8()?)
+            .ok_or(SaveStateError::InvalidData("wave RAM access out of range"))?;
+
+        let mut cartridge_reader = StateReader::new(reader.read_bytes()?);
+        self.cartridge.load_state(&mut cartridge_reader)?;
+        if !cartridge_reader.is_at_end() {
+            return Err(SaveStateError::InvalidData(
+                "cartridge state does not match the loaded game",
+            ));
+        }
+        Ok(())
     }
 }
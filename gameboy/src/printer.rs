@@ -0,0 +1,373 @@
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+use crate::serial::SerialDevice;
+
+const MAGIC: [u8; 2] = [0x88, 0x33];
+
+const COMMAND_INIT: u8 = 0x01;
+const COMMAND_PRINT: u8 = 0x02;
+const COMMAND_DATA: u8 = 0x04;
+const COMMAND_STATUS: u8 = 0x0F;
+
+const STATUS_CHECKSUM_ERROR: u8 = 0b0000_0001;
+const STATUS_PRINTING: u8 = 0b0000_0010;
+const STATUS_IMAGE_FULL: u8 = 0b0000_0100;
+const STATUS_UNPROCESSED: u8 = 0b0000_1000;
+const STATUS_PACKET_ERROR: u8 = 0b0001_0000;
+
+// Answered in place of the first status byte to show a printer is connected
+const ALIVE: u8 = 0x81;
+
+const PAPER_WIDTH: usize = 160;
+const TILES_PER_ROW: usize = PAPER_WIDTH / 8;
+const BYTES_PER_TILE: usize = 16;
+// A data packet holds a band of two rows of tiles, the printer stores nine
+const BAND_SIZE: usize = 2 * TILES_PER_ROW * BYTES_PER_TILE;
+const BUFFER_SIZE: usize = 9 * BAND_SIZE;
+// Blank lines fed for each unit of the margins of a print
+const MARGIN_LINES: usize = 8;
+
+const SHADES: [u8; 4] = [0xFF, 0xAA, 0x55, 0x00];
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum PacketState {
+    Magic(usize),
+    Command,
+    Compression,
+    LengthLow,
+    LengthHigh,
+    Data,
+    ChecksumLow,
+    ChecksumHigh,
+    Alive,
+    Status,
+}
+
+/// The Game Boy Printer. Games send it packets of tile data through the link
+/// port and then tell it to print. Everything printed up to a print with a
+/// bottom margin ends up on one sheet, which is saved as a grayscale PNG
+/// image 160 pixels wide in the output directory.
+pub struct Printer {
+    directory: PathBuf,
+    state: PacketState,
+    command: u8,
+    compressed: bool,
+    length: usize,
+    packet: Vec<u8>,
+    checksum: u16,
+    received_checksum: u16,
+    status: u8,
+    // Tile data waiting to be printed
+    buffer: Vec<u8>,
+    // The pixels printed on the current sheet, one shade per byte
+    paper: Vec<u8>,
+    next_sheet: u32,
+}
+
+impl Printer {
+    /// Creates a printer that saves its sheets in `directory`, which is
+    /// created if it is missing
+    pub fn new<P: Into<PathBuf>>(directory: P) -> io::Result<Printer> {
+        let directory = directory.into();
+        fs::create_dir_all(&directory)?;
+        Ok(Printer {
+            directory,
+            state: PacketState::Magic(0),
+            command: 0,
+            compressed: false,
+            length: 0,
+            packet: Vec::new(),
+            checksum: 0,
+            received_checksum: 0,
+            status: 0,
+            buffer: Vec::with_capacity(BUFFER_SIZE),
+            paper: Vec::new(),
+            next_sheet: 1,
+        })
+    }
+
+    fn execute(&mut self) {
+        if self.checksum != self.received_checksum {
+            self.status |= STATUS_CHECKSUM_ERROR;
+            return;
+        }
+        self.status &= !STATUS_CHECKSUM_ERROR;
+
+        match self.command {
+            COMMAND_INIT => {
+                self.buffer.clear();
+                self.status = 0;
+            }
+            COMMAND_DATA => {
+                let data = std::mem::take(&mut self.packet);
+                if self.compressed {
+                    decompress(&data, &mut self.buffer);
+                } else {
+                    self.buffer.extend_from_slice(&data);
+                }
+                self.buffer.truncate(BUFFER_SIZE);
+                if !self.buffer.is_empty() {
+                    self.status |= STATUS_UNPROCESSED;
+                }
+                if self.buffer.len() == BUFFER_SIZE {
+                    self.status |= STATUS_IMAGE_FULL;
+                }
+            }
+            COMMAND_PRINT if self.packet.len() == 4 => {
+                let sheets = self.packet[0];
+                let margins = self.packet[1];
+                let palette = self.packet[2];
+                self.print(sheets, margins >> 4, margins & 0x0F, palette);
+            }
+            COMMAND_PRINT => self.status |= STATUS_PACKET_ERROR,
+            _ => {}
+        }
+    }
+
+    fn print(&mut self, sheets: u8, top_margin: u8, bottom_margin: u8, palette: u8) {
+        self.feed(top_margin);
+        // No copies just feeds the paper
+        if sheets > 0 {
+            // Games often leave the palette at zero to mean the usual one
+            let palette = if palette == 0 { 0b1110_0100 } else { palette };
+            let rows = self.buffer.len() / (TILES_PER_ROW * BYTES_PER_TILE);
+            for row in 0..rows {
+                for line in 0..8 {
+                    for x in 0..PAPER_WIDTH {
+                        let tile = row * TILES_PER_ROW + x / 8;
+                        let offset = tile * BYTES_PER_TILE + line * 2;
+                        let bit = 7 - (x % 8);
+                        let low = (self.buffer[offset] >> bit) & 1;
+                        let high = (self.buffer[offset + 1] >> bit) & 1;
+                        let color = (high << 1) | low;
+                        let shade = (palette >> (color * 2)) & 0b11;
+                        self.paper.push(SHADES[shade as usize]);
+                    }
+                }
+            }
+            self.status |= STATUS_PRINTING;
+        }
+        self.buffer.clear();
+        self.status &= !(STATUS_UNPROCESSED | STATUS_IMAGE_FULL);
+
+        self.feed(bottom_margin);
+        if bottom_margin > 0 {
+            self.cut();
+        }
+    }
+
+    fn feed(&mut self, margin: u8) {
+        // Feeding blank paper before anything is printed leaves no mark
+        if !self.paper.is_empty() {
+            let lines = margin as usize * MARGIN_LINES;
+            self.paper
+                .resize(self.paper.len() + lines * PAPER_WIDTH, SHADES[0]);
+        }
+    }
+
+    // Saves the current sheet and starts a new one
+    fn cut(&mut self) {
+        if self.paper.is_empty() {
+            return;
+        }
+        let paper = std::mem::take(&mut self.paper);
+        let path = loop {
+            let path = self
+                .directory
+                .join(format!("print_{:04}.png", self.next_sheet));
+            self.next_sheet += 1;
+            if !path.exists() {
+                break path;
+            }
+        };
+
+        let png = encode_png(
+            &paper,
+            PAPER_WIDTH as u32,
+            (paper.len() / PAPER_WIDTH) as u32,
+        );
+        match fs::write(&path, png) {
+            Ok(()) => println!("Printed {}", path.display()),
+            Err(x) => eprintln!("Could not save {}: {}", path.display(), x),
+        }
+    }
+}
+
+impl Drop for Printer {
+    fn drop(&mut self) {
+        // Games that print without a bottom margin leave the last sheet in
+        // the printer, save it when the printer is disconnected
+        self.cut();
+    }
+}
+
+impl SerialDevice for Printer {
+    fn transfer(&mut self, outgoing: u8) -> u8 {
+        let mut response = 0x00;
+        self.state = match self.state {
+            PacketState::Magic(index) if outgoing == MAGIC[index] => {
+                if index + 1 < MAGIC.len() {
+                    PacketState::Magic(index + 1)
+                } else {
+                    PacketState::Command
+                }
+            }
+            // Bytes outside of a packet are ignored until the next one starts
+            PacketState::Magic(_) if outgoing == MAGIC[0] => PacketState::Magic(1),
+            PacketState::Magic(_) => PacketState::Magic(0),
+            PacketState::Command => {
+                self.command = outgoing;
+                self.checksum = outgoing as u16;
+                PacketState::Compression
+            }
+            PacketState::Compression => {
+                self.compressed = outgoing & 1 != 0;
+                self.checksum = self.checksum.wrapping_add(outgoing as u16);
+                PacketState::LengthLow
+            }
+            PacketState::LengthLow => {
+                self.length = outgoing as usize;
+                self.checksum = self.checksum.wrapping_add(outgoing as u16);
+                PacketState::LengthHigh
+            }
+            PacketState::LengthHigh => {
+                self.length |= (outgoing as usize) << 8;
+                self.checksum = self.checksum.wrapping_add(outgoing as u16);
+                self.packet.clear();
+                if self.length > 0 {
+                    PacketState::Data
+                } else {
+                    PacketState::ChecksumLow
+                }
+            }
+            PacketState::Data => {
+                self.packet.push(outgoing);
+                self.checksum = self.checksum.wrapping_add(outgoing as u16);
+                if self.packet.len() < self.length {
+                    PacketState::Data
+                } else {
+                    PacketState::ChecksumLow
+                }
+            }
+            PacketState::ChecksumLow => {
+                self.received_checksum = outgoing as u16;
+                PacketState::ChecksumHigh
+            }
+            PacketState::ChecksumHigh => {
+                self.received_checksum |= (outgoing as u16) << 8;
+                self.execute();
+                PacketState::Alive
+            }
+            PacketState::Alive => {
+                response = ALIVE;
+                PacketState::Status
+            }
+            PacketState::Status => {
+                response = self.status;
+                // Printing is done once a game has seen the printer busy
+                if self.command == COMMAND_STATUS {
+                    self.status &= !STATUS_PRINTING;
+                }
+                PacketState::Magic(0)
+            }
+        };
+        response
+    }
+}
+
+// Expands data compressed with runs. A control byte with the top bit set
+// repeats the next byte (control & 0x7F) + 2 times, otherwise the next
+// control + 1 bytes are copied as they are.
+fn decompress(data: &[u8], output: &mut Vec<u8>) {
+    let mut index = 0;
+    while index < data.len() {
+        let control = data[index];
+        index += 1;
+        if control & 0x80 != 0 {
+            let count = (control & 0x7F) as usize + 2;
+            if let Some(&value) = data.get(index) {
+                output.resize(output.len() + count, value);
+            }
+            index += 1;
+        } else {
+            let end = (index + control as usize + 1).min(data.len());
+            output.extend_from_slice(&data[index..end]);
+            index = end;
+        }
+    }
+}
+
+/// Encodes 8-bit grayscale pixels as a PNG image. The image data is stored
+/// without compression, which keeps the encoder small.
+pub fn encode_png(pixels: &[u8], width: u32, height: u32) -> Vec<u8> {
+    let mut png = vec![0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+
+    let mut header = Vec::with_capacity(13);
+    header.extend_from_slice(&width.to_be_bytes());
+    header.extend_from_slice(&height.to_be_bytes());
+    // 8 bits per pixel, grayscale, deflate, adaptive filtering, no interlace
+    header.extend_from_slice(&[8, 0, 0, 0, 0]);
+    write_chunk(&mut png, b"IHDR", &header);
+
+    // Every line starts with its filter type, which is none
+    let mut lines = Vec::with_capacity(pixels.len() + height as usize);
+    for line in pixels.chunks(width as usize) {
+        lines.push(0);
+        lines.extend_from_slice(line);
+    }
+
+    // A zlib stream made of stored deflate blocks
+    let mut data = vec![0x78, 0x01];
+    let mut blocks = lines.chunks(0xFFFF).peekable();
+    if blocks.peek().is_none() {
+        data.extend_from_slice(&[1, 0x00, 0x00, 0xFF, 0xFF]);
+    }
+    while let Some(block) = blocks.next() {
+        let last = blocks.peek().is_none();
+        let length = block.len() as u16;
+        data.push(last as u8);
+        data.extend_from_slice(&length.to_le_bytes());
+        data.extend_from_slice(&(!length).to_le_bytes());
+        data.extend_from_slice(block);
+    }
+    data.extend_from_slice(&adler32(&lines).to_be_bytes());
+    write_chunk(&mut png, b"IDAT", &data);
+
+    write_chunk(&mut png, b"IEND", &[]);
+    png
+}
+
+fn write_chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = png.len();
+    png.extend_from_slice(kind);
+    png.extend_from_slice(data);
+    let crc = crc32(&png[start..]);
+    png.extend_from_slice(&crc.to_be_bytes());
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for &byte in data {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
@@ -0,0 +1,171 @@
+use std::fmt;
+
+const MAGIC: &[u8; 4] = b"GBSS";
+
+/// Incremented whenever the layout of a serialized component changes so that
+/// states written by older builds are rejected instead of misread.
+pub const STATE_VERSION: u32 = 19;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SaveStateError {
+    InvalidHeader,
+    UnsupportedVersion(u32),
+    UnexpectedEnd,
+    InvalidData(&'static str),
+}
+
+impl fmt::Display for SaveStateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SaveStateError::InvalidHeader => write!(f, "data is not a save state"),
+            SaveStateError::UnsupportedVersion(version) => write!(
+                f,
+                "save state version {} is not supported (expected {})",
+                version, STATE_VERSION
+            ),
+            SaveStateError::UnexpectedEnd => write!(f, "save state is truncated"),
+            SaveStateError::InvalidData(reason) => write!(f, "save state is invalid: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for SaveStateError {}
+
+/// Implemented by every component that holds emulation state.
+pub trait SaveState {
+    fn save_state(&self, writer: &mut StateWriter);
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError>;
+}
+
+#[derive(Default)]
+pub struct StateWriter {
+    buf: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> Self {
+        Self { buf: Vec::new() }
+    }
+
+    pub fn write_header(&mut self) {
+        self.buf.extend_from_slice(MAGIC);
+        self.write_u32(STATE_VERSION);
+    }
+
+    pub fn write_u8(&mut self, value: u8) {
+        self.buf.push(value);
+    }
+
+    pub fn write_bool(&mut self, value: bool) {
+        self.write_u8(value as u8);
+    }
+
+    pub fn write_u16(&mut self, value: u16) {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_u32(&mut self, value: u32) {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_u64(&mut self, value: u64) {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    /// Writes a length prefixed block of bytes
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        self.write_u32(bytes.len() as u32);
+        self.buf.extend_from_slice(bytes);
+    }
+
+    pub fn into_inner(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data, position: 0 }
+    }
+
+    pub fn read_header(&mut self) -> Result<(), SaveStateError> {
+        if self.data.len() < MAGIC.len() || &self.data[..MAGIC.len()] != MAGIC {
+            return Err(SaveStateError::InvalidHeader);
+        }
+        self.position = MAGIC.len();
+
+        let version = self.read_u32()?;
+        if version != STATE_VERSION {
+            return Err(SaveStateError::UnsupportedVersion(version));
+        }
+
+        Ok(())
+    }
+
+    pub fn is_at_end(&self) -> bool {
+        self.position == self.data.len()
+    }
+
+    fn take(&mut self, length: usize) -> Result<&'a [u8], SaveStateError> {
+        if self.data.len() - self.position < length {
+            return Err(SaveStateError::UnexpectedEnd);
+        }
+
+        let result = &self.data[self.position..self.position + length];
+        self.position += length;
+        Ok(result)
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, SaveStateError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn read_bool(&mut self) -> Result<bool, SaveStateError> {
+        match self.read_u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(SaveStateError::InvalidData("boolean out of range")),
+        }
+    }
+
+    pub fn read_u16(&mut self) -> Result<u16, SaveStateError> {
+        let mut bytes = [0; 2];
+        bytes.copy_from_slice(self.take(2)?);
+        Ok(u16::from_le_bytes(bytes))
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, SaveStateError> {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64, SaveStateError> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    /// Reads a length prefixed block of bytes written by `StateWriter::write_bytes`
+    pub fn read_bytes(&mut self) -> Result<&'a [u8], SaveStateError> {
+        let length = self.read_u32()? as usize;
+        self.take(length)
+    }
+
+    /// Reads a length prefixed block of bytes into `dest`, which must be the
+    /// same size as the block that was written
+    pub fn read_bytes_into(&mut self, dest: &mut [u8]) -> Result<(), SaveStateError> {
+        let bytes = self.read_bytes()?;
+        if bytes.len() != dest.len() {
+            return Err(SaveStateError::InvalidData("memory block size mismatch"));
+        }
+
+        dest.copy_from_slice(bytes);
+        Ok(())
+    }
+}
@@ -0,0 +1,130 @@
+use crate::game_boy::GameBoy;
+use crate::memory::Register;
+use crate::save_state::{SaveState, SaveStateError, StateReader, StateWriter};
+
+const SERIAL_TRANSFER_START: u8 = 0b1000_0000;
+const SERIAL_FAST_CLOCK: u8 = 0b0000_0010;
+const SERIAL_INTERNAL_CLOCK: u8 = 0b0000_0001;
+
+// The internal clock shifts one bit every 512 cycles at 8192Hz, the CGB can
+// also run it at 262144Hz. Both rates double in double speed mode because
+// they are counted in CPU cycles.
+const BIT_CYCLES: u32 = 512;
+const BYTE_CYCLES: u32 = 8 * BIT_CYCLES;
+const FAST_BYTE_CYCLES: u32 = 8 * 16;
+
+/// Something plugged into the link port. The Game Boy that drives the clock
+/// decides when a byte is exchanged, both sides send and receive a byte at the
+/// same time.
+pub trait SerialDevice {
+    /// Called when the Game Boy finished clocking out `outgoing` with its
+    /// internal clock. Returns the byte the device sent back.
+    fn transfer(&mut self, outgoing: u8) -> u8;
+
+    /// Called regularly to check for transfers clocked by the device.
+    /// `outgoing` holds the byte the Game Boy sends in return while it waits
+    /// for such a transfer, and is `None` while it is not ready, in which case
+    /// the device receives 0xFF. Returns the byte the device sent if a
+    /// transfer the Game Boy was ready for happened.
+    fn poll_external_transfer(&mut self, _outgoing: Option<u8>) -> Option<u8> {
+        None
+    }
+}
+
+/// An empty link port. Nothing drives the data line so every bit reads as 1,
+/// and transfers using an external clock never finish.
+pub struct Disconnected;
+
+impl SerialDevice for Disconnected {
+    fn transfer(&mut self, _outgoing: u8) -> u8 {
+        0xFF
+    }
+}
+
+/// The serial port registers SB and SC, which shift bytes through the
+/// attached `SerialDevice`
+pub struct Serial {
+    device: Box<dyn SerialDevice>,
+    // Cycles until a transfer using the internal clock finishes
+    cycles_remaining: u32,
+    // The device is checked for transfers it clocks once per bit
+    poll_cycles: u32,
+}
+
+impl Default for Serial {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Serial {
+    pub fn new() -> Serial {
+        Serial {
+            device: Box::new(Disconnected),
+            cycles_remaining: 0,
+            poll_cycles: 0,
+        }
+    }
+
+    /// Plugs a device into the link port and returns the one that was there
+    pub fn connect(&mut self, device: Box<dyn SerialDevice>) -> Box<dyn SerialDevice> {
+        std::mem::replace(&mut self.device, device)
+    }
+
+    pub fn tick(&mut self, gb: &mut GameBoy, cycles: u8) {
+        let control = gb.memory.get_register(Register::SerialControl);
+        if gb.memory.serial_triggered() {
+            self.cycles_remaining = if gb.memory.is_cgb() && control & SERIAL_FAST_CLOCK != 0 {
+                FAST_BYTE_CYCLES
+            } else {
+                BYTE_CYCLES
+            };
+        }
+
+        let transferring = control & SERIAL_TRANSFER_START != 0;
+        let internal_clock = control & SERIAL_INTERNAL_CLOCK != 0;
+        let outgoing = gb.memory.get_register(Register::SerialData);
+        let incoming = if transferring && internal_clock {
+            self.cycles_remaining = self.cycles_remaining.saturating_sub(cycles as u32);
+            if self.cycles_remaining > 0 {
+                return;
+            }
+            self.device.transfer(outgoing)
+        } else {
+            self.poll_cycles += cycles as u32;
+            if self.poll_cycles < BIT_CYCLES {
+                return;
+            }
+            self.poll_cycles -= BIT_CYCLES;
+
+            let ready = transferring && !internal_clock;
+            match self
+                .device
+                .poll_external_transfer(Some(outgoing).filter(|_| ready))
+            {
+                Some(incoming) if ready => incoming,
+                _ => return,
+            }
+        };
+
+        gb.memory.set_register(Register::SerialData, incoming);
+        gb.memory
+            .set_register(Register::SerialControl, control & !SERIAL_TRANSFER_START);
+        let int_flags = gb.memory.get_register(Register::InterruptFlag);
+        gb.memory
+            .set_register(Register::InterruptFlag, int_flags | 0b1000);
+    }
+}
+
+impl SaveState for Serial {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u32(self.cycles_remaining);
+        writer.write_u32(self.poll_cycles);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.cycles_remaining = reader.read_u32()?;
+        self.poll_cycles = reader.read_u32()? % BIT_CYCLES;
+        Ok(())
+    }
+}
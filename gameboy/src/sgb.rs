@@ -0,0 +1,561 @@
+use crate::gpu::{HORIZONTAL_RES, VERTICAL_RES};
+use crate::save_state::{SaveState, SaveStateError, StateReader, StateWriter};
+use crate::util::concat_bytes;
+
+pub const SCREEN_WIDTH: u32 = 256;
+pub const SCREEN_HEIGHT: u32 = 224;
+// The Game Boy screen sits in the middle of the border
+const LCD_X: usize = 48;
+const LCD_Y: usize = 40;
+
+const PACKET_SIZE: usize = 16;
+const PACKET_BITS: usize = PACKET_SIZE * 8;
+// The longest commands are made of seven packets
+const MAX_COMMAND_SIZE: usize = 7 * PACKET_SIZE;
+// The LCD is divided into 20x18 cells of 8x8 pixels that each use one of the
+// four palettes
+const CELLS_X: usize = 20;
+const CELLS_Y: usize = 18;
+const ATTRIBUTE_FILE_SIZE: usize = (CELLS_X * CELLS_Y) / 4;
+const ATTRIBUTE_FILE_COUNT: usize = 45;
+
+const TRANSFER_SIZE: usize = 0x1000;
+const BORDER_TILE_SIZE: usize = 32;
+const BORDER_MAP_SIZE: usize = 0x800;
+// Four palettes of 16 colors follow the tile map in PCT_TRN data
+const BORDER_PICTURE_SIZE: usize = BORDER_MAP_SIZE + (4 * 16 * 2);
+
+// The palettes shown before a game sends its own
+const DEFAULT_PALETTE: [u16; 4] = [0x639E, 0x263A, 0x10D4, 0x2866];
+
+const PAL01: u8 = 0x00;
+const PAL23: u8 = 0x01;
+const PAL03: u8 = 0x02;
+const PAL12: u8 = 0x03;
+const ATTR_BLK: u8 = 0x04;
+const ATTR_LIN: u8 = 0x05;
+const ATTR_DIV: u8 = 0x06;
+const ATTR_CHR: u8 = 0x07;
+const PAL_SET: u8 = 0x0A;
+const PAL_TRN: u8 = 0x0B;
+const MLT_REQ: u8 = 0x11;
+const CHR_TRN: u8 = 0x13;
+const PCT_TRN: u8 = 0x14;
+const ATTR_TRN: u8 = 0x15;
+const ATTR_SET: u8 = 0x16;
+const MASK_EN: u8 = 0x17;
+
+const MASK_NONE: u8 = 0;
+const MASK_FREEZE: u8 = 1;
+const MASK_BLACK: u8 = 2;
+const MASK_COLOR_0: u8 = 3;
+
+/// The Super Game Boy. Games talk to it by sending 16 byte packets one bit at
+/// a time through the joypad register, and bulk data such as borders is sent
+/// by showing it on the screen. It colors the LCD output and draws it inside
+/// a 256x224 border.
+pub struct SuperGameBoy {
+    // Packet reception
+    receiving: bool,
+    bit_index: usize,
+    packet: [u8; PACKET_SIZE],
+    command: Vec<u8>,
+    last_select: u8,
+    // Command set up by a *_TRN command, read from the screen once a frame
+    // has been drawn
+    pending_transfer: Option<(u8, u8)>,
+
+    palettes: [[u16; 4]; 4],
+    system_palettes: Vec<u8>,
+    attributes: [u8; CELLS_X * CELLS_Y],
+    attribute_files: Vec<u8>,
+    border_tiles: Vec<u8>,
+    border_picture: Vec<u8>,
+    mask: u8,
+    players: u8,
+    player: u8,
+    // The LCD output shown inside the border, one shade per pixel
+    screen: Vec<u8>,
+}
+
+impl Default for SuperGameBoy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SuperGameBoy {
+    pub fn new() -> SuperGameBoy {
+        SuperGameBoy {
+            receiving: false,
+            bit_index: 0,
+            packet: [0; PACKET_SIZE],
+            command: Vec::new(),
+            last_select: 0x30,
+            pending_transfer: None,
+            palettes: [DEFAULT_PALETTE; 4],
+            system_palettes: vec![0; TRANSFER_SIZE],
+            attributes: [0; CELLS_X * CELLS_Y],
+            attribute_files: vec![0; ATTRIBUTE_FILE_SIZE * ATTRIBUTE_FILE_COUNT],
+            border_tiles: vec![0; 2 * TRANSFER_SIZE],
+            border_picture: vec![0; BORDER_PICTURE_SIZE],
+            mask: MASK_NONE,
+            players: 1,
+            player: 0,
+            screen: vec![0; HORIZONTAL_RES as usize * VERTICAL_RES as usize],
+        }
+    }
+
+    /// The joypad that the game currently reads from
+    pub fn current_player(&self) -> usize {
+        self.player as usize
+    }
+
+    /// Receives a write to P1. Pulling both P14 and P15 low starts a packet,
+    /// after which P14 low sends a 0 bit and P15 low sends a 1 bit with both
+    /// lines going high again in between.
+    pub fn joypad_written(&mut self, value: u8) {
+        let select = value & 0x30;
+        let last_select = self.last_select;
+        self.last_select = select;
+
+        // Joypads are read in turn, moving on whenever P15 goes high
+        if self.players > 1 && last_select & 0x20 == 0 && select & 0x20 != 0 {
+            self.player = (self.player + 1) % self.players;
+        }
+
+        if select == 0x00 {
+            self.receiving = true;
+            self.bit_index = 0;
+            self.packet = [0; PACKET_SIZE];
+            return;
+        }
+
+        if !self.receiving || last_select != 0x30 || select == 0x30 {
+            return;
+        }
+
+        // Every packet ends with a 0 bit
+        if self.bit_index == PACKET_BITS {
+            self.receiving = false;
+            self.packet_received();
+            return;
+        }
+
+        if select == 0x10 {
+            self.packet[self.bit_index / 8] |= 1 << (self.bit_index % 8);
+        }
+        self.bit_index += 1;
+    }
+
+    fn packet_received(&mut self) {
+        self.command.extend_from_slice(&self.packet);
+
+        // Commands can span up to seven packets, the length is only stored in
+        // the first one
+        let packet_count = (self.command[0] & 0b111).max(1) as usize;
+        if self.command.len() >= packet_count * PACKET_SIZE {
+            let command = std::mem::take(&mut self.command);
+            self.execute(&command);
+        }
+    }
+
+    fn execute(&mut self, data: &[u8]) {
+        match data[0] >> 3 {
+            PAL01 => self.set_palette_pair(0, 1, data),
+            PAL23 => self.set_palette_pair(2, 3, data),
+            PAL03 => self.set_palette_pair(0, 3, data),
+            PAL12 => self.set_palette_pair(1, 2, data),
+            ATTR_BLK => self.attribute_blocks(data),
+            ATTR_LIN => self.attribute_lines(data),
+            ATTR_DIV => self.attribute_divide(data),
+            ATTR_CHR => self.attribute_characters(data),
+            PAL_SET => {
+                for i in 0..4 {
+                    let palette = concat_bytes(data[2 + (i * 2)], data[1 + (i * 2)]) & 0x1FF;
+                    let start = palette as usize * 8;
+                    for color in 0..4 {
+                        self.palettes[i][color] = concat_bytes(
+                            self.system_palettes[start + (color * 2) + 1],
+                            self.system_palettes[start + (color * 2)],
+                        );
+                    }
+                }
+                self.share_color_0(self.palettes[0][0]);
+
+                let flags = data[9];
+                if flags & 0x80 != 0 {
+                    self.apply_attribute_file(flags & 0x3F);
+                }
+                if flags & 0x40 != 0 {
+                    self.mask = MASK_NONE;
+                }
+            }
+            MLT_REQ => {
+                self.players = match data[1] & 0b11 {
+                    1 => 2,
+                    3 => 4,
+                    _ => 1,
+                };
+                self.player = 0;
+            }
+            PAL_TRN | CHR_TRN | PCT_TRN | ATTR_TRN => {
+                self.pending_transfer = Some((data[0] >> 3, data[1]));
+            }
+            ATTR_SET => {
+                self.apply_attribute_file(data[1] & 0x3F);
+                if data[1] & 0x40 != 0 {
+                    self.mask = MASK_NONE;
+                }
+            }
+            MASK_EN => self.mask = data[1] & 0b11,
+            // Sound, SNES programs and the rest are not emulated
+            _ => {}
+        }
+    }
+
+    // Color 0 is shared by all four palettes
+    fn share_color_0(&mut self, color: u16) {
+        for palette in self.palettes.iter_mut() {
+            palette[0] = color;
+        }
+    }
+
+    fn set_palette_pair(&mut self, first: usize, second: usize, data: &[u8]) {
+        self.share_color_0(concat_bytes(data[2], data[1]));
+        for color in 1..4 {
+            let index = 1 + (color * 2);
+            self.palettes[first][color] = concat_bytes(data[index + 1], data[index]);
+            self.palettes[second][color] = concat_bytes(data[index + 7], data[index + 6]);
+        }
+    }
+
+    fn attribute_blocks(&mut self, data: &[u8]) {
+        let count = (data[1] & 0x1F) as usize;
+        for block in data[2..].chunks_exact(6).take(count) {
+            let control = block[0] & 0b111;
+            let inside_palette = block[1] & 0b11;
+            let outside_palette = (block[1] >> 4) & 0b11;
+            // When only the inside or only the outside is changed the border
+            // takes the same palette
+            let (change_border, border_palette) = match control {
+                0b001 => (true, inside_palette),
+                0b100 => (true, outside_palette),
+                _ => (control & 0b010 != 0, (block[1] >> 2) & 0b11),
+            };
+            let (x1, y1) = (block[2] as usize & 0x1F, block[3] as usize & 0x1F);
+            let (x2, y2) = (block[4] as usize & 0x1F, block[5] as usize & 0x1F);
+
+            for y in 0..CELLS_Y {
+                for x in 0..CELLS_X {
+                    let within = x >= x1 && x <= x2 && y >= y1 && y <= y2;
+                    let inside = x > x1 && x < x2 && y > y1 && y < y2;
+                    let palette = if inside {
+                        Some(inside_palette).filter(|_| control & 0b001 != 0)
+                    } else if within {
+                        Some(border_palette).filter(|_| change_border)
+                    } else {
+                        Some(outside_palette).filter(|_| control & 0b100 != 0)
+                    };
+                    if let Some(palette) = palette {
+                        self.attributes[(y * CELLS_X) + x] = palette;
+                    }
+                }
+            }
+        }
+    }
+
+    fn attribute_lines(&mut self, data: &[u8]) {
+        let count = data[1] as usize;
+        for line in data[2..].iter().take(count) {
+            let index = (line & 0x1F) as usize;
+            let palette = (line >> 5) & 0b11;
+            let is_row = line & 0x80 != 0;
+            for i in 0..(if is_row { CELLS_X } else { CELLS_Y }) {
+                let cell = if is_row {
+                    (index * CELLS_X) + i
+                } else {
+                    (i * CELLS_X) + index
+                };
+                if let Some(attribute) = self.attributes.get_mut(cell) {
+                    *attribute = palette;
+                }
+            }
+        }
+    }
+
+    fn attribute_divide(&mut self, data: &[u8]) {
+        let after_palette = data[1] & 0b11;
+        let before_palette = (data[1] >> 2) & 0b11;
+        let line_palette = (data[1] >> 4) & 0b11;
+        let divides_rows = data[1] & 0x40 != 0;
+        let line = (data[2] & 0x1F) as usize;
+
+        for y in 0..CELLS_Y {
+            for x in 0..CELLS_X {
+                let position = if divides_rows { y } else { x };
+                self.attributes[(y * CELLS_X) + x] = if position < line {
+                    before_palette
+                } else if position == line {
+                    line_palette
+                } else {
+                    after_palette
+                };
+            }
+        }
+    }
+
+    fn attribute_characters(&mut self, data: &[u8]) {
+        let mut x = data[1] as usize % CELLS_X;
+        let mut y = data[2] as usize % CELLS_Y;
+        let count = concat_bytes(data[4], data[3]) as usize;
+        let vertical = data[5] & 1 != 0;
+
+        for i in 0..count.min(CELLS_X * CELLS_Y) {
+            let byte = match data.get(6 + (i / 4)) {
+                Some(byte) => byte,
+                None => break,
+            };
+            self.attributes[(y * CELLS_X) + x] = (byte >> (6 - ((i % 4) * 2))) & 0b11;
+
+            if vertical {
+                y += 1;
+                if y == CELLS_Y {
+                    y = 0;
+                    x = (x + 1) % CELLS_X;
+                }
+            } else {
+                x += 1;
+                if x == CELLS_X {
+                    x = 0;
+                    y = (y + 1) % CELLS_Y;
+                }
+            }
+        }
+    }
+
+    fn apply_attribute_file(&mut self, file: u8) {
+        let file = file as usize;
+        if file >= ATTRIBUTE_FILE_COUNT {
+            return;
+        }
+
+        let data = &self.attribute_files[file * ATTRIBUTE_FILE_SIZE..][..ATTRIBUTE_FILE_SIZE];
+        for (i, attribute) in self.attributes.iter_mut().enumerate() {
+            *attribute = (data[i / 4] >> (6 - ((i % 4) * 2))) & 0b11;
+        }
+    }
+
+    /// Called with the shades of every pixel once the LCD finished a frame.
+    /// Finishes pending transfers and updates the picture shown in the border
+    /// unless it is frozen.
+    pub fn frame_finished(&mut self, shades: &[u8]) {
+        if let Some((command, argument)) = self.pending_transfer.take() {
+            let data = read_transfer(shades);
+            match command {
+                PAL_TRN => self.system_palettes.copy_from_slice(&data),
+                CHR_TRN => {
+                    let start = (argument as usize & 1) * TRANSFER_SIZE;
+                    self.border_tiles[start..start + TRANSFER_SIZE].copy_from_slice(&data);
+                }
+                PCT_TRN => self
+                    .border_picture
+                    .copy_from_slice(&data[..BORDER_PICTURE_SIZE]),
+                _ => {
+                    let length = self.attribute_files.len();
+                    self.attribute_files.copy_from_slice(&data[..length]);
+                }
+            }
+        }
+
+        if self.mask != MASK_FREEZE {
+            self.screen.copy_from_slice(shades);
+        }
+    }
+
+    /// Draws the colored LCD picture and the border into a 256x224 frame
+    pub fn render(&self, framebuffer: &mut [u8]) {
+        let backdrop = self.palettes[0][0];
+        for y in 0..SCREEN_HEIGHT as usize {
+            for x in 0..SCREEN_WIDTH as usize {
+                let color = match self.border_color(x, y) {
+                    Some(color) => color,
+                    None => {
+                        let lcd_x = x.wrapping_sub(LCD_X);
+                        let lcd_y = y.wrapping_sub(LCD_Y);
+                        if lcd_x < HORIZONTAL_RES as usize && lcd_y < VERTICAL_RES as usize {
+                            self.lcd_color(lcd_x, lcd_y)
+                        } else {
+                            backdrop
+                        }
+                    }
+                };
+
+                let pixel_index = 4 * ((y * SCREEN_WIDTH as usize) + x);
+                framebuffer[pixel_index] = expand_color_channel(color >> 10);
+                framebuffer[pixel_index + 1] = expand_color_channel(color >> 5);
+                framebuffer[pixel_index + 2] = expand_color_channel(color);
+                framebuffer[pixel_index + 3] = 255;
+            }
+        }
+    }
+
+    fn lcd_color(&self, x: usize, y: usize) -> u16 {
+        match self.mask {
+            MASK_BLACK => 0x0000,
+            MASK_COLOR_0 => self.palettes[0][0],
+            _ => {
+                let attribute = self.attributes[((y / 8) * CELLS_X) + (x / 8)];
+                let shade = self.screen[(y * HORIZONTAL_RES as usize) + x];
+                self.palettes[attribute as usize][shade as usize & 0b11]
+            }
+        }
+    }
+
+    // Border pixels using color 0 are transparent
+    fn border_color(&self, x: usize, y: usize) -> Option<u16> {
+        let map_index = (((y / 8) * 32) + (x / 8)) * 2;
+        let entry = concat_bytes(
+            self.border_picture[map_index + 1],
+            self.border_picture[map_index],
+        );
+        let tile = (entry & 0xFF) as usize;
+        let palette = ((entry >> 10) & 0b11) as usize;
+        let column = if entry & 0x4000 != 0 {
+            x % 8
+        } else {
+            7 - (x % 8)
+        };
+        let row = if entry & 0x8000 != 0 {
+            7 - (y % 8)
+        } else {
+            y % 8
+        };
+
+        // SNES tiles store two bit planes per row, followed by the other two
+        let tile_data = &self.border_tiles[tile * BORDER_TILE_SIZE..][..BORDER_TILE_SIZE];
+        let planes = [
+            tile_data[row * 2],
+            tile_data[(row * 2) + 1],
+            tile_data[16 + (row * 2)],
+            tile_data[16 + (row * 2) + 1],
+        ];
+        let color_index = planes.iter().enumerate().fold(0, |index, (plane, bits)| {
+            index | (((*bits as usize >> column) & 1) << plane)
+        });
+        if color_index == 0 {
+            return None;
+        }
+
+        let color_offset = BORDER_MAP_SIZE + (palette * 32) + (color_index * 2);
+        Some(concat_bytes(
+            self.border_picture[color_offset + 1],
+            self.border_picture[color_offset],
+        ))
+    }
+}
+
+// Transfers read the first 256 tiles shown on screen, going left to right and
+// then top to bottom, as 4KB of 2 bit per pixel tile data
+fn read_transfer(shades: &[u8]) -> Vec<u8> {
+    let tiles_per_row = HORIZONTAL_RES as usize / 8;
+    let mut data = vec![0; TRANSFER_SIZE];
+    for (tile, tile_data) in data.chunks_exact_mut(16).enumerate() {
+        let tile_x = (tile % tiles_per_row) * 8;
+        let tile_y = (tile / tiles_per_row) * 8;
+        for row in 0..8 {
+            for column in 0..8 {
+                let shade = shades[((tile_y + row) * HORIZONTAL_RES as usize) + tile_x + column];
+                let bit = 7 - column;
+                tile_data[row * 2] |= (shade & 1) << bit;
+                tile_data[(row * 2) + 1] |= ((shade >> 1) & 1) << bit;
+            }
+        }
+    }
+    data
+}
+
+// Scales a 5-bit channel to 8 bits so that 0x1F becomes 0xFF
+fn expand_color_channel(channel: u16) -> u8 {
+    let channel = (channel & 0x1F) as u8;
+    (channel << 3) | (channel >> 2)
+}
+
+impl SaveState for SuperGameBoy {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.receiving);
+        writer.write_u8(self.bit_index as u8);
+        writer.write_bytes(&self.packet);
+        // Commands still being received are padded so the state always has
+        // the same size
+        let mut command = [0; MAX_COMMAND_SIZE];
+        command[..self.command.len()].copy_from_slice(&self.command);
+        writer.write_u8(self.command.len() as u8);
+        writer.write_bytes(&command);
+        writer.write_u8(self.last_select);
+        let (command, argument) = self.pending_transfer.unwrap_or((0, 0));
+        writer.write_bool(self.pending_transfer.is_some());
+        writer.write_u8(command);
+        writer.write_u8(argument);
+        for palette in self.palettes.iter() {
+            for color in palette.iter() {
+                writer.write_u16(*color);
+            }
+        }
+        writer.write_bytes(&self.system_palettes);
+        writer.write_bytes(&self.attributes);
+        writer.write_bytes(&self.attribute_files);
+        writer.write_bytes(&self.border_tiles);
+        writer.write_bytes(&self.border_picture);
+        writer.write_u8(self.mask);
+        writer.write_u8(self.players);
+        writer.write_u8(self.player);
+        writer.write_bytes(&self.screen);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.receiving = reader.read_bool()?;
+        self.bit_index = reader.read_u8()? as usize;
+        if self.bit_index > PACKET_BITS {
+            return Err(SaveStateError::InvalidData("SGB packet bit out of range"));
+        }
+        reader.read_bytes_into(&mut self.packet)?;
+        let command_length = reader.read_u8()? as usize;
+        if command_length & (PACKET_SIZE - 1) != 0 || command_length >= MAX_COMMAND_SIZE {
+            return Err(SaveStateError::InvalidData(
+                "SGB command length out of range",
+            ));
+        }
+        let mut command = [0; MAX_COMMAND_SIZE];
+        reader.read_bytes_into(&mut command)?;
+        self.command = command[..command_length].to_vec();
+        self.last_select = reader.read_u8()?;
+        let has_pending_transfer = reader.read_bool()?;
+        let pending_transfer = (reader.read_u8()?, reader.read_u8()?);
+        self.pending_transfer = if has_pending_transfer {
+            Some(pending_transfer)
+        } else {
+            None
+        };
+        for palette in self.palettes.iter_mut() {
+            for color in palette.iter_mut() {
+                *color = reader.read_u16()?;
+            }
+        }
+        reader.read_bytes_into(&mut self.system_palettes)?;
+        reader.read_bytes_into(&mut self.attributes)?;
+        reader.read_bytes_into(&mut self.attribute_files)?;
+        reader.read_bytes_into(&mut self.border_tiles)?;
+        reader.read_bytes_into(&mut self.border_picture)?;
+        self.mask = reader.read_u8()? & 0b11;
+        self.players = reader.read_u8()?;
+        self.player = reader.read_u8()?;
+        if ![1, 2, 4].contains(&self.players) || self.player >= self.players {
+            return Err(SaveStateError::InvalidData("SGB player out of range"));
+        }
+        if self.attributes.iter().any(|attribute| *attribute > 3) {
+            return Err(SaveStateError::InvalidData("SGB palette out of range"));
+        }
+        reader.read_bytes_into(&mut self.screen)
+    }
+}
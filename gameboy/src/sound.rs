@@ -1,18 +1,78 @@
-use crate::{game_boy::GameBoy, memory::Register};
+use crate::{
+    blip_buffer::BlipBuffer,
+    game_boy::GameBoy,
+    memory::Register,
+    save_state::{SaveState, SaveStateError, StateReader, StateWriter},
+    sound_registers::{WaveRamAccess, WAVE_RAM_START},
+};
 
-const CLOCKS_PER_FRAME: u32 = 70_224;
 const FRAME_SEQUENCER_TICK: u64 = 8_192;
-const WAVE_MEMORY_START: u16 = 0xFF30;
-const WAVE_MEMORY_END: u16 = 0xFF40;
+// The DMG lets the CPU access wave RAM for this many cycles after channel 3
+// read a byte
+const WAVE_RAM_ACCESS_CYCLES: u32 = 4;
+
+/// A sample format the sound can be produced in. Samples are interleaved,
+/// left first.
+pub trait AudioSample: Copy {
+    /// Converts from a sample between -1.0 and 1.0
+    fn from_f32(value: f32) -> Self;
+}
+
+impl AudioSample for i16 {
+    fn from_f32(value: f32) -> Self {
+        (value.clamp(-1.0, 1.0) * i16::MAX as f32) as i16
+    }
+}
+
+impl AudioSample for f32 {
+    fn from_f32(value: f32) -> Self {
+        value.clamp(-1.0, 1.0)
+    }
+}
+
+/// One of the four sound channels of the APU
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum AudioChannel {
+    /// The square wave with a frequency sweep
+    One,
+    /// The square wave
+    Two,
+    /// The wave played from wave RAM
+    Three,
+    /// The noise
+    Four,
+}
+
+impl AudioChannel {
+    pub const ALL: [AudioChannel; 4] = [
+        AudioChannel::One,
+        AudioChannel::Two,
+        AudioChannel::Three,
+        AudioChannel::Four,
+    ];
+}
+
+// The output of one channel before it is mixed, kept while capturing
+struct ChannelCapture {
+    buffer: BlipBuffer,
+    samples: Vec<f32>,
+}
 
 pub struct SoundController {
-    total_cycle_count: u32,
-    last_sample_output: i32,
     frame_sequencer: FrameSequencer,
     channel_one: SoundChannel<SquareWave>,
     channel_two: SoundChannel<SquareWave>,
     channel_three: SoundChannel<CustomWave>,
+    channel_four: SoundChannel<Noise>,
+    powered: bool,
+    left: BlipBuffer,
+    right: BlipBuffer,
     frequency: u32,
+    // Settings for listening to single channels, they don't change the
+    // emulated hardware and aren't saved
+    muted: [bool; 4],
+    soloed: [bool; 4],
+    captures: Option<[ChannelCapture; 4]>,
 }
 
 fn channel_1_triggered(gb: &GameBoy) -> bool {
@@ -24,12 +84,13 @@ fn channel_2_triggered(gb: &GameBoy) -> bool {
 fn channel_3_triggered(gb: &GameBoy) -> bool {
     gb.memory.channel_3_triggered()
 }
+fn channel_4_triggered(gb: &GameBoy) -> bool {
+    gb.memory.channel_4_triggered()
+}
 
 impl SoundController {
     pub fn new(frequency: u32) -> Self {
         Self {
-            total_cycle_count: 0,
-            last_sample_output: -1,
             frame_sequencer: FrameSequencer::new(),
             channel_one: SoundChannel::new(
                 SquareWave::new(Register::Channel1LengthDuty),
@@ -55,7 +116,11 @@ impl SoundController {
             ),
 
             channel_three: SoundChannel::new(
-                CustomWave::new(Register::Channel3Length, Register::Channel3VolumeCode),
+                CustomWave::new(
+                    Register::Channel3DacPower,
+                    Register::Channel3Length,
+                    Register::Channel3VolumeCode,
+                ),
                 None,
                 None,
                 Register::Channel3FrequencyLo,
@@ -65,52 +130,212 @@ impl SoundController {
                 0b0100_0000,
                 0b0000_0100,
             ),
+            // The noise channel has no frequency, NR43 sets how fast it runs
+            channel_four: SoundChannel::new(
+                Noise::new(
+                    Register::Channel4Length,
+                    Register::Channel4PolynomialCounter,
+                ),
+                None,
+                Some(Register::Channel4VolumeEnvelope),
+                Register::Channel4PolynomialCounter,
+                Register::Channel4TriggerLength,
+                channel_4_triggered,
+                0b0000_1000,
+                0b1000_0000,
+                0b0000_1000,
+            ),
+            powered: true,
+            left: BlipBuffer::new(frequency),
+            right: BlipBuffer::new(frequency),
             frequency,
+            muted: [false; 4],
+            soloed: [false; 4],
+            captures: None,
         }
     }
-    pub fn update(&mut self, gb: &mut GameBoy, sound_buffer: &mut Vec<u8>, cycles_elapsed: u8) {
-        for _ in 0..cycles_elapsed {
-            let clocks = self.frame_sequencer.update(1);
 
-            self.channel_one.update(gb, clocks, 1);
-            self.channel_two.update(gb, clocks, 1);
-            self.channel_three.update(gb, clocks, 1);
+    /// Leaves a channel out of the mixed output
+    pub fn set_muted(&mut self, channel: AudioChannel, muted: bool) {
+        self.muted[channel as usize] = muted;
+    }
+
+    pub fn is_muted(&self, channel: AudioChannel) -> bool {
+        self.muted[channel as usize]
+    }
+
+    /// Only soloed channels are mixed while any channel is soloed
+    pub fn set_soloed(&mut self, channel: AudioChannel, soloed: bool) {
+        self.soloed[channel as usize] = soloed;
+    }
+
+    pub fn is_soloed(&self, channel: AudioChannel) -> bool {
+        self.soloed[channel as usize]
+    }
 
-            self.total_cycle_count += 1;
-            self.output_sample(gb, sound_buffer);
+    /// Starts or stops keeping the output of every channel before it is
+    /// mixed. Stopping drops the samples that weren't taken.
+    pub fn set_capture_enabled(&mut self, enabled: bool) {
+        if !enabled {
+            self.captures = None;
+        } else if self.captures.is_none() {
+            let frequency = self.frequency;
+            self.captures = Some([(); 4].map(|_| ChannelCapture {
+                buffer: BlipBuffer::new(frequency),
+                samples: Vec::new(),
+            }));
         }
     }
 
-    fn output_sample(&mut self, gb: &GameBoy, sound_buffer: &mut Vec<u8>) {
-        let sampling_rate = CLOCKS_PER_FRAME / (self.frequency / 60);
-        let current_sample = self.total_cycle_count / sampling_rate;
+    /// Returns the mono samples captured from a channel since the last call
+    pub fn take_captured_samples<S: AudioSample>(&mut self, channel: AudioChannel) -> Vec<S> {
+        match self.captures.as_mut() {
+            Some(captures) => captures[channel as usize]
+                .samples
+                .drain(..)
+                .map(S::from_f32)
+                .collect(),
+            None => Vec::new(),
+        }
+    }
 
-        let do_output_new_sample = current_sample as i32 > self.last_sample_output;
+    /// The rate of the sound output in samples per second
+    pub fn frequency(&self) -> u32 {
+        self.frequency
+    }
 
-        if do_output_new_sample {
-            let channel_control = gb.memory.get_register(Register::ChannelControl);
+    pub fn update<S: AudioSample>(
+        &mut self,
+        gb: &mut GameBoy,
+        sound_buffer: &mut Vec<S>,
+        cycles_elapsed: u8,
+    ) {
+        for cycle in 0..cycles_elapsed {
+            // Register writes between updates can change the output on the
+            // first cycle, after that only the channels themselves can
+            let mut changed = cycle == 0;
+            let powered = gb.memory.is_sound_powered();
+            if powered != self.powered {
+                self.powered = powered;
+                if powered {
+                    self.frame_sequencer = FrameSequencer::new();
+                } else {
+                    // The DMG keeps its length counters while powered off
+                    let keep_length = !gb.memory.is_cgb();
+                    self.channel_one.power_off(keep_length);
+                    self.channel_two.power_off(keep_length);
+                    self.channel_three.power_off(keep_length);
+                    self.channel_four.power_off(keep_length);
+                }
+            }
 
-            let left_volume = ((channel_control & 0b0111_0000) >> 4) as i8;
-            let right_volume = (channel_control & 0b0000_0111) as i8;
+            if powered {
+                let clocks = self.frame_sequencer.update(1);
+                changed |= clocks.length || clocks.envelope || clocks.sweep;
 
-            let sample1 = self.channel_one.sample();
-            let sample2 = self.channel_two.sample();
-            let sample3 = self.channel_three.sample();
-            let sample_left =
-                ((sample1.0 as i16 + sample2.0 as i16 + sample3.0 as i16) / 3i16) as i8;
-            let sample_right =
-                ((sample1.1 as i16 + sample2.1 as i16 + sample3.1 as i16) / 3i16) as i8;
-            // let sample_left = sample2.0;
-            // let sample_right = sample2.0;
+                changed |= self.channel_one.update(gb, clocks, 1);
+                changed |= self.channel_two.update(gb, clocks, 1);
+                changed |= self.channel_three.update(gb, clocks, 1);
+                changed |= self.channel_four.update(gb, clocks, 1);
+            }
+            let wave_ram_access = self.channel_three.wave_ram_access(gb);
+            gb.memory.set_wave_ram_access(wave_ram_access);
 
-            let left_sample = ((sample_left as i32 * left_volume as i32 / 8) + 127) as u8;
-            let right_sample = ((sample_right as i32 * right_volume as i32 / 8) + 127) as u8;
+            // Mixing is skipped on the cycles where no level can change
+            if changed {
+                self.mix(gb);
+            }
+            self.left.advance(1);
+            self.right.advance(1);
+            for capture in self.captures.iter_mut().flatten() {
+                capture.buffer.advance(1);
+            }
+        }
 
-            sound_buffer.push(left_sample);
-            sound_buffer.push(right_sample);
-            self.last_sample_output = current_sample as i32;
+        while let (Some(left), Some(right)) = (self.left.read_sample(), self.right.read_sample()) {
+            sound_buffer.push(S::from_f32(left));
+            sound_buffer.push(S::from_f32(right));
+        }
+
+        if let Some(captures) = self.captures.as_mut() {
+            for capture in captures.iter_mut() {
+                while let Some(sample) = capture.buffer.read_sample() {
+                    capture.samples.push(sample);
+                }
+            }
         }
     }
+
+    // Adds up the channels sent to each side through NR51 and scales them by
+    // the master volume in NR50
+    fn mix(&mut self, gb: &GameBoy) {
+        let outputs = [
+            self.channel_one.output(gb),
+            self.channel_two.output(gb),
+            self.channel_three.output(gb),
+            self.channel_four.output(gb),
+        ];
+        let masks = [
+            self.channel_one.output_masks(),
+            self.channel_two.output_masks(),
+            self.channel_three.output_masks(),
+            self.channel_four.output_masks(),
+        ];
+        if let Some(captures) = self.captures.as_mut() {
+            for (capture, output) in captures.iter_mut().zip(outputs.iter()) {
+                capture.buffer.set_level(*output);
+            }
+        }
+
+        let any_soloed = self.soloed.iter().any(|soloed| *soloed);
+        let terminals = gb.memory.get_register(Register::SoundOutputTerminal);
+        let (mut left, mut right) = (0.0, 0.0);
+        for (index, (output, (left_mask, right_mask))) in
+            outputs.iter().zip(masks.iter()).enumerate()
+        {
+            if self.muted[index] || (any_soloed && !self.soloed[index]) {
+                continue;
+            }
+            if terminals & left_mask != 0 {
+                left += output;
+            }
+            if terminals & right_mask != 0 {
+                right += output;
+            }
+        }
+
+        let channel_control = gb.memory.get_register(Register::ChannelControl);
+        let left_volume = ((channel_control & 0b0111_0000) >> 4) + 1;
+        let right_volume = (channel_control & 0b0000_0111) + 1;
+        self.left
+            .set_level(left * left_volume as f32 / (8.0 * outputs.len() as f32));
+        self.right
+            .set_level(right * right_volume as f32 / (8.0 * outputs.len() as f32));
+    }
+}
+
+impl SaveState for SoundController {
+    fn save_state(&self, writer: &mut StateWriter) {
+        self.frame_sequencer.save_state(writer);
+        self.channel_one.save_state(writer);
+        self.channel_two.save_state(writer);
+        self.channel_three.save_state(writer);
+        self.channel_four.save_state(writer);
+        writer.write_bool(self.powered);
+        self.left.save_state(writer);
+        self.right.save_state(writer);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.frame_sequencer.load_state(reader)?;
+        self.channel_one.load_state(reader)?;
+        self.channel_two.load_state(reader)?;
+        self.channel_three.load_state(reader)?;
+        self.channel_four.load_state(reader)?;
+        self.powered = reader.read_bool()?;
+        self.left.load_state(reader)?;
+        self.right.load_state(reader)
+    }
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -157,6 +382,19 @@ impl FrameSequencer {
     }
 }
 
+impl SaveState for FrameSequencer {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u64(self.total_cycle_count);
+        writer.write_u32(self.frame_sequencer_clock);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.total_cycle_count = reader.read_u64()?;
+        self.frame_sequencer_clock = reader.read_u32()?;
+        Ok(())
+    }
+}
+
 struct SoundChannel<T: SoundChannelType> {
     frequency_timer: u32,
     period_timer: u8,
@@ -173,9 +411,6 @@ struct SoundChannel<T: SoundChannelType> {
     frequency_lo_register: Register,
     frequency_hi_register: Register,
     check_trigger_event: fn(&GameBoy) -> bool,
-    left_accumulator: i32,
-    right_accumulator: i32,
-    samples_accumulated: u32,
     channel_enable_mask: u8,
     left_output_mask: u8,
     right_output_mask: u8,
@@ -199,7 +434,7 @@ impl<T: SoundChannelType> SoundChannel<T> {
             length_timer: 0,
             prev_length_counter: 0,
             envelope_volume: 0,
-            disabled: false,
+            disabled: true,
             sweep_enabled: false,
             shadow_freqency: 0,
             sweep_timer: 0,
@@ -209,22 +444,27 @@ impl<T: SoundChannelType> SoundChannel<T> {
             frequency_lo_register,
             frequency_hi_register,
             check_trigger_event,
-            left_accumulator: 0,
-            right_accumulator: 0,
-            samples_accumulated: 0,
             channel_enable_mask,
             left_output_mask,
             right_output_mask,
         }
     }
 
-    fn update(&mut self, gb: &mut GameBoy, clocks: FrameSequencerClocks, cycles_elapsed: u8) {
+    // Returns whether the waveform moved on to its next step
+    fn update(
+        &mut self,
+        gb: &mut GameBoy,
+        clocks: FrameSequencerClocks,
+        cycles_elapsed: u8,
+    ) -> bool {
+        let mut stepped = false;
         for _ in 0..cycles_elapsed {
             if self.frequency_timer == 0 {
                 let timer = self.get_frequency(gb);
 
-                self.frequency_timer = self.channel_type.reload_frequency_counter(timer);
+                self.frequency_timer = self.channel_type.reload_frequency_counter(gb, timer);
                 self.channel_type.cycle(gb);
+                stepped = true;
             }
             self.frequency_timer -= 1;
         }
@@ -246,7 +486,7 @@ impl<T: SoundChannelType> SoundChannel<T> {
                 self.period_timer = envelope_values.initial_period;
             }
 
-            self.disabled = false;
+            self.disabled = !self.dac_enabled(gb);
             if self.length_timer == 0 {
                 self.length_timer = self.channel_type.new_length_timer(0);
             }
@@ -262,6 +502,11 @@ impl<T: SoundChannelType> SoundChannel<T> {
             }
         }
 
+        // Turning off the DAC silences the channel until it is triggered again
+        if !self.dac_enabled(gb) {
+            self.disabled = true;
+        }
+
         // Tick length function
         if clocks.length
             && gb.memory.get_register(self.frequency_hi_register) & 0b0100_0000 != 0
@@ -327,31 +572,40 @@ impl<T: SoundChannelType> SoundChannel<T> {
             };
             gb.memory.set_register(Register::SoundEnable, value);
         }
+        stepped
+    }
 
-        self.samples_accumulated += cycles_elapsed as u32;
-        let master_disable = gb.memory.get_register(Register::SoundEnable) & 0b1000_0000 == 0;
-        if self.disabled || master_disable {
-            self.left_accumulator = 0;
-            self.right_accumulator = 0;
-            self.samples_accumulated = 1;
-            return;
-        } else {
-            let channel_control = gb.memory.get_register(Register::ChannelControl);
-            let channel_volume = (channel_control & 0b0111_0000) >> 4;
+    // The level coming out of the DAC, between -1.0 and 1.0
+    fn output(&self, gb: &GameBoy) -> f32 {
+        if self.disabled || !self.dac_enabled(gb) {
+            return 0.0;
+        }
+        let sample = self.channel_type.sample(gb, self.envelope_volume);
+        sample as f32 / 7.5 - 1.0
+    }
 
-            self.samples_accumulated = 1;
-            let sample = self
-                .channel_type
-                .sample(gb, channel_volume, self.envelope_volume);
-            let terminals = gb.memory.get_register(Register::SoundOutputTerminal);
+    // The NR51 bits that send the channel to the left and right side
+    fn output_masks(&self) -> (u8, u8) {
+        (self.left_output_mask, self.right_output_mask)
+    }
 
-            if terminals & self.left_output_mask > 0 {
-                self.left_accumulator = sample as i32;
-            }
+    fn dac_enabled(&self, gb: &GameBoy) -> bool {
+        match self.volume_envelope_register {
+            // The DAC is off when both the volume and direction are 0
+            Some(register) => gb.memory.get_register(register) & 0b1111_1000 != 0,
+            None => self.channel_type.dac_enabled(gb),
+        }
+    }
 
-            if terminals & self.right_output_mask > 0 {
-                self.right_accumulator = sample as i32;
-            }
+    fn power_off(&mut self, keep_length: bool) {
+        self.disabled = true;
+        self.envelope_volume = 0;
+        self.sweep_enabled = false;
+        // Powering off clears the registers, which must not look like a new
+        // length was written
+        self.prev_length_counter = 0;
+        if !keep_length {
+            self.length_timer = 0;
         }
     }
 
@@ -375,19 +629,6 @@ impl<T: SoundChannelType> SoundChannel<T> {
         return new_frequency;
     }
 
-    fn sample(&mut self) -> (i8, i8) {
-        let left_sample = self.left_accumulator / self.samples_accumulated as i32;
-        let right_sample = self.right_accumulator / self.samples_accumulated as i32;
-        self.left_accumulator = 0;
-        self.right_accumulator = 0;
-        self.samples_accumulated = 0;
-
-        check_sample_bounds(left_sample, "Left");
-        check_sample_bounds(right_sample, "Right");
-
-        return (left_sample as i8, right_sample as i8);
-    }
-
     fn get_frequency(&self, gb: &mut GameBoy) -> u32 {
         let timer_hi = gb.memory.get_register(self.frequency_hi_register);
         let timer_lo = gb.memory.get_register(self.frequency_lo_register);
@@ -419,6 +660,55 @@ impl<T: SoundChannelType> SoundChannel<T> {
     }
 }
 
+impl SoundChannel<CustomWave> {
+    fn wave_ram_access(&self, gb: &mut GameBoy) -> WaveRamAccess {
+        if self.disabled {
+            return WaveRamAccess::Free;
+        }
+
+        let index = self.channel_type.position_counter / 2;
+        if gb.memory.is_cgb() {
+            return WaveRamAccess::Byte(index);
+        }
+
+        let timer = self.get_frequency(gb);
+        let reload = self.channel_type.reload_frequency_counter(gb, timer);
+        if reload.saturating_sub(self.frequency_timer + 1) < WAVE_RAM_ACCESS_CYCLES {
+            WaveRamAccess::Byte(index)
+        } else {
+            WaveRamAccess::Blocked
+        }
+    }
+}
+
+impl<T: SoundChannelType> SaveState for SoundChannel<T> {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u32(self.frequency_timer);
+        writer.write_u8(self.period_timer);
+        writer.write_u32(self.length_timer);
+        writer.write_u32(self.prev_length_counter);
+        writer.write_u8(self.envelope_volume);
+        writer.write_bool(self.disabled);
+        writer.write_bool(self.sweep_enabled);
+        writer.write_u32(self.shadow_freqency);
+        writer.write_u8(self.sweep_timer);
+        self.channel_type.save_state(writer);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.frequency_timer = reader.read_u32()?;
+        self.period_timer = reader.read_u8()?;
+        self.length_timer = reader.read_u32()?;
+        self.prev_length_counter = reader.read_u32()?;
+        self.envelope_volume = reader.read_u8()?;
+        self.disabled = reader.read_bool()?;
+        self.sweep_enabled = reader.read_bool()?;
+        self.shadow_freqency = reader.read_u32()?;
+        self.sweep_timer = reader.read_u8()?;
+        self.channel_type.load_state(reader)
+    }
+}
+
 #[derive(Copy, Clone, Debug)]
 struct SweepValues {
     period: u8,
@@ -456,22 +746,17 @@ impl EnvelopeValues {
     }
 }
 
-fn check_sample_bounds(sample: i32, channel: &'static str) {
-    if !(sample <= i8::MAX as i32 && sample >= i8::MIN as i32) {
-        println!("oops ({}) {}", channel, sample);
-    }
-
-    assert!(sample <= i8::MAX as i32 && sample >= i8::MIN as i32);
-}
-
-trait SoundChannelType {
+trait SoundChannelType: SaveState {
     fn cycle(&mut self, gb: &GameBoy);
-    fn reload_frequency_counter(&self, timer: u32) -> u32;
-    // Channel volume will be between 0-7 while envelope volume will be between 0-15
-    fn sample(&self, gb: &GameBoy, channel_volume: u8, envelope_volume: u8) -> i8;
+    fn reload_frequency_counter(&self, gb: &GameBoy, timer: u32) -> u32;
+    // The digital output from 0-15, envelope volume will be between 0-15
+    fn sample(&self, gb: &GameBoy, envelope_volume: u8) -> u8;
     fn length_counter(&self, gb: &GameBoy) -> u32;
     fn new_length_timer(&self, length: u32) -> u32;
     fn trigger_event(&mut self);
+    fn dac_enabled(&self, _: &GameBoy) -> bool {
+        true
+    }
 }
 
 const DUTY_PATTERNS: [u8; 4] = [0b0000_0001, 0b0000_0011, 0b0000_1111, 0b1111_1100];
@@ -496,22 +781,16 @@ impl SoundChannelType for SquareWave {
             self.duty_position = 0;
         }
     }
-    fn reload_frequency_counter(&self, timer: u32) -> u32 {
+    fn reload_frequency_counter(&self, _: &GameBoy, timer: u32) -> u32 {
         (2048 - timer) * 4
     }
-    fn sample(&self, gb: &GameBoy, channel_volume: u8, envelope_volume: u8) -> i8 {
+    fn sample(&self, gb: &GameBoy, envelope_volume: u8) -> u8 {
         let duty_length = gb.memory.get_register(self.length_duty_register);
         let duty = (0b1100_0000 & duty_length) >> 6;
 
         let pattern = DUTY_PATTERNS[duty as usize];
         let amplitude = (pattern >> self.duty_position) & 0b0000_0001;
-
-        let volume = (channel_volume * envelope_volume) as i8;
-        if amplitude > 0 {
-            volume
-        } else {
-            -volume
-        }
+        amplitude * envelope_volume
     }
     fn length_counter(&self, gb: &GameBoy) -> u32 {
         let length_register = gb.memory.get_register(self.length_duty_register);
@@ -525,7 +804,24 @@ impl SoundChannelType for SquareWave {
     fn trigger_event(&mut self) {}
 }
 
+impl SaveState for SquareWave {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u32(self.duty_position);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.duty_position = reader.read_u32()?;
+        if self.duty_position > 7 {
+            return Err(SaveStateError::InvalidData(
+                "square wave duty position out of range",
+            ));
+        }
+        Ok(())
+    }
+}
+
 struct CustomWave {
+    dac_register: Register,
     length_register: Register,
     volume_shift_register: Register,
     position_counter: u8,
@@ -533,8 +829,13 @@ struct CustomWave {
 }
 
 impl CustomWave {
-    fn new(length_register: Register, volume_shift_register: Register) -> Self {
+    fn new(
+        dac_register: Register,
+        length_register: Register,
+        volume_shift_register: Register,
+    ) -> Self {
         Self {
+            dac_register,
             length_register,
             volume_shift_register,
             position_counter: 0,
@@ -550,17 +851,17 @@ impl SoundChannelType for CustomWave {
             self.position_counter = 0;
         }
         let offset = self.position_counter as u16 / 2;
-        let data = gb.memory.get_byte(WAVE_MEMORY_START + offset);
+        let data = gb.memory.get_unchecked(WAVE_RAM_START + offset);
         self.sample_buffer = if self.position_counter % 2 == 0 {
             (data & 0xF0) >> 4
         } else {
             data & 0x0F
         };
     }
-    fn reload_frequency_counter(&self, timer: u32) -> u32 {
+    fn reload_frequency_counter(&self, _: &GameBoy, timer: u32) -> u32 {
         (2048 - timer) * 2
     }
-    fn sample(&self, gb: &GameBoy, channel_volume: u8, _: u8) -> i8 {
+    fn sample(&self, gb: &GameBoy, _: u8) -> u8 {
         let value = gb.memory.get_register(self.volume_shift_register);
         let volume_code = (value & 0b0110_0000) >> 5;
 
@@ -570,16 +871,7 @@ impl SoundChannelType for CustomWave {
             3 => 2, // 25%
             _ => 4, // mute (0)
         };
-
-        let (sample_magnitude, sample_modifier) = if self.sample_buffer >= 7 {
-            (self.sample_buffer - 7, 1)
-        } else {
-            (7 - self.sample_buffer, -1)
-        };
-
-        // Sample magnitude can be up to 8
-        let volume = (sample_magnitude >> volume_shift) * channel_volume * 2;
-        sample_modifier * (volume as i8)
+        self.sample_buffer >> volume_shift
     }
     fn length_counter(&self, gb: &GameBoy) -> u32 {
         let length_register = gb.memory.get_register(self.length_register);
@@ -591,4 +883,89 @@ impl SoundChannelType for CustomWave {
     fn trigger_event(&mut self) {
         self.position_counter = 0;
     }
+    fn dac_enabled(&self, gb: &GameBoy) -> bool {
+        gb.memory.get_register(self.dac_register) & 0b1000_0000 != 0
+    }
+}
+
+impl SaveState for CustomWave {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.position_counter);
+        writer.write_u8(self.sample_buffer);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.position_counter = reader.read_u8()?;
+        self.sample_buffer = reader.read_u8()?;
+        Ok(())
+    }
+}
+
+// Noise comes from a 15-bit linear feedback shift register
+const LFSR_INITIAL: u16 = 0x7FFF;
+const LFSR_SHORT_MODE: u8 = 0b0000_1000;
+
+struct Noise {
+    length_register: Register,
+    polynomial_register: Register,
+    lfsr: u16,
+}
+
+impl Noise {
+    fn new(length_register: Register, polynomial_register: Register) -> Self {
+        Self {
+            length_register,
+            polynomial_register,
+            lfsr: LFSR_INITIAL,
+        }
+    }
+}
+
+impl SoundChannelType for Noise {
+    fn cycle(&mut self, gb: &GameBoy) {
+        let feedback = (self.lfsr ^ (self.lfsr >> 1)) & 1;
+        self.lfsr = (self.lfsr >> 1) | (feedback << 14);
+
+        // The 7-bit mode also feeds bit 6, which repeats a shorter pattern
+        if gb.memory.get_register(self.polynomial_register) & LFSR_SHORT_MODE != 0 {
+            self.lfsr = (self.lfsr & !(1 << 6)) | (feedback << 6);
+        }
+    }
+    fn reload_frequency_counter(&self, gb: &GameBoy, _: u32) -> u32 {
+        let polynomial = gb.memory.get_register(self.polynomial_register);
+        let divisor = match polynomial & 0b0000_0111 {
+            0 => 8,
+            code => code as u32 * 16,
+        };
+        let shift = (polynomial & 0b1111_0000) >> 4;
+        divisor << shift
+    }
+    fn sample(&self, _: &GameBoy, envelope_volume: u8) -> u8 {
+        if self.lfsr & 1 == 0 {
+            envelope_volume
+        } else {
+            0
+        }
+    }
+    fn length_counter(&self, gb: &GameBoy) -> u32 {
+        let length_register = gb.memory.get_register(self.length_register);
+        (length_register & 0b0011_1111) as u32
+    }
+    fn new_length_timer(&self, length: u32) -> u32 {
+        64 - length
+    }
+    fn trigger_event(&mut self) {
+        self.lfsr = LFSR_INITIAL;
+    }
+}
+
+impl SaveState for Noise {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u16(self.lfsr);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.lfsr = reader.read_u16()? & LFSR_INITIAL;
+        Ok(())
+    }
 }
@@ -0,0 +1,79 @@
+use crate::memory::Register;
+
+pub const SOUND_REGISTERS_START: u16 = 0xFF10;
+pub const WAVE_RAM_START: u16 = 0xFF30;
+pub const WAVE_RAM_END: u16 = 0xFF40;
+
+pub const SOUND_POWER: u8 = 0b1000_0000;
+
+// Bits of NR10-NR52 and the unused addresses up to wave RAM that always read
+// as 1, either because they are write only or because they don't exist
+const READ_MASKS: [u8; (WAVE_RAM_START - SOUND_REGISTERS_START) as usize] = [
+    0x80, 0x3F, 0x00, 0xFF, 0xBF, // NR10-NR14
+    0xFF, 0x3F, 0x00, 0xFF, 0xBF, // NR20-NR24
+    0x7F, 0xFF, 0x9F, 0xFF, 0xBF, // NR30-NR34
+    0xFF, 0xFF, 0x00, 0x00, 0xBF, // NR40-NR44
+    0x00, 0x00, 0x70, // NR50-NR52
+    0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+];
+
+/// How the CPU sees wave RAM. While channel 3 plays, the CGB redirects every
+/// access to the byte the channel is reading, the DMG only allows access
+/// right when the channel reads a byte.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum WaveRamAccess {
+    Free,
+    Byte(u8),
+    Blocked,
+}
+
+impl WaveRamAccess {
+    pub fn to_u8(self) -> u8 {
+        match self {
+            WaveRamAccess::Free => 0xFF,
+            WaveRamAccess::Byte(index) => index,
+            WaveRamAccess::Blocked => 0xFE,
+        }
+    }
+
+    pub fn from_u8(value: u8) -> Option<WaveRamAccess> {
+        match value {
+            0xFF => Some(WaveRamAccess::Free),
+            0xFE => Some(WaveRamAccess::Blocked),
+            index if index < 0x10 => Some(WaveRamAccess::Byte(index)),
+            _ => None,
+        }
+    }
+}
+
+/// Whether an address belongs to the sound registers NR10-NR52, including the
+/// unused ones in between but not wave RAM
+pub fn is_sound_register(address: u16) -> bool {
+    (SOUND_REGISTERS_START..WAVE_RAM_START).contains(&address)
+}
+
+/// The bits of a sound register that read as 1 whatever was written
+pub fn read_mask(address: u16) -> u8 {
+    READ_MASKS[(address - SOUND_REGISTERS_START) as usize]
+}
+
+/// The value a write stores while the APU is powered off, or `None` if the
+/// register is locked. Only the length counters of the DMG can be written.
+pub fn write_while_off(address: u16, value: u8, is_cgb: bool) -> Option<u8> {
+    if is_cgb {
+        return None;
+    }
+
+    if address == Register::Channel1LengthDuty as u16
+        || address == Register::Channel2LengthDuty as u16
+    {
+        // The duty shares the register and stays cleared
+        Some(value & 0b0011_1111)
+    } else if address == Register::Channel3Length as u16
+        || address == Register::Channel4Length as u16
+    {
+        Some(value)
+    } else {
+        None
+    }
+}
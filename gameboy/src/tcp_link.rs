@@ -0,0 +1,164 @@
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, TcpListener, TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use crate::serial::SerialDevice;
+
+// Every message is a kind followed by the byte that was shifted out
+const TRANSFER: u8 = 0x01;
+const REPLY: u8 = 0x02;
+
+// How long the clocking side waits for the other process before giving up on
+// a transfer, so a stalled peer can't hang the emulator
+const REPLY_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// A link cable to a Game Boy running in another process, connected through
+/// TCP. The side that clocks a transfer sends its byte and waits for the
+/// other side to answer with the byte in its SB register, so both sides see
+/// every transfer exactly once. A side that is not ready for a transfer
+/// answers with 0xFF like an unconnected port.
+pub struct TcpLink {
+    stream: Option<TcpStream>,
+    // Replies to transfers that timed out, dropped when they arrive
+    stale_replies: u32,
+}
+
+impl TcpLink {
+    /// Waits on localhost for the other emulator to connect
+    pub fn host(port: u16) -> io::Result<TcpLink> {
+        TcpLink::listen(port)?.accept()
+    }
+
+    /// Opens the port on localhost without waiting for a connection yet.
+    /// Port 0 picks a free port, see `TcpLinkListener::port`.
+    pub fn listen(port: u16) -> io::Result<TcpLinkListener> {
+        Ok(TcpLinkListener {
+            listener: TcpListener::bind((Ipv4Addr::LOCALHOST, port))?,
+        })
+    }
+
+    /// Connects to an emulator waiting in `host`
+    pub fn connect<A: ToSocketAddrs>(address: A) -> io::Result<TcpLink> {
+        TcpLink::new(TcpStream::connect(address)?)
+    }
+
+    fn new(stream: TcpStream) -> io::Result<TcpLink> {
+        stream.set_nodelay(true)?;
+        Ok(TcpLink {
+            stream: Some(stream),
+            stale_replies: 0,
+        })
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.stream.is_some()
+    }
+
+    fn send(&mut self, kind: u8, value: u8) {
+        let result = match self.stream.as_mut() {
+            Some(stream) => stream.write_all(&[kind, value]),
+            None => return,
+        };
+        if result.is_err() {
+            self.disconnect();
+        }
+    }
+
+    // Reads the next message, returns `None` when nothing arrived in time
+    fn receive(&mut self, timeout: Option<Duration>) -> Option<(u8, u8)> {
+        let stream = self.stream.as_mut()?;
+        let configured = match timeout {
+            Some(timeout) => stream
+                .set_nonblocking(false)
+                .and_then(|_| stream.set_read_timeout(Some(timeout))),
+            None => stream.set_nonblocking(true),
+        };
+        if configured.is_err() {
+            self.disconnect();
+            return None;
+        }
+
+        let mut message = [0; 2];
+        let result = match stream.read(&mut message[..1]) {
+            Ok(0) => Err(io::ErrorKind::UnexpectedEof.into()),
+            // The second byte is sent together with the first
+            Ok(_) => stream
+                .set_nonblocking(false)
+                .and_then(|_| stream.set_read_timeout(None))
+                .and_then(|_| stream.read_exact(&mut message[1..])),
+            Err(error) => Err(error),
+        };
+
+        match result {
+            Ok(()) => Some((message[0], message[1])),
+            Err(error)
+                if error.kind() == io::ErrorKind::WouldBlock
+                    || error.kind() == io::ErrorKind::TimedOut =>
+            {
+                None
+            }
+            Err(_) => {
+                self.disconnect();
+                None
+            }
+        }
+    }
+
+    fn disconnect(&mut self) {
+        if self.stream.take().is_some() {
+            eprintln!("Link cable disconnected");
+        }
+    }
+}
+
+/// The hosting side of a link cable that has not been connected yet
+pub struct TcpLinkListener {
+    listener: TcpListener,
+}
+
+impl TcpLinkListener {
+    /// The port the other emulator has to connect to
+    pub fn port(&self) -> io::Result<u16> {
+        Ok(self.listener.local_addr()?.port())
+    }
+
+    /// Waits for the other emulator to connect
+    pub fn accept(self) -> io::Result<TcpLink> {
+        let (stream, _) = self.listener.accept()?;
+        TcpLink::new(stream)
+    }
+}
+
+impl SerialDevice for TcpLink {
+    fn transfer(&mut self, outgoing: u8) -> u8 {
+        self.send(TRANSFER, outgoing);
+        while self.is_connected() {
+            match self.receive(Some(REPLY_TIMEOUT)) {
+                Some((REPLY, _)) if self.stale_replies > 0 => self.stale_replies -= 1,
+                Some((REPLY, incoming)) => return incoming,
+                // Both sides clocked a transfer at the same time, each one
+                // takes the byte of the other
+                Some((TRANSFER, incoming)) => return incoming,
+                Some(_) => {}
+                None => {
+                    self.stale_replies += 1;
+                    break;
+                }
+            }
+        }
+        0xFF
+    }
+
+    fn poll_external_transfer(&mut self, outgoing: Option<u8>) -> Option<u8> {
+        loop {
+            match self.receive(None)? {
+                (REPLY, _) => self.stale_replies = self.stale_replies.saturating_sub(1),
+                (TRANSFER, incoming) => {
+                    self.send(REPLY, outgoing.unwrap_or(0xFF));
+                    return Some(incoming);
+                }
+                _ => {}
+            }
+        }
+    }
+}
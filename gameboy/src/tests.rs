@@ -1,13 +1,52 @@
 #[allow(unused_imports)]
+use crate::blip_buffer::BlipBuffer;
+#[allow(unused_imports)]
+use crate::cartridge::{Cartridge, LoadError};
+#[allow(unused_imports)]
+use crate::cartridge_info::{
+    compute_global_checksum, compute_header_checksum, parse_header, CartridgeType, CgbSupport,
+    Licensee, Mapper, NINTENDO_LOGO, NINTENDO_LOGO_START,
+};
+#[allow(unused_imports)]
 use crate::cb_instructions;
 #[allow(unused_imports)]
+use crate::compatibility_palette::{title_palette, CompatibilityPalette, PaletteShortcut};
+#[allow(unused_imports)]
 use crate::cpu::InstructionSet;
 #[allow(unused_imports)]
 use crate::game_boy::GameBoy;
 #[allow(unused_imports)]
 use crate::instructions;
 #[allow(unused_imports)]
+use crate::linked_systems::LinkedSystems;
+#[allow(unused_imports)]
+use crate::mbc1::MemoryBankController1;
+#[allow(unused_imports)]
+use crate::mbc2::MemoryBankController2;
+#[allow(unused_imports)]
+use crate::mbc3::MemoryBankController3;
+#[allow(unused_imports)]
+use crate::mbc5::MemoryBankController5;
+#[allow(unused_imports)]
+use crate::memory::Register;
+#[allow(unused_imports)]
+use crate::printer::Printer;
+#[allow(unused_imports)]
+use crate::save_state::{SaveState, SaveStateError, StateReader, StateWriter};
+#[allow(unused_imports)]
+use crate::serial::SerialDevice;
+#[allow(unused_imports)]
+use crate::sgb::SuperGameBoy;
+#[allow(unused_imports)]
+use crate::sound::AudioChannel;
+#[allow(unused_imports)]
+use crate::tcp_link::TcpLink;
+#[allow(unused_imports)]
 use crate::util;
+#[allow(unused_imports)]
+use crate::wav::{AudioRecorder, WavWriter};
+#[allow(unused_imports)]
+use crate::{InitializationOptions, System, SystemEvent};
 
 #[test]
 fn test() {
@@ -96,3 +135,1159 @@ fn adding_usign_and_sign() {
 
     assert_eq!(0xCBAE, gb.cpu.pc);
 }
+
+#[allow(dead_code)]
+fn create_test_rom(cartridge_type: u8, rom_size: u8, ram_size: u8) -> Vec<u8> {
+    let mut rom = vec![0; 0x8000 << rom_size];
+    rom[0x147] = cartridge_type;
+    rom[0x148] = rom_size;
+    rom[0x149] = ram_size;
+    rom
+}
+
+#[allow(dead_code)]
+fn test_options(rom: &[u8]) -> InitializationOptions<'_> {
+    InitializationOptions {
+        boot_rom: None,
+        game_rom: rom,
+        external_ram: None,
+        cartridge: None,
+        cgb_compatibility: false,
+        super_game_boy: false,
+        debug_mode: false,
+        sound_frequency: 48000,
+    }
+}
+
+#[allow(dead_code)]
+fn create_test_system(rom: &[u8]) -> System {
+    System::new(test_options(rom)).unwrap()
+}
+
+#[allow(dead_code)]
+fn run_frames(system: &mut System, frames: u32) -> (Vec<u8>, Vec<i16>) {
+    let mut framebuffer = vec![0; 160 * 144 * 4];
+    let mut sound_buffer = Vec::new();
+    for _ in 0..frames {
+        system.run_single_frame(&[], &mut framebuffer, &mut sound_buffer);
+    }
+    (framebuffer, sound_buffer)
+}
+
+#[test]
+fn save_state_round_trip() {
+    let rom = create_test_rom(0x03, 1, 3);
+    let mut system = create_test_system(&rom);
+    run_frames(&mut system, 3);
+
+    let state = system.save_state();
+    let expected = run_frames(&mut system, 2);
+    let expected_state = system.save_state();
+
+    system.load_state(&state).unwrap();
+    assert_eq!(expected, run_frames(&mut system, 2));
+    assert_eq!(expected_state, system.save_state());
+}
+
+#[test]
+fn save_state_rejects_invalid_data() {
+    let rom = create_test_rom(0x00, 0, 0);
+    let mut system = create_test_system(&rom);
+    run_frames(&mut system, 1);
+    let state = system.save_state();
+
+    let mut bad_magic = state.clone();
+    bad_magic[0] = b'X';
+    assert_eq!(
+        system.load_state(&bad_magic),
+        Err(SaveStateError::InvalidHeader)
+    );
+
+    let mut bad_version = state.clone();
+    bad_version[4] = 0xFF;
+    assert!(matches!(
+        system.load_state(&bad_version),
+        Err(SaveStateError::UnsupportedVersion(_))
+    ));
+
+    assert_eq!(
+        system.load_state(&state[..state.len() - 1]),
+        Err(SaveStateError::UnexpectedEnd)
+    );
+    assert_eq!(state, system.save_state());
+
+    let mbc1_rom = create_test_rom(0x01, 1, 0);
+    let mut mbc1_system = create_test_system(&mbc1_rom);
+    assert!(mbc1_system.load_state(&state).is_err());
+}
+
+#[test]
+fn external_ram_view_is_backed_by_cartridge() {
+    let rom = create_test_rom(0x03, 1, 3);
+    let mut system = create_test_system(&rom);
+
+    let ram = system.external_ram_mut().unwrap();
+    assert_eq!(ram.len(), 0x8000);
+    ram[0x2001] = 0x42;
+
+    assert_eq!(system.copy_external_ram_banks().unwrap()[0x2001], 0x42);
+
+    let no_battery_rom = create_test_rom(0x01, 1, 3);
+    let mut no_battery_system = create_test_system(&no_battery_rom);
+    assert!(no_battery_system.external_ram_mut().is_none());
+}
+
+#[test]
+fn mbc3_rtc_latch_and_persistence() {
+    let rom = create_test_rom(0x10, 1, 3);
+    let mut mbc3 = MemoryBankController3::new();
+    mbc3.initialize(&rom, &parse_header(&rom).unwrap()).unwrap();
+
+    mbc3.write_register(0x0000, 0x0A);
+    mbc3.write_register(0x4000, 0x08);
+    mbc3.write_ram(0xA000, 58);
+    for _ in 0..(2 * 4_194_304 / 4) {
+        mbc3.tick(4);
+    }
+
+    // Registers only change once the clock is latched
+    assert_eq!(mbc3.read_ram(0xA000), 58);
+    mbc3.write_register(0x6000, 0x00);
+    mbc3.write_register(0x6000, 0x01);
+    assert_eq!(mbc3.read_ram(0xA000), 0);
+    mbc3.write_register(0x4000, 0x09);
+    assert_eq!(mbc3.read_ram(0xA000), 1);
+
+    // Halting the clock stops it from advancing
+    mbc3.write_register(0x4000, 0x0C);
+    mbc3.write_ram(0xA000, 0b0100_0000);
+    for _ in 0..(4_194_304 / 4) {
+        mbc3.tick(4);
+    }
+    mbc3.write_register(0x6000, 0x00);
+    mbc3.write_register(0x6000, 0x01);
+    mbc3.write_register(0x4000, 0x08);
+    assert_eq!(mbc3.read_ram(0xA000), 0);
+
+    mbc3.write_register(0x4000, 0x01);
+    mbc3.write_ram(0xA010, 0x55);
+    let battery_data = mbc3.export_battery_data();
+    assert_eq!(battery_data.len(), 0x8000 + 48);
+    // The clock on its own is the block that follows the RAM
+    let rtc_data = mbc3.export_rtc_data().unwrap();
+    assert_eq!(rtc_data[..40], battery_data[0x8000..0x8000 + 40]);
+
+    let mut restored = MemoryBankController3::new();
+    restored
+        .initialize(&rom, &parse_header(&rom).unwrap())
+        .unwrap();
+    restored.import_battery_data(&battery_data).unwrap();
+    restored.write_register(0x0000, 0x0A);
+    restored.write_register(0x4000, 0x01);
+    assert_eq!(restored.read_ram(0xA010), 0x55);
+    restored.write_register(0x4000, 0x09);
+    assert_eq!(restored.read_ram(0xA000), 1);
+
+    // RAM and clock are inaccessible until enabled
+    restored.write_register(0x0000, 0x00);
+    assert_eq!(restored.read_ram(0xA000), 0xFF);
+}
+
+#[test]
+fn mbc5_rom_banking_and_rumble() {
+    let mut rom = create_test_rom(0x1E, 8, 3);
+    for bank in 0..512 {
+        rom[bank * 0x4000] = (bank & 0xFF) as u8;
+        rom[bank * 0x4000 + 1] = (bank >> 8) as u8;
+    }
+    let mut mbc5 = MemoryBankController5::new();
+    mbc5.initialize(&rom, &parse_header(&rom).unwrap()).unwrap();
+
+    mbc5.write_register(0x2000, 0x00);
+    assert_eq!(mbc5.read_rom(0x4000), 0);
+    mbc5.write_register(0x2000, 0x34);
+    mbc5.write_register(0x3000, 0x01);
+    assert_eq!(mbc5.read_rom(0x4000), 0x34);
+    assert_eq!(mbc5.read_rom(0x4001), 0x01);
+
+    mbc5.write_register(0x4000, 0b0000_1010);
+    assert!(mbc5.rumble_active());
+    mbc5.write_register(0x0000, 0x0A);
+    mbc5.write_ram(0xA000, 0x77);
+    assert_eq!(mbc5.external_ram()[2 * 0x2000], 0x77);
+    mbc5.write_register(0x4000, 0b0000_0010);
+    assert!(!mbc5.rumble_active());
+}
+
+#[test]
+fn mbc2_register_decoding_and_half_byte_ram() {
+    let mut rom = create_test_rom(0x06, 3, 0);
+    rom[3 * 0x4000] = 0x33;
+    let mut mbc2 = MemoryBankController2::new();
+    mbc2.initialize(&rom, &parse_header(&rom).unwrap()).unwrap();
+
+    // Address bit 8 set selects the ROM bank register
+    mbc2.write_register(0x2100, 0x03);
+    assert_eq!(mbc2.read_rom(0x4000), 0x33);
+    // Address bit 8 clear is the RAM enable register, not a bank switch
+    mbc2.write_register(0x2000, 0x0A);
+    assert_eq!(mbc2.read_rom(0x4000), 0x33);
+
+    mbc2.write_ram(0xA005, 0xAB);
+    assert_eq!(mbc2.read_ram(0xA005), 0xFB);
+    assert_eq!(mbc2.read_ram(0xA205), 0xFB);
+
+    mbc2.write_register(0x0000, 0x00);
+    assert_eq!(mbc2.read_ram(0xA005), 0xFF);
+
+    let mut system = create_test_system(&rom);
+    assert_eq!(system.copy_external_ram_banks().unwrap().len(), 512);
+    assert_eq!(system.external_ram_mut().unwrap().len(), 512);
+}
+
+#[allow(dead_code)]
+fn label_rom_banks(rom: &mut [u8]) {
+    for bank in 0..(rom.len() / 0x4000) {
+        rom[bank * 0x4000 + 0x3FFF] = bank as u8;
+    }
+}
+
+#[test]
+fn mbc1_ram_enable_and_advanced_banking() {
+    let mut rom = create_test_rom(0x03, 5, 3);
+    label_rom_banks(&mut rom);
+    let mut mbc1 = MemoryBankController1::new();
+    mbc1.initialize(&rom, &parse_header(&rom).unwrap()).unwrap();
+
+    // RAM is disabled until 0x0A is written to 0x0000-0x1FFF
+    mbc1.write_ram(0xA000, 0x12);
+    assert_eq!(mbc1.read_ram(0xA000), 0xFF);
+    mbc1.write_register(0x0000, 0x0A);
+    mbc1.write_ram(0xA000, 0x12);
+    assert_eq!(mbc1.read_ram(0xA000), 0x12);
+
+    mbc1.write_register(0x2000, 0x00);
+    mbc1.write_register(0x4000, 0x01);
+    assert_eq!(mbc1.read_rom(0x7FFF), 0x21);
+    assert_eq!(mbc1.read_rom(0x3FFF), 0x00);
+
+    // Mode 1 remaps 0x0000-0x3FFF and the RAM bank
+    mbc1.write_register(0x6000, 0x01);
+    assert_eq!(mbc1.read_rom(0x3FFF), 0x20);
+    assert_eq!(mbc1.read_ram(0xA000), 0x00);
+    mbc1.write_ram(0xA000, 0x34);
+    assert_eq!(mbc1.external_ram()[0x2000], 0x34);
+    assert_eq!(mbc1.external_ram()[0], 0x12);
+
+    mbc1.write_register(0x0000, 0x00);
+    assert_eq!(mbc1.read_ram(0xA000), 0xFF);
+}
+
+#[test]
+fn mbc1_multicart_detection() {
+    let mut rom = create_test_rom(0x01, 5, 0);
+    label_rom_banks(&mut rom);
+    for game in 0..4 {
+        for i in 0x104..0x134 {
+            rom[game * 0x40000 + i] = i as u8;
+        }
+    }
+    let mut mbc1 = MemoryBankController1::new();
+    mbc1.initialize(&rom, &parse_header(&rom).unwrap()).unwrap();
+
+    mbc1.write_register(0x4000, 0x01);
+    mbc1.write_register(0x2000, 0x02);
+    assert_eq!(mbc1.read_rom(0x7FFF), 0x12);
+    mbc1.write_register(0x6000, 0x01);
+    assert_eq!(mbc1.read_rom(0x3FFF), 0x10);
+}
+
+#[allow(dead_code)]
+struct EchoCartridge {
+    rom: Vec<u8>,
+    register: u8,
+}
+
+impl Cartridge for EchoCartridge {
+    fn read_rom(&self, address: u16) -> u8 {
+        self.rom[address as usize]
+    }
+
+    fn write_register(&mut self, _address: u16, value: u8) {
+        self.register = value;
+    }
+
+    fn read_ram(&self, _address: u16) -> u8 {
+        self.register + 1
+    }
+
+    fn write_ram(&mut self, _address: u16, _value: u8) {}
+}
+
+impl SaveState for EchoCartridge {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.register);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.register = reader.read_u8()?;
+        Ok(())
+    }
+}
+
+#[test]
+fn custom_cartridge_receives_bus_accesses() {
+    let mut rom = create_test_rom(0x00, 0, 0);
+    let program = [
+        0x3E, 0x5A, // LD A, 0x5A
+        0xEA, 0x00, 0x20, // LD (0x2000), A
+        0xFA, 0x00, 0xA0, // LD A, (0xA000)
+        0xEA, 0x00, 0xC0, // LD (0xC000), A
+        0x18, 0xFE, // JR -2
+    ];
+    rom[0x100..0x100 + program.len()].copy_from_slice(&program);
+
+    let mut system = System::new(InitializationOptions {
+        cartridge: Some(Box::new(EchoCartridge { rom, register: 0 })),
+        ..test_options(&[])
+    })
+    .unwrap();
+    run_frames(&mut system, 1);
+
+    assert_eq!(system.work_ram_mut()[0], 0x5B);
+
+    // The custom cartridge is part of save states too
+    let state = system.save_state();
+    system.work_ram_mut()[0] = 0x00;
+    system.load_state(&state).unwrap();
+    assert_eq!(system.work_ram_mut()[0], 0x5B);
+    assert_eq!(system.save_state(), state);
+}
+
+#[test]
+fn cartridge_header_parsing() {
+    let mut rom = create_test_rom(0x1B, 2, 3);
+    rom[0x134..0x13F].copy_from_slice(b"POKEMON RED");
+    rom[0x13F..0x143].copy_from_slice(b"APSE");
+    rom[0x143] = 0x80;
+    rom[0x144..0x146].copy_from_slice(b"01");
+    rom[0x146] = 0x03;
+    rom[0x14B] = 0x33;
+    rom[0x14C] = 0x01;
+
+    let info = parse_header(&rom).unwrap();
+    assert_eq!(info.title, "POKEMON RED");
+    assert_eq!(info.manufacturer_code.as_deref(), Some("APSE"));
+    assert_eq!(info.cgb_support, CgbSupport::Enhanced);
+    assert!(info.sgb_support);
+    assert_eq!(info.licensee, Licensee::New("01".to_string()));
+    assert_eq!(info.cartridge_type, CartridgeType::Mbc5RamBattery);
+    assert_eq!(info.cartridge_type.mapper(), Some(Mapper::Mbc5));
+    assert!(info.cartridge_type.has_battery());
+    assert_eq!(info.rom_size, Some(0x20000));
+    assert_eq!(info.ram_size, Some(0x8000));
+    assert_eq!(info.version, 1);
+    assert!(!info.logo_valid);
+    assert!(!info.header_checksum_valid);
+    assert!(!info.global_checksum_valid);
+
+    rom[NINTENDO_LOGO_START..NINTENDO_LOGO_START + NINTENDO_LOGO.len()]
+        .copy_from_slice(&NINTENDO_LOGO);
+    rom[0x14D] = compute_header_checksum(&rom);
+    let global_checksum = compute_global_checksum(&rom);
+    rom[0x14E] = (global_checksum >> 8) as u8;
+    rom[0x14F] = global_checksum as u8;
+    let system = create_test_system(&rom);
+    let info = system.cartridge_info().unwrap();
+    assert!(info.logo_valid);
+    assert!(info.header_checksum_valid);
+    assert!(info.global_checksum_valid);
+
+    assert!(parse_header(&rom[..0x14F]).is_none());
+}
+
+#[test]
+fn loading_rejects_bad_roms_and_saves() {
+    let load = |rom: &[u8], external_ram: Option<&[u8]>| {
+        System::new(InitializationOptions {
+            external_ram,
+            ..test_options(rom)
+        })
+        .err()
+    };
+
+    assert_eq!(
+        load(&[0; 0x100], None),
+        Some(LoadError::TruncatedRom {
+            expected: 0x150,
+            actual: 0x100
+        })
+    );
+
+    let rom = create_test_rom(0x03, 2, 3);
+    assert_eq!(
+        load(&rom[..0x8000], None),
+        Some(LoadError::TruncatedRom {
+            expected: 0x20000,
+            actual: 0x8000
+        })
+    );
+    assert_eq!(
+        load(&rom, Some(&[0; 0x100])),
+        Some(LoadError::SaveSizeMismatch {
+            expected: 0x8000,
+            actual: 0x100
+        })
+    );
+    assert_eq!(load(&rom, Some(&[0; 0x8000])), None);
+
+    assert_eq!(
+        load(&create_test_rom(0xFD, 0, 0), None),
+        Some(LoadError::UnsupportedMapper(0xFD))
+    );
+
+    let mut bad_rom_size = create_test_rom(0x01, 0, 0);
+    bad_rom_size[0x148] = 0x07;
+    assert_eq!(
+        load(&bad_rom_size, None),
+        Some(LoadError::InvalidRomSize(0x07))
+    );
+
+    let mut bad_ram_size = create_test_rom(0x13, 0, 0);
+    bad_ram_size[0x149] = 0x04;
+    assert_eq!(
+        load(&bad_ram_size, None),
+        Some(LoadError::InvalidRamSize(0x04))
+    );
+}
+
+#[test]
+fn illegal_opcode_locks_cpu() {
+    let mut rom = create_test_rom(0x00, 0, 0);
+    rom[0x100] = 0xD3;
+    let mut system = create_test_system(&rom);
+
+    // Frames only complete if the LCD keeps running while the CPU is locked
+    let (_, sound_buffer) = run_frames(&mut system, 2);
+    assert!(system.is_cpu_locked());
+    assert!(!sound_buffer.is_empty());
+    assert_eq!(
+        system.take_events(),
+        vec![SystemEvent::CpuLocked {
+            address: 0x100,
+            opcode: 0xD3
+        }]
+    );
+    assert!(system.take_events().is_empty());
+    assert_eq!(system.gameboy.cpu.pc, 0x100);
+
+    let state = system.save_state();
+    let mut restored = create_test_system(&rom);
+    restored.load_state(&state).unwrap();
+    assert!(restored.is_cpu_locked());
+}
+
+#[test]
+fn cgb_banking_and_palettes() {
+    let mut rom = create_test_rom(0x00, 0, 0);
+    rom[0x143] = 0x80;
+    rom[0x100..0x102].copy_from_slice(&[0x18, 0xFE]); // JR -2
+    let mut system = create_test_system(&rom);
+    assert!(system.is_cgb());
+    assert_eq!(system.gameboy.cpu.get_a(), 0x11);
+
+    let memory = &mut system.gameboy.memory;
+    memory.set_byte(0xFF4F, 0x01);
+    memory.set_byte(0x9800, 0x01); // BG map attributes: palette 1
+    assert_eq!(memory.get_byte(0xFF4F), 0xFF);
+    memory.set_byte(0xFF4F, 0x00);
+    assert_eq!(memory.get_byte(0x9800), 0x00);
+
+    memory.set_byte(0xFF70, 0x03);
+    memory.set_byte(0xD000, 0x33);
+    assert_eq!(memory.get_byte(0xF000), 0x33);
+    memory.set_byte(0xFF70, 0x00);
+    assert_eq!(memory.get_byte(0xD000), 0x00);
+    assert_eq!(memory.work_ram_mut()[3 * 0x1000], 0x33);
+
+    // Palette 1 color 0 is pure red, written with auto increment
+    memory.set_byte(0xFF68, 0x88);
+    memory.set_byte(0xFF69, 0x1F);
+    memory.set_byte(0xFF69, 0x00);
+    assert_eq!(memory.get_byte(0xFF68), 0xCA);
+    assert_eq!(memory.bg_palette_color(1, 0), 0x001F);
+
+    let (framebuffer, _) = run_frames(&mut system, 2);
+    assert_eq!(&framebuffer[0..4], &[0x00, 0x00, 0xFF, 0xFF]);
+    // The next tile uses palette 0 which starts out white
+    assert_eq!(&framebuffer[8 * 4..8 * 4 + 4], &[0xFF, 0xFF, 0xFF, 0xFF]);
+}
+
+#[test]
+fn cgb_hdma_and_speed_switch() {
+    let mut rom = create_test_rom(0x00, 0, 0);
+    rom[0x143] = 0x80;
+    // LD A,1; LDH (KEY1),A; STOP; JR -2
+    rom[0x100..0x108].copy_from_slice(&[0x3E, 0x01, 0xE0, 0x4D, 0x10, 0x00, 0x18, 0xFE]);
+    let mut system = create_test_system(&rom);
+
+    let memory = &mut system.gameboy.memory;
+    for i in 0..0x40 {
+        memory.set_byte(0xC000 + i, i as u8);
+    }
+    memory.set_byte(0xFF51, 0xC0);
+    memory.set_byte(0xFF52, 0x00);
+    memory.set_byte(0xFF53, 0x01);
+    memory.set_byte(0xFF54, 0x0F);
+
+    // General purpose DMA copies everything at once and stalls the CPU
+    memory.set_byte(0xFF55, 0x01);
+    assert_eq!(memory.get_byte(0xFF55), 0xFF);
+    assert_eq!(memory.get_byte(0x8100), 0x00);
+    assert_eq!(memory.get_byte(0x811F), 0x1F);
+    let mut stall_cycles = 0;
+    loop {
+        match memory.take_stall_cycles() {
+            0 => break,
+            x => stall_cycles += x as u32,
+        }
+    }
+    assert_eq!(stall_cycles, 64);
+
+    // HBlank DMA continues from where the last transfer stopped and can be
+    // cancelled between blocks
+    memory.set_byte(0xFF55, 0x81);
+    assert_eq!(memory.get_byte(0xFF55), 0x01);
+    memory.hblank_started();
+    assert_eq!(memory.get_byte(0x8120), 0x20);
+    assert_eq!(memory.get_byte(0x8130), 0x00);
+    assert_eq!(memory.get_byte(0xFF55), 0x00);
+    memory.set_byte(0xFF55, 0x00);
+    assert_eq!(memory.get_byte(0xFF55), 0x80);
+    memory.hblank_started();
+    assert_eq!(memory.get_byte(0x8130), 0x00);
+    while memory.take_stall_cycles() > 0 {}
+
+    assert!(!system.gameboy.memory.is_double_speed());
+    run_frames(&mut system, 1);
+    assert!(system.gameboy.memory.is_double_speed());
+    assert_eq!(system.gameboy.memory.get_byte(0xFF4D), 0xFE);
+}
+
+#[test]
+fn cgb_compatibility_palettes() {
+    let red = PaletteShortcut::RightA.palette().object0;
+    let green = PaletteShortcut::UpA.palette().object0;
+    let blue = PaletteShortcut::UpA.palette().object1;
+    let mut rom = create_test_rom(0x00, 0, 0);
+    rom[0x14B] = 0x01;
+    let mut palette_for = |title: &[u8]| {
+        rom[0x134..0x144].iter_mut().for_each(|b| *b = 0);
+        rom[0x134..0x134 + title.len()].copy_from_slice(title);
+        title_palette(&parse_header(&rom).unwrap(), &rom)
+    };
+    assert_eq!(
+        palette_for(b"POKEMON RED"),
+        CompatibilityPalette {
+            background: red,
+            object0: green,
+            object1: red,
+        }
+    );
+    assert_eq!(palette_for(b"TETRIS"), PaletteShortcut::DownA.palette());
+
+    // POKEMON BLUE and VEGAS STAKES share a checksum, the fourth letter of
+    // the title picks between them and any other letter gets the default
+    assert_eq!(
+        palette_for(b"POKEMON BLUE"),
+        CompatibilityPalette {
+            background: blue,
+            object0: red,
+            object1: blue,
+        }
+    );
+    assert_eq!(
+        palette_for(b"VEGAS STAKES"),
+        CompatibilityPalette {
+            background: green,
+            object0: red,
+            object1: blue,
+        }
+    );
+    assert_eq!(
+        palette_for(b"VEGSA STAKES"),
+        PaletteShortcut::RightA.palette()
+    );
+
+    // Only Nintendo titles are looked up
+    rom[0x14B] = 0x08;
+    let info = parse_header(&rom).unwrap();
+    assert_eq!(
+        title_palette(&info, &rom),
+        PaletteShortcut::RightA.palette()
+    );
+
+    let mut system = System::new(InitializationOptions {
+        cgb_compatibility: true,
+        ..test_options(&rom)
+    })
+    .unwrap();
+    assert!(!system.is_cgb());
+    assert_eq!(system.gameboy.cpu.get_a(), 0x11);
+
+    // The lightest shade of the default palette is white, the inverted one
+    // starts with black
+    let (framebuffer, _) = run_frames(&mut system, 2);
+    assert_eq!(&framebuffer[0..4], &[0xFF, 0xFF, 0xFF, 0xFF]);
+    system.set_compatibility_palette(&PaletteShortcut::RightB.palette());
+    let (framebuffer, _) = run_frames(&mut system, 2);
+    assert_eq!(&framebuffer[0..4], &[0x00, 0x00, 0x00, 0xFF]);
+}
+
+#[allow(dead_code)]
+fn send_sgb_packet(sgb: &mut SuperGameBoy, packet: &[u8]) {
+    sgb.joypad_written(0x00);
+    sgb.joypad_written(0x30);
+    for i in 0..128 {
+        let bit = packet.get(i / 8).map_or(0, |b| (b >> (i % 8)) & 1);
+        sgb.joypad_written(if bit == 1 { 0x10 } else { 0x20 });
+        sgb.joypad_written(0x30);
+    }
+    sgb.joypad_written(0x20);
+    sgb.joypad_written(0x30);
+}
+
+#[test]
+fn sgb_packets_color_the_screen() {
+    let mut sgb = SuperGameBoy::new();
+    // PAL01: red as the shared color 0, green as color 1 of palette 1
+    send_sgb_packet(
+        &mut sgb,
+        &[0x01, 0x1F, 0x00, 0, 0, 0, 0, 0, 0, 0xE0, 0x03, 0, 0, 0, 0],
+    );
+    // ATTR_DIV: palette 0 left of column 10, palette 1 from there on
+    send_sgb_packet(&mut sgb, &[0x31, 0b01_00_01, 10]);
+
+    let mut framebuffer = vec![0; 256 * 224 * 4];
+    sgb.frame_finished(&[1; 160 * 144]);
+    sgb.render(&mut framebuffer);
+    let pixel = |framebuffer: &[u8], x: usize, y: usize| {
+        let index = 4 * ((y * 256) + x);
+        framebuffer[index..index + 4].to_vec()
+    };
+    assert_eq!(pixel(&framebuffer, 0, 0), vec![0x00, 0x00, 0xFF, 0xFF]);
+    assert_eq!(
+        pixel(&framebuffer, 48 + 100, 40),
+        vec![0x00, 0xFF, 0x00, 0xFF]
+    );
+    assert_eq!(pixel(&framebuffer, 48, 40), vec![0x00, 0x00, 0x00, 0xFF]);
+
+    // MASK_EN blanks the screen to black
+    send_sgb_packet(&mut sgb, &[0xB9, 0x02]);
+    sgb.render(&mut framebuffer);
+    assert_eq!(
+        pixel(&framebuffer, 48 + 100, 40),
+        vec![0x00, 0x00, 0x00, 0xFF]
+    );
+
+    // MLT_REQ makes the joypads take turns whenever P15 goes high
+    send_sgb_packet(&mut sgb, &[0x89, 0x01]);
+    assert_eq!(sgb.current_player(), 0);
+    sgb.joypad_written(0x10);
+    sgb.joypad_written(0x30);
+    assert_eq!(sgb.current_player(), 1);
+
+    let mut rom = create_test_rom(0x00, 0, 0);
+    rom[0x146] = 0x03;
+    rom[0x14B] = 0x33;
+    let mut system = System::new(InitializationOptions {
+        super_game_boy: true,
+        ..test_options(&rom)
+    })
+    .unwrap();
+    assert!(system.is_sgb());
+    assert_eq!(system.framebuffer_width(), 256);
+    assert_eq!(system.framebuffer_height(), 224);
+    let mut framebuffer = vec![0; 256 * 224 * 4];
+    let mut sound_buffer: Vec<i16> = Vec::new();
+    system.run_single_frame(&[], &mut framebuffer, &mut sound_buffer);
+    system.run_single_frame(&[], &mut framebuffer, &mut sound_buffer);
+    assert_eq!(framebuffer[3], 0xFF);
+
+    // A restored state draws the same frame and keeps the same size with a
+    // command half received
+    let state_size = system.save_state().len();
+    send_sgb_packet(system.sgb.as_mut().unwrap(), &[0x02]);
+    let state = system.save_state();
+    assert_eq!(state.len(), state_size);
+    system.run_single_frame(&[], &mut framebuffer, &mut sound_buffer);
+    let expected = framebuffer.clone();
+    system.load_state(&state).unwrap();
+    system.run_single_frame(&[], &mut framebuffer, &mut sound_buffer);
+    assert_eq!(framebuffer, expected);
+}
+
+#[allow(dead_code)]
+struct RecordingDevice {
+    received: std::rc::Rc<std::cell::RefCell<Vec<u8>>>,
+}
+
+impl SerialDevice for RecordingDevice {
+    fn transfer(&mut self, outgoing: u8) -> u8 {
+        self.received.borrow_mut().push(outgoing);
+        0x99
+    }
+}
+
+#[test]
+fn serial_transfer_exchanges_bytes() {
+    let mut rom = create_test_rom(0x00, 0, 0);
+    // LD A,0x42; LDH (SB),A; LD A,0x81; LDH (SC),A; JR -2
+    rom[0x100..0x10A]
+        .copy_from_slice(&[0x3E, 0x42, 0xE0, 0x01, 0x3E, 0x81, 0xE0, 0x02, 0x18, 0xFE]);
+
+    // Nothing is connected at first, the bits shifted in are all 1s
+    let mut system = create_test_system(&rom);
+    run_frames(&mut system, 1);
+    assert_eq!(system.gameboy.memory.get_byte(0xFF01), 0xFF);
+    assert_eq!(system.gameboy.memory.get_byte(0xFF02), 0x7F);
+    assert_eq!(system.gameboy.memory.get_byte(0xFF0F) & 0x08, 0x08);
+
+    let received = std::rc::Rc::new(std::cell::RefCell::new(Vec::new()));
+    let mut system = create_test_system(&rom);
+    system.connect_serial_device(Box::new(RecordingDevice {
+        received: received.clone(),
+    }));
+    // A byte takes 4096 cycles at the normal rate
+    run_frames(&mut system, 1);
+    assert_eq!(*received.borrow(), vec![0x42]);
+    assert_eq!(system.gameboy.memory.get_byte(0xFF01), 0x99);
+}
+
+#[test]
+fn tcp_link_exchanges_bytes() {
+    // Any free port will do
+    let listener = TcpLink::listen(0).unwrap();
+    let port = listener.port().unwrap();
+    let host = std::thread::spawn(move || {
+        let mut link = listener.accept().unwrap();
+        link.transfer(0x12)
+    });
+
+    let mut link = TcpLink::connect(("127.0.0.1", port)).unwrap();
+    // The host clocks the transfer, the byte arrives on one of the polls
+    let received = loop {
+        if let Some(incoming) = link.poll_external_transfer(Some(0x34)) {
+            break incoming;
+        }
+        std::thread::yield_now();
+    };
+    assert_eq!(received, 0x12);
+    assert_eq!(host.join().unwrap(), 0x34);
+
+    // The host hung up, the port reads as unconnected from now on
+    assert_eq!(link.transfer(0x56), 0xFF);
+    assert!(!link.is_connected());
+}
+
+#[test]
+fn linked_systems_exchange_bytes() {
+    // LD A,0x42; LDH (SB),A; LD A,0x81; LDH (SC),A; JR -2
+    let mut master = create_test_rom(0x00, 0, 0);
+    master[0x100..0x10A]
+        .copy_from_slice(&[0x3E, 0x42, 0xE0, 0x01, 0x3E, 0x81, 0xE0, 0x02, 0x18, 0xFE]);
+    // Same, but waits for the other side to clock the transfer
+    let mut slave = master.clone();
+    slave[0x101] = 0x99;
+    slave[0x105] = 0x80;
+
+    let run = || {
+        let mut linked = LinkedSystems::new(vec![
+            create_test_system(&master),
+            create_test_system(&slave),
+        ]);
+        linked.connect(0, 1);
+        for _ in 0..2 {
+            assert!(linked.run_single_frame(&[]));
+        }
+        linked
+    };
+
+    let linked = run();
+    let memory = |index: usize| &linked.system(index).gameboy.memory;
+    assert_eq!(memory(0).get_byte(0xFF01), 0x99);
+    assert_eq!(memory(1).get_byte(0xFF01), 0x42);
+    assert_eq!(memory(1).get_byte(0xFF02), 0x7E);
+    assert_eq!(memory(1).get_byte(0xFF0F) & 0x08, 0x08);
+
+    // Running again gives exactly the same machines
+    let again = run();
+    for index in 0..linked.len() {
+        assert_eq!(
+            linked.system(index).save_state(),
+            again.system(index).save_state()
+        );
+        assert_eq!(linked.framebuffer(index), again.framebuffer(index));
+    }
+}
+
+// Sends a packet to the printer and returns the two status bytes it answers
+#[allow(dead_code)]
+fn send_printer_packet(
+    printer: &mut Printer,
+    command: u8,
+    compressed: bool,
+    data: &[u8],
+) -> [u8; 2] {
+    let mut packet = vec![
+        command,
+        compressed as u8,
+        data.len() as u8,
+        (data.len() >> 8) as u8,
+    ];
+    packet.extend_from_slice(data);
+    let checksum = packet
+        .iter()
+        .fold(0u16, |sum, b| sum.wrapping_add(*b as u16));
+    packet.extend_from_slice(&checksum.to_le_bytes());
+
+    for byte in [0x88, 0x33].iter().chain(packet.iter()) {
+        assert_eq!(printer.transfer(*byte), 0x00);
+    }
+    [printer.transfer(0x00), printer.transfer(0x00)]
+}
+
+#[test]
+fn printer_saves_png_sheets() {
+    let directory = std::env::temp_dir().join(format!("gameboy_printer_{}", std::process::id()));
+    let mut printer = Printer::new(&directory).unwrap();
+
+    assert_eq!(
+        send_printer_packet(&mut printer, 0x01, false, &[]),
+        [0x81, 0x00]
+    );
+    // A black band compressed with runs of 129 and 124 bytes, then a white one
+    let mut black = vec![0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF];
+    black.extend_from_slice(&[0xFA, 0xFF]);
+    assert_eq!(
+        send_printer_packet(&mut printer, 0x04, true, &black),
+        [0x81, 0x08]
+    );
+    send_printer_packet(&mut printer, 0x04, false, &[0x00; 640]);
+    send_printer_packet(&mut printer, 0x04, false, &[]);
+
+    // One sheet with a bottom margin of 8 lines, the printer is busy once
+    assert_eq!(
+        send_printer_packet(&mut printer, 0x02, false, &[1, 0x01, 0xE4, 0x40]),
+        [0x81, 0x02]
+    );
+    assert_eq!(
+        send_printer_packet(&mut printer, 0x0F, false, &[]),
+        [0x81, 0x02]
+    );
+    assert_eq!(
+        send_printer_packet(&mut printer, 0x0F, false, &[]),
+        [0x81, 0x00]
+    );
+    // A packet with a bad checksum is reported
+    let responses: Vec<u8> = [0x88, 0x33, 0x0F, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00]
+        .iter()
+        .map(|byte| printer.transfer(*byte))
+        .collect();
+    assert_eq!(&responses[8..], &[0x81, 0x01]);
+
+    // Without a bottom margin the sheet is saved once the printer goes away
+    send_printer_packet(&mut printer, 0x04, false, &[0x00; 640]);
+    send_printer_packet(&mut printer, 0x02, false, &[1, 0x00, 0xE4, 0x40]);
+    assert!(!directory.join("print_0002.png").exists());
+    drop(printer);
+    assert!(directory.join("print_0002.png").exists());
+
+    let png = std::fs::read(directory.join("print_0001.png")).unwrap();
+    std::fs::remove_dir_all(&directory).unwrap();
+    assert_eq!(&png[..8], &[0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A]);
+    assert_eq!(&png[12..16], b"IHDR");
+    assert_eq!(
+        u32::from_be_bytes([png[16], png[17], png[18], png[19]]),
+        160
+    );
+    assert_eq!(u32::from_be_bytes([png[20], png[21], png[22], png[23]]), 40);
+}
+
+#[test]
+fn noise_channel_plays() {
+    let mut rom = create_test_rom(0x00, 0, 0);
+    // Full volume envelope, fastest clock, both sides, trigger, then JR -2
+    rom[0x100..0x114].copy_from_slice(&[
+        0x3E, 0xF0, 0xE0, 0x21, 0xAF, 0xE0, 0x22, 0x3E, 0xFF, 0xE0, 0x25, 0x3E, 0x77, 0xE0, 0x24,
+        0x3E, 0x80, 0xE0, 0x23, 0x18,
+    ]);
+    rom[0x114] = 0xFE;
+
+    let mut system = create_test_system(&rom);
+    let (_, sound_buffer) = run_frames(&mut system, 2);
+    assert_eq!(system.gameboy.memory.get_byte(0xFF26) & 0x08, 0x08);
+    let mut samples = sound_buffer.clone();
+    samples.sort_unstable();
+    samples.dedup();
+    assert!(samples.len() > 1);
+
+    // Without the trigger the channel stays silent
+    rom[0x110] = 0x00;
+    let mut system = create_test_system(&rom);
+    let (_, silent_buffer) = run_frames(&mut system, 2);
+    assert!(silent_buffer
+        .iter()
+        .all(|sample| *sample == silent_buffer[0]));
+}
+
+#[test]
+fn sound_registers_follow_hardware_rules() {
+    let mut rom = create_test_rom(0x00, 0, 0);
+    rom[0x100..0x102].copy_from_slice(&[0x18, 0xFE]);
+    let mut system = create_test_system(&rom);
+
+    // Write only bits read as 1
+    system.gameboy.memory.set_byte(0xFF10, 0x00);
+    system.gameboy.memory.set_byte(0xFF11, 0xC5);
+    assert_eq!(system.gameboy.memory.get_byte(0xFF10), 0x80);
+    assert_eq!(system.gameboy.memory.get_byte(0xFF11), 0xFF);
+    assert_eq!(system.gameboy.memory.get_byte(0xFF27), 0xFF);
+
+    // NR52 shows which channels play, a channel stops when its DAC is off
+    system.gameboy.memory.set_byte(0xFF17, 0xF0);
+    system.gameboy.memory.set_byte(0xFF19, 0x80);
+    run_frames(&mut system, 1);
+    assert_eq!(system.gameboy.memory.get_byte(0xFF26), 0xF2);
+    system.gameboy.memory.set_byte(0xFF17, 0x00);
+    run_frames(&mut system, 1);
+    assert_eq!(system.gameboy.memory.get_byte(0xFF26), 0xF0);
+
+    // Powering off clears the registers and ignores writes, except to the
+    // length counters of the DMG. Wave RAM is kept.
+    system.gameboy.memory.set_byte(0xFF30, 0x12);
+    system.gameboy.memory.set_byte(0xFF26, 0x00);
+    run_frames(&mut system, 1);
+    assert_eq!(system.gameboy.memory.get_byte(0xFF26), 0x70);
+    assert_eq!(system.gameboy.memory.get_byte(0xFF24), 0x00);
+    system.gameboy.memory.set_byte(0xFF24, 0x77);
+    system.gameboy.memory.set_byte(0xFF11, 0xFF);
+    assert_eq!(system.gameboy.memory.get_byte(0xFF24), 0x00);
+    assert_eq!(
+        system
+            .gameboy
+            .memory
+            .get_register(Register::Channel1LengthDuty),
+        0x3F
+    );
+    assert_eq!(system.gameboy.memory.get_byte(0xFF30), 0x12);
+
+    system.gameboy.memory.set_byte(0xFF26, 0x80);
+    system.gameboy.memory.set_byte(0xFF24, 0x77);
+    assert_eq!(system.gameboy.memory.get_byte(0xFF24), 0x77);
+    assert_eq!(system.gameboy.memory.get_byte(0xFF26), 0xF0);
+
+    // The DMG blocks wave RAM while channel 3 plays, apart from the moment
+    // it reads a byte
+    system.gameboy.memory.set_byte(0xFF1A, 0x80);
+    system.gameboy.memory.set_byte(0xFF1E, 0x80);
+    run_frames(&mut system, 1);
+    assert_eq!(system.gameboy.memory.get_byte(0xFF26), 0xF4);
+    let reads: Vec<u8> = (0..64)
+        .map(|_| {
+            system.step(&mut [0; 160 * 144 * 4], &mut Vec::<i16>::new());
+            system.gameboy.memory.get_byte(0xFF30)
+        })
+        .collect();
+    assert!(reads.contains(&0xFF));
+}
+
+// A game that plays a constant square wave on channel 2
+#[allow(dead_code)]
+fn square_wave_rom(frequency: u16, master_volume: u8) -> Vec<u8> {
+    let mut rom = create_test_rom(0x00, 0, 0);
+    rom[0x100..0x116].copy_from_slice(&[
+        0x3E,
+        master_volume,
+        0xE0,
+        0x24,
+        0x3E,
+        0xF0,
+        0xE0,
+        0x17,
+        0x3E,
+        0x80,
+        0xE0,
+        0x16,
+        0x3E,
+        frequency as u8,
+        0xE0,
+        0x18,
+        0x3E,
+        0x80 | (frequency >> 8) as u8,
+        0xE0,
+        0x19,
+        0x18,
+        0xFE,
+    ]);
+    rom
+}
+
+// Plays a constant square wave on channel 2 and returns the second frame
+#[allow(dead_code)]
+fn play_square_wave<S: crate::sound::AudioSample>(frequency: u16, master_volume: u8) -> Vec<S> {
+    let mut system = create_test_system(&square_wave_rom(frequency, master_volume));
+    let mut framebuffer = vec![0; 160 * 144 * 4];
+    let mut sound_buffer = Vec::new();
+    system.run_single_frame(&[], &mut framebuffer, &mut sound_buffer);
+    sound_buffer.clear();
+    system.run_single_frame(&[], &mut framebuffer, &mut sound_buffer);
+    sound_buffer
+}
+
+#[test]
+fn save_states_have_a_fixed_size() {
+    let mut system = create_test_system(&square_wave_rom(1917, 0x77));
+    system.set_channel_capture(true);
+    let state_size = system.save_state().len();
+    for _ in 0..10 {
+        run_frames(&mut system, 1);
+        assert_eq!(system.save_state().len(), state_size);
+    }
+}
+
+#[test]
+fn sound_is_band_limited() {
+    let loudest = |samples: &[i16]| samples.iter().map(|s| (*s as i32).abs()).max().unwrap();
+
+    // About 48000 / 59.73 stereo samples per frame
+    let tone = play_square_wave::<i16>(1917, 0x77);
+    assert!((1600..1620).contains(&tone.len()));
+    assert!(loudest(&tone) > 4000);
+
+    // A tone far above what the output can hold is filtered out rather than
+    // folding back as noise
+    let ultrasonic = play_square_wave::<i16>(2047, 0x77);
+    assert!(loudest(&ultrasonic) < 1000);
+
+    // NR50 scales the output
+    let quiet = play_square_wave::<i16>(1917, 0x00);
+    assert!(loudest(&quiet) * 4 < loudest(&tone));
+
+    // Floating point samples match the 16-bit ones
+    let float_tone = play_square_wave::<f32>(1917, 0x77);
+    for (float, integer) in float_tone.iter().zip(tone.iter()) {
+        assert!((float * i16::MAX as f32 - *integer as f32).abs() <= 1.0);
+    }
+}
+
+#[test]
+fn sound_plays_at_high_sample_rates() {
+    // The longest sound update leaves 255 cycles of steps unread, which is
+    // over 23 samples at this rate
+    let mut buffer = BlipBuffer::new(384000);
+    buffer.set_level(1.0);
+    buffer.advance(255);
+    buffer.set_level(0.0);
+    buffer.advance(255);
+
+    let samples: Vec<f32> = std::iter::from_fn(|| buffer.read_sample()).collect();
+    assert_eq!(samples.len(), 46);
+    assert!(samples[12..20].iter().all(|sample| *sample > 0.9));
+    assert!(samples[40..].iter().all(|sample| sample.abs() < 0.1));
+}
+
+#[test]
+fn sound_channels_can_be_muted_soloed_and_captured() {
+    let loudest = |samples: &[i16]| samples.iter().map(|s| (*s as i32).abs()).max().unwrap();
+    let mut system = create_test_system(&square_wave_rom(1917, 0x77));
+    system.set_channel_capture(true);
+    run_frames(&mut system, 1);
+    for channel in AudioChannel::ALL.iter() {
+        system.take_channel_samples::<i16>(*channel);
+    }
+
+    let (_, mixed) = run_frames(&mut system, 1);
+    let captured = system.take_channel_samples::<i16>(AudioChannel::Two);
+    assert_eq!(captured.len(), mixed.len() / 2);
+    assert!(loudest(&captured) > 4000);
+    assert!(loudest(&system.take_channel_samples::<i16>(AudioChannel::One)) < 100);
+
+    // Muting only changes the mix, the capture keeps the channel
+    system.set_channel_muted(AudioChannel::Two, true);
+    run_frames(&mut system, 1);
+    let (_, mixed) = run_frames(&mut system, 1);
+    assert!(loudest(&mixed) < 100);
+    system.take_channel_samples::<i16>(AudioChannel::Two);
+    run_frames(&mut system, 1);
+    assert!(loudest(&system.take_channel_samples::<i16>(AudioChannel::Two)) > 4000);
+
+    // Soloing another channel silences this one too
+    system.set_channel_muted(AudioChannel::Two, false);
+    system.set_channel_soloed(AudioChannel::One, true);
+    run_frames(&mut system, 1);
+    let (_, mixed) = run_frames(&mut system, 1);
+    assert!(loudest(&mixed) < 100);
+
+    system.set_channel_soloed(AudioChannel::Two, true);
+    let (_, mixed) = run_frames(&mut system, 1);
+    assert!(loudest(&mixed) > 4000);
+
+    system.set_channel_capture(false);
+    run_frames(&mut system, 1);
+    assert!(system
+        .take_channel_samples::<i16>(AudioChannel::Two)
+        .is_empty());
+}
+
+#[test]
+fn audio_is_recorded_to_wav_files() {
+    let directory = std::env::temp_dir().join(format!("gameboy_wav_{}", std::process::id()));
+    std::fs::create_dir_all(&directory).unwrap();
+    let path = directory.join("recording.wav");
+
+    let mut system = create_test_system(&square_wave_rom(1917, 0x77));
+    let mut recorder = AudioRecorder::start(&mut system, &path, true).unwrap();
+    let mut recorded = Vec::new();
+    for _ in 0..3 {
+        let (_, samples) = run_frames(&mut system, 1);
+        recorder.record(&mut system, &samples).unwrap();
+        recorded.extend(samples);
+    }
+
+    recorder.stop(&mut system).unwrap();
+
+    let read_u32 = |wav: &[u8], offset: usize| {
+        u32::from_le_bytes([
+            wav[offset],
+            wav[offset + 1],
+            wav[offset + 2],
+            wav[offset + 3],
+        ])
+    };
+    let wav = std::fs::read(&path).unwrap();
+    assert_eq!(&wav[0..4], b"RIFF");
+    assert_eq!(read_u32(&wav, 4) as usize, wav.len() - 8);
+    assert_eq!(&wav[8..16], b"WAVEfmt ");
+    assert_eq!(&wav[22..24], &2u16.to_le_bytes());
+    assert_eq!(read_u32(&wav, 24), system.sound_frequency());
+    assert_eq!(&wav[36..40], b"data");
+    assert_eq!(read_u32(&wav, 40) as usize, recorded.len() * 2);
+    let first: Vec<u8> = recorded[..4].iter().flat_map(|s| s.to_le_bytes()).collect();
+    assert_eq!(&wav[44..52], first.as_slice());
+
+    let stem = std::fs::read(directory.join("recording_channel2.wav")).unwrap();
+    assert_eq!(&stem[22..24], &1u16.to_le_bytes());
+    assert_eq!(read_u32(&stem, 40) as usize, recorded.len());
+    assert_eq!(read_u32(&stem, 4) as usize, stem.len() - 8);
+
+    // Dropping a writer finishes the file too
+    let dropped_path = directory.join("dropped.wav");
+    let mut writer = WavWriter::create(&dropped_path, 48000, 1).unwrap();
+    writer.write_samples(&[1, -1, 2]).unwrap();
+    drop(writer);
+    let wav = std::fs::read(&dropped_path).unwrap();
+    assert_eq!(wav.len(), 44 + 6);
+    assert_eq!(read_u32(&wav, 40), 6);
+    std::fs::remove_dir_all(&directory).unwrap();
+}
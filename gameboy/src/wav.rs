@@ -0,0 +1,182 @@
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use crate::sound::AudioChannel;
+use crate::System;
+
+const HEADER_SIZE: u32 = 44;
+const BYTES_PER_SAMPLE: u16 = 2;
+// Offsets of the sizes that grow with the recording
+const RIFF_SIZE_OFFSET: u64 = 4;
+const DATA_SIZE_OFFSET: u64 = 40;
+
+/// Writes 16-bit PCM samples to a RIFF WAV file. The sizes in the header are
+/// updated once per second of audio and when the writer is finished or
+/// dropped, so a recording cut short by a crash loses at most the last second.
+pub struct WavWriter<W: Write + Seek> {
+    writer: BufWriter<W>,
+    data_size: u32,
+    // The data size the header was last updated with
+    header_data_size: u32,
+    header_update_interval: u32,
+}
+
+impl WavWriter<File> {
+    /// Creates the file at `path`, replacing it if it exists
+    pub fn create<P: AsRef<Path>>(
+        path: P,
+        sample_rate: u32,
+        channels: u16,
+    ) -> io::Result<WavWriter<File>> {
+        WavWriter::new(File::create(path)?, sample_rate, channels)
+    }
+}
+
+impl<W: Write + Seek> WavWriter<W> {
+    pub fn new(writer: W, sample_rate: u32, channels: u16) -> io::Result<WavWriter<W>> {
+        let mut writer = BufWriter::new(writer);
+        let block_align = channels * BYTES_PER_SAMPLE;
+        let mut header = Vec::with_capacity(HEADER_SIZE as usize);
+        header.extend_from_slice(b"RIFF");
+        header.extend_from_slice(&(HEADER_SIZE - 8).to_le_bytes());
+        header.extend_from_slice(b"WAVE");
+        header.extend_from_slice(b"fmt ");
+        header.extend_from_slice(&16u32.to_le_bytes());
+        // Uncompressed PCM
+        header.extend_from_slice(&1u16.to_le_bytes());
+        header.extend_from_slice(&channels.to_le_bytes());
+        header.extend_from_slice(&sample_rate.to_le_bytes());
+        header.extend_from_slice(&(sample_rate * block_align as u32).to_le_bytes());
+        header.extend_from_slice(&block_align.to_le_bytes());
+        header.extend_from_slice(&(8 * BYTES_PER_SAMPLE).to_le_bytes());
+        header.extend_from_slice(b"data");
+        header.extend_from_slice(&0u32.to_le_bytes());
+        writer.write_all(&header)?;
+        writer.flush()?;
+
+        Ok(WavWriter {
+            writer,
+            data_size: 0,
+            header_data_size: 0,
+            header_update_interval: sample_rate * block_align as u32,
+        })
+    }
+
+    /// Appends samples, interleaved if there is more than one channel
+    pub fn write_samples(&mut self, samples: &[i16]) -> io::Result<()> {
+        let size = samples.len() as u64 * BYTES_PER_SAMPLE as u64;
+        let data_size = self.data_size as u64 + size;
+        if data_size > (u32::MAX - HEADER_SIZE) as u64 {
+            return Err(io::Error::other("the WAV file reached its maximum size"));
+        }
+
+        let mut data = Vec::with_capacity(size as usize);
+        for sample in samples {
+            data.extend_from_slice(&sample.to_le_bytes());
+        }
+        self.writer.write_all(&data)?;
+        self.data_size = data_size as u32;
+
+        if self.data_size - self.header_data_size >= self.header_update_interval {
+            self.update_header()?;
+        }
+        Ok(())
+    }
+
+    /// Writes out the remaining samples and the final sizes
+    pub fn finish(mut self) -> io::Result<()> {
+        self.update_header()
+    }
+
+    fn update_header(&mut self) -> io::Result<()> {
+        if self.data_size == self.header_data_size {
+            return Ok(());
+        }
+
+        // The samples go out first so the header never covers missing data
+        self.writer.flush()?;
+        self.writer.seek(SeekFrom::Start(RIFF_SIZE_OFFSET))?;
+        self.writer
+            .write_all(&(HEADER_SIZE - 8 + self.data_size).to_le_bytes())?;
+        self.writer.seek(SeekFrom::Start(DATA_SIZE_OFFSET))?;
+        self.writer.write_all(&self.data_size.to_le_bytes())?;
+        self.writer.seek(SeekFrom::End(0))?;
+        self.writer.flush()?;
+        self.header_data_size = self.data_size;
+        Ok(())
+    }
+}
+
+impl<W: Write + Seek> Drop for WavWriter<W> {
+    fn drop(&mut self) {
+        // Errors can't be reported here, `finish` returns them
+        let _ = self.update_header();
+    }
+}
+
+/// Records the sound of a system to WAV files: the stereo mix from the buffer
+/// `run_single_frame` fills, and optionally a mono stem for every channel
+/// next to it, named after the mix with `_channel1` to `_channel4` added.
+pub struct AudioRecorder {
+    mixed: WavWriter<File>,
+    stems: Vec<(AudioChannel, WavWriter<File>)>,
+}
+
+impl AudioRecorder {
+    pub fn start<P: AsRef<Path>>(
+        system: &mut System,
+        path: P,
+        stems: bool,
+    ) -> io::Result<AudioRecorder> {
+        let path = path.as_ref();
+        let sample_rate = system.sound_frequency();
+        let mixed = WavWriter::create(path, sample_rate, 2)?;
+
+        let mut recorder = AudioRecorder {
+            mixed,
+            stems: Vec::new(),
+        };
+        if stems {
+            for (index, channel) in AudioChannel::ALL.iter().enumerate() {
+                let stem_path = stem_path(path, index + 1);
+                let writer = WavWriter::create(stem_path, sample_rate, 1)?;
+                recorder.stems.push((*channel, writer));
+            }
+            system.set_channel_capture(true);
+            // Only keep what is played from now on
+            for channel in AudioChannel::ALL.iter() {
+                system.take_channel_samples::<i16>(*channel);
+            }
+        }
+        Ok(recorder)
+    }
+
+    /// Writes the samples of the last frames, `samples` being the interleaved
+    /// stereo output of `run_single_frame`
+    pub fn record(&mut self, system: &mut System, samples: &[i16]) -> io::Result<()> {
+        self.mixed.write_samples(samples)?;
+        for (channel, writer) in self.stems.iter_mut() {
+            writer.write_samples(&system.take_channel_samples::<i16>(*channel))?;
+        }
+        Ok(())
+    }
+
+    /// Finishes the files and stops capturing the channels
+    pub fn stop(self, system: &mut System) -> io::Result<()> {
+        if !self.stems.is_empty() {
+            system.set_channel_capture(false);
+        }
+        self.mixed.finish()?;
+        for (_, writer) in self.stems {
+            writer.finish()?;
+        }
+        Ok(())
+    }
+}
+
+fn stem_path(path: &Path, channel: usize) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!("{}_channel{}.wav", stem, channel))
+}
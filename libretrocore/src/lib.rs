@@ -1,7 +1,7 @@
 pub mod libretro_types;
 
 use crate::libretro_types::*;
-use gameboy::{Button, ButtonState, InitializationOptions, InputEvent, System};
+use gameboy::{Button, ButtonState, InitializationOptions, InputEvent, System, SystemEvent};
 use std::cell::RefCell;
 use std::ffi::CString;
 use std::os::raw::c_void;
@@ -18,6 +18,7 @@ struct LibRetroCallbacks {
     render_audio_batch: Option<RenderAudioBatch>,
     poll_input: Option<PollInput>,
     query_input_state: Option<QueryInputState>,
+    set_rumble_state: Option<SetRumbleState>,
 }
 
 impl LibRetroCallbacks {
@@ -29,6 +30,7 @@ impl LibRetroCallbacks {
             render_audio_batch: None,
             poll_input: None,
             query_input_state: None,
+            set_rumble_state: None,
         }
     }
 }
@@ -44,10 +46,20 @@ thread_local! {
     static SYSTEM_INFO: RefCell<Option<SystemInfo>> = RefCell::new(None);
     static SYSTEM: RefCell<Option<System>> = RefCell::new(None);
     static OUTPUT_FRAMEBUFFER: RefCell<[u8; 144 * 160 * 4]> = RefCell::new([0; 144 * 160 * 4]);
-    static OUTPUT_SOUND_BUFFER: RefCell<Vec<u8>> = RefCell::new(Vec::with_capacity(48000));
+    static OUTPUT_SOUND_BUFFER: RefCell<Vec<i16>> = const { RefCell::new(Vec::new()) };
     static LOG: RefCell<Option<RetroLogPrintf>> = RefCell::new(None);
+    static SAVE_STATE_SIZE: RefCell<usize> = const { RefCell::new(0) };
+    static RUMBLE_STRENGTH: RefCell<u16> = const { RefCell::new(0) };
+    static RTC_DATA: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
+    static EXPORTED_RTC_DATA: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
 }
 
+const SOUND_FREQUENCY: u32 = 48000;
+
+// Save states are prefixed with their length so the padding up to
+// retro_serialize_size() can be ignored when a state is loaded.
+const SAVE_STATE_LENGTH_PREFIX: usize = 4;
+
 #[no_mangle]
 pub unsafe extern "C" fn retro_set_environment(get_environment_info: GetEnvironmentInfo) {
     CALLBACKS.with(|state| {
@@ -180,13 +192,22 @@ pub unsafe extern "C" fn retro_get_system_info(info: *mut RetroSystemInfo) {
 pub unsafe extern "C" fn retro_load_game(game: *const RetroGameInfo) -> bool {
     let mut supports_format = false;
     CALLBACKS.with(|callback| {
-        let callback = callback.borrow();
+        let mut callback = callback.borrow_mut();
+        let get_environment_info = callback.get_environment_info.unwrap();
         let mut format = RetroPixelFormat::RetroPixelFormatXRGB8888 as i32;
 
-        supports_format = callback.get_environment_info.unwrap()(
+        supports_format = get_environment_info(
             RETRO_ENVIRONMENT_SET_PIXEL_FORMAT,
             &mut format as *mut i32 as *mut c_void,
         );
+
+        let mut rumble_interface = std::mem::MaybeUninit::<RetroRumbleInterface>::zeroed();
+        if get_environment_info(
+            RETRO_ENVIRONMENT_GET_RUMBLE_INTERFACE,
+            rumble_interface.as_mut_ptr() as *mut c_void,
+        ) {
+            callback.set_rumble_state = Some(rumble_interface.assume_init().set_rumble_state);
+        }
     });
 
     if !supports_format {
@@ -196,16 +217,43 @@ pub unsafe extern "C" fn retro_load_game(game: *const RetroGameInfo) -> bool {
     let game_rom: &[u8] = std::slice::from_raw_parts((*game).data.cast(), (*game).size);
     SYSTEM.with(|system| {
         let mut system = system.borrow_mut();
-        *system = Some(System::new(crate::InitializationOptions {
+        let new_system = System::new(crate::InitializationOptions {
             boot_rom: None,
             game_rom,
             debug_mode: false,
             external_ram: None,
-            sound_frequency: 48000,
-        }));
-    });
+            cartridge: None,
+            cgb_compatibility: false,
+            super_game_boy: false,
+            sound_frequency: SOUND_FREQUENCY,
+        });
+        let new_system = match new_system {
+            Ok(new_system) => new_system,
+            Err(error) => {
+                log_message(
+                    RetroLogLevel::Error,
+                    &format!("Failed to load game: {}", error),
+                );
+                return false;
+            }
+        };
+
+        let rtc_data = new_system.copy_rtc_data().unwrap_or_default();
+        RTC_DATA.with(|rtc| *rtc.borrow_mut() = rtc_data.clone());
+        EXPORTED_RTC_DATA.with(|exported| *exported.borrow_mut() = rtc_data);
+
+        OUTPUT_SOUND_BUFFER.with(|sound_output| {
+            sound_output.borrow_mut().reserve(SOUND_FREQUENCY as usize);
+        });
 
-    true
+        // Every component saves itself at a fixed size, so the state of
+        // the freshly loaded game is as large as it will ever get
+        SAVE_STATE_SIZE.with(|size| {
+            *size.borrow_mut() = SAVE_STATE_LENGTH_PREFIX + new_system.save_state().len();
+        });
+        *system = Some(new_system);
+        true
+    })
 }
 
 // Gets information about system audio/video timings and geometry.
@@ -221,8 +269,8 @@ pub unsafe extern "C" fn retro_get_system_av_info(info: *mut RetroSystemAvInfo)
     (*info).geometry.max_width = 160;
     (*info).geometry.max_height = 144;
     (*info).geometry.aspect_ratio = 0.0;
-    (*info).timing.fps = 60.0;
-    (*info).timing.sample_rate = 0.0;
+    (*info).timing.fps = System::refresh_rate();
+    (*info).timing.sample_rate = SOUND_FREQUENCY as f64;
 }
 
 // Runs the game for one video frame.
@@ -241,6 +289,7 @@ pub unsafe extern "C" fn retro_run() {
             let callbacks = callbacks.borrow();
 
             callbacks.poll_input.unwrap()();
+            sync_rtc(system);
 
             let query_input_state = callbacks.query_input_state.unwrap();
             let mut input_events = [InputEvent {
@@ -313,13 +362,67 @@ pub unsafe extern "C" fn retro_run() {
                         System::screen_height(),
                         System::screen_width() as usize * 4,
                     );
+
+                    if let Some(render_audio_batch) = callbacks.render_audio_batch {
+                        render_audio_batch(sound_buffer.as_ptr(), sound_buffer.len() / 2);
+                    }
+                    sound_buffer.clear();
                 });
             });
+
+            for event in system.take_events() {
+                match event {
+                    SystemEvent::CpuLocked { address, opcode } => log_message(
+                        RetroLogLevel::Error,
+                        &format!(
+                            "The game locked up executing illegal opcode {:02X} at {:04X}",
+                            opcode, address
+                        ),
+                    ),
+                }
+            }
+
+            if let Some(set_rumble_state) = callbacks.set_rumble_state {
+                let strength = (system.rumble_strength() * u16::MAX as f32) as u16;
+                RUMBLE_STRENGTH.with(|previous_strength| {
+                    let mut previous_strength = previous_strength.borrow_mut();
+                    if *previous_strength != strength {
+                        *previous_strength = strength;
+                        set_rumble_state(0, RetroRumbleEffect::Strong, strength);
+                    }
+                });
+            }
             // }
         });
     });
 }
 
+// Frontends keep the cartridge clock through RETRO_MEMORY_RTC. Data they wrote
+// there since the last frame is loaded into the cartridge, then the region is
+// refreshed with the current clock state.
+fn sync_rtc(system: &mut System) {
+    RTC_DATA.with(|rtc| {
+        EXPORTED_RTC_DATA.with(|exported| {
+            let mut rtc = rtc.borrow_mut();
+            let mut exported = exported.borrow_mut();
+            if *rtc != *exported {
+                if let Err(error) = system.import_rtc_data(&rtc) {
+                    log_message(
+                        RetroLogLevel::Error,
+                        &format!("Failed to load the clock state: {}", error),
+                    );
+                }
+            }
+
+            if let Some(data) = system.copy_rtc_data() {
+                // The region keeps its address for the lifetime of the game
+                rtc.copy_from_slice(&data);
+                exported.copy_from_slice(&data);
+            }
+        });
+    });
+}
+
 unsafe fn update_button(
     input_event: &mut InputEvent,
     button: Button,
@@ -341,18 +444,89 @@ unsafe fn update_button(
 // value, to ensure that the frontend can allocate a save state buffer once.
 #[no_mangle]
 pub unsafe extern "C" fn retro_serialize_size() -> usize {
-    0
+    SAVE_STATE_SIZE.with(|size| *size.borrow())
 }
 
 // Serializes internal state. If failed, or size is lower than
 // retro_serialize_size(), it should return false, true otherwise.
 #[no_mangle]
-pub unsafe extern "C" fn retro_serialize(_data: *mut c_void, _size: usize) -> bool {
-    return false;
+pub unsafe extern "C" fn retro_serialize(data: *mut c_void, size: usize) -> bool {
+    if data.is_null() {
+        return false;
+    }
+
+    SYSTEM.with(|system| {
+        let system = system.borrow();
+        let system = match system.as_ref() {
+            Some(system) => system,
+            None => return false,
+        };
+
+        let state = system.save_state();
+        if size < SAVE_STATE_LENGTH_PREFIX + state.len() {
+            return false;
+        }
+
+        let output = std::slice::from_raw_parts_mut(data as *mut u8, size);
+        output[..SAVE_STATE_LENGTH_PREFIX].copy_from_slice(&(state.len() as u32).to_le_bytes());
+        output[SAVE_STATE_LENGTH_PREFIX..SAVE_STATE_LENGTH_PREFIX + state.len()]
+            .copy_from_slice(&state);
+        for byte in output[SAVE_STATE_LENGTH_PREFIX + state.len()..].iter_mut() {
+            *byte = 0;
+        }
+        true
+    })
 }
+
 #[no_mangle]
-pub unsafe extern "C" fn retro_unserialize(_data: *mut c_void, _size: usize) -> bool {
-    return false;
+pub unsafe extern "C" fn retro_unserialize(data: *const c_void, size: usize) -> bool {
+    if data.is_null() || size < SAVE_STATE_LENGTH_PREFIX {
+        return false;
+    }
+
+    let input = std::slice::from_raw_parts(data as *const u8, size);
+    let mut length = [0; SAVE_STATE_LENGTH_PREFIX];
+    length.copy_from_slice(&input[..SAVE_STATE_LENGTH_PREFIX]);
+    let length = u32::from_le_bytes(length) as usize;
+    if length > size - SAVE_STATE_LENGTH_PREFIX {
+        return false;
+    }
+
+    SYSTEM.with(|system| {
+        let mut system = system.borrow_mut();
+        let system = match system.as_mut() {
+            Some(system) => system,
+            None => return false,
+        };
+
+        let state = &input[SAVE_STATE_LENGTH_PREFIX..SAVE_STATE_LENGTH_PREFIX + length];
+        match system.load_state(state) {
+            Ok(()) => true,
+            Err(error) => {
+                log_message(
+                    RetroLogLevel::Error,
+                    &format!("Failed to load save state: {}", error),
+                );
+                false
+            }
+        }
+    })
+}
+
+fn log_message(level: RetroLogLevel, message: &str) {
+    LOG.with(|log| {
+        if let Some(log) = *log.borrow() {
+            let format = CString::new("%s\n").unwrap();
+            let message = CString::new(message).unwrap();
+            unsafe {
+                log(
+                    level,
+                    format.as_ptr() as *const char,
+                    message.as_ptr() as *const char,
+                )
+            };
+        }
+    });
 }
 
 #[no_mangle]
@@ -383,12 +557,41 @@ pub unsafe extern "C" fn retro_get_region() -> u32 {
 }
 
 //  Gets region of memory.
+// The returned buffers are owned by the loaded System and remain valid until
+// the game is unloaded, so the frontend can read and write them directly.
 #[no_mangle]
-pub unsafe extern "C" fn retro_get_memory_data(_id: u32) -> *mut c_void {
-    return std::ptr::null_mut();
+pub unsafe extern "C" fn retro_get_memory_data(id: u32) -> *mut c_void {
+    with_memory_region(id, |region| region.as_mut_ptr() as *mut c_void)
+        .unwrap_or(std::ptr::null_mut())
 }
 
 #[no_mangle]
-pub unsafe extern "C" fn retro_get_memory_size(_id: u32) -> usize {
-    return 0;
+pub unsafe extern "C" fn retro_get_memory_size(id: u32) -> usize {
+    with_memory_region(id, |region| region.len()).unwrap_or(0)
+}
+
+fn with_memory_region<T>(id: u32, f: impl FnOnce(&mut [u8]) -> T) -> Option<T> {
+    if id == RETRO_MEMORY_RTC {
+        return RTC_DATA.with(|rtc| {
+            let mut rtc = rtc.borrow_mut();
+            if rtc.is_empty() {
+                None
+            } else {
+                Some(f(rtc.as_mut_slice()))
+            }
+        });
+    }
+
+    SYSTEM.with(|system| {
+        let mut system = system.borrow_mut();
+        let system = system.as_mut()?;
+        let region = match id {
+            RETRO_MEMORY_SAVE_RAM => system.external_ram_mut()?,
+            RETRO_MEMORY_SYSTEM_RAM => system.work_ram_mut(),
+            RETRO_MEMORY_VIDEO_RAM => system.video_ram_mut(),
+            _ => return None,
+        };
+
+        Some(f(region))
+    })
 }
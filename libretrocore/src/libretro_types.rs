@@ -22,7 +22,7 @@ pub type RefreshVideo =
  * generates a single sample at a time.
  * Format is signed 16-bit native endian.
  */
-pub type RenderAudioFrame = unsafe extern "C" fn(left: u16, right: u16);
+pub type RenderAudioFrame = unsafe extern "C" fn(left: i16, right: i16);
 
 /* Renders multiple audio frames in one go.
  *
@@ -30,7 +30,7 @@ pub type RenderAudioFrame = unsafe extern "C" fn(left: u16, right: u16);
  * I.e. int16_t buf[4] = { l, r, l, r }; would be 2 frames.
  * Only one of the audio callbacks must ever be used.
  */
-pub type RenderAudioBatch = unsafe extern "C" fn(data: *const u16, frames: usize);
+pub type RenderAudioBatch = unsafe extern "C" fn(data: *const i16, frames: usize) -> usize;
 
 /* Polls input. */
 pub type PollInput = unsafe extern "C" fn();
@@ -182,6 +182,38 @@ pub const RETRO_ENVIRONMENT_GET_LOG_INTERFACE: u32 = 27;
  */
 pub const RETRO_ENVIRONMENT_SET_CONTROLLER_INFO: u32 = 35;
 
+/* struct retro_rumble_interface * --
+ * Gets an interface which is used by a libretro core to set
+ * state of rumble motors in controllers.
+ * A strong and weak motor is supported, and they can be
+ * controlled indepedently.
+ * Should be called from either retro_init() or retro_load_game().
+ * Should not be called from retro_set_environment().
+ * Returns false if rumble functionality is unavailable.
+ */
+pub const RETRO_ENVIRONMENT_GET_RUMBLE_INTERFACE: u32 = 23;
+
+#[repr(u32)]
+pub enum RetroRumbleEffect {
+    Strong = 0,
+    Weak = 1,
+}
+
+/* Sets rumble state for joypad plugged in port 'port'.
+ * Rumble effects are controlled independently,
+ * and setting e.g. strong rumble does not override weak rumble.
+ * Strength has a range of [0, 0xffff].
+ *
+ * Returns true if rumble state request was honored.
+ * Calling this before first retro_run() is likely to return false. */
+pub type SetRumbleState =
+    unsafe extern "C" fn(port: u32, effect: RetroRumbleEffect, strength: u16) -> bool;
+
+#[repr(C)]
+pub struct RetroRumbleInterface {
+    pub set_rumble_state: SetRumbleState,
+}
+
 #[repr(i32)]
 pub enum RetroPixelFormat {
     /* 0RGB1555, native endian.
@@ -223,6 +255,14 @@ pub struct RetroLogCallback {
     pub log: RetroLogPrintf,
 }
 
+/* Regions of memory exposed through retro_get_memory_data().
+ * RETRO_MEMORY_SAVE_RAM is "Save RAM", regularly saved to disk by the
+ * frontend. RETRO_MEMORY_SYSTEM_RAM is the main working RAM. */
+pub const RETRO_MEMORY_SAVE_RAM: u32 = 0;
+pub const RETRO_MEMORY_RTC: u32 = 1;
+pub const RETRO_MEMORY_SYSTEM_RAM: u32 = 2;
+pub const RETRO_MEMORY_VIDEO_RAM: u32 = 3;
+
 #[repr(u32)]
 pub enum RetroRegion {
     Ntsc = 0,